@@ -0,0 +1,16 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::process::Command;
+
+fn main() {
+    if let Ok(output) = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+    {
+        if output.status.success() {
+            let hash = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=GIT_HASH={}", hash.trim());
+        }
+    }
+}
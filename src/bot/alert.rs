@@ -0,0 +1,306 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Alert delivery planning
+//!
+//! Alerts are generated per subscribed user, but several users can share a
+//! room (and even a token). The planner first merges the account alerts of
+//! each user into a single message (ex. many workers going offline together),
+//! then groups the messages by room and content, so each room receives every
+//! message only once.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Pool-wide event (ex. block found), same for every user
+    Pool,
+    /// Event related to the account of the user, mention the affected users
+    Account,
+}
+
+/// Notification state saved once the alert is delivered, so it doesn't fire again.
+/// `None` deletes the saved state (ex. recovery), to fire again on the next change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlertState {
+    pub key: String,
+    pub value: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub user_id: String,
+    pub room_id: String,
+    pub scope: Scope,
+    pub message: String,
+    pub state: Option<AlertState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub room_id: String,
+    pub message: String,
+    /// Indexes of the alerts merged in this delivery
+    pub sources: Vec<usize>,
+}
+
+pub fn fingerprint(scope: Scope, message: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    scope.hash(&mut hasher);
+    message.trim().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Alerts of a user in a room, merged into one message
+struct Batch<'a> {
+    first: &'a Alert,
+    messages: Vec<&'a str>,
+    sources: Vec<usize>,
+}
+
+impl Batch<'_> {
+    /// One message per line, as a list if more than one
+    fn message(&self) -> String {
+        match self.messages.as_slice() {
+            [message] => message.to_string(),
+            messages => messages
+                .iter()
+                .map(|message| format!("- {}", message))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+/// Merge the account alerts of each user in a room, keeping the order of first appearance
+fn batch(alerts: &[Alert]) -> Vec<Batch<'_>> {
+    let mut batches: Vec<Batch> = Vec::new();
+    let mut index: HashMap<(&str, &str), usize> = HashMap::new();
+
+    for (i, alert) in alerts.iter().enumerate() {
+        let pos: Option<usize> = match alert.scope {
+            Scope::Pool => None,
+            Scope::Account => index
+                .get(&(alert.room_id.as_str(), alert.user_id.as_str()))
+                .copied(),
+        };
+
+        match pos {
+            Some(pos) => {
+                let batch = &mut batches[pos];
+                if !batch.messages.contains(&alert.message.as_str()) {
+                    batch.messages.push(alert.message.as_str());
+                }
+                batch.sources.push(i);
+            }
+            None => {
+                if alert.scope == Scope::Account {
+                    index.insert(
+                        (alert.room_id.as_str(), alert.user_id.as_str()),
+                        batches.len(),
+                    );
+                }
+                batches.push(Batch {
+                    first: alert,
+                    messages: vec![alert.message.as_str()],
+                    sources: vec![i],
+                });
+            }
+        }
+    }
+
+    batches
+}
+
+/// Merge the account alerts of each user, then group the messages by
+/// `(room_id, fingerprint)`, keeping the order of first appearance
+pub fn plan(alerts: &[Alert]) -> Vec<Delivery> {
+    let mut groups: Vec<(&Alert, String, Vec<&str>, Vec<usize>)> = Vec::new();
+    let mut index: HashMap<(&str, u64), usize> = HashMap::new();
+
+    for batch in batch(alerts).into_iter() {
+        let alert: &Alert = batch.first;
+        let message: String = batch.message();
+        let key = (alert.room_id.as_str(), fingerprint(alert.scope, &message));
+
+        match index.get(&key) {
+            Some(pos) => {
+                let (_, _, users, sources) = &mut groups[*pos];
+                if !users.contains(&alert.user_id.as_str()) {
+                    users.push(alert.user_id.as_str());
+                }
+                sources.extend(batch.sources);
+            }
+            None => {
+                index.insert(key, groups.len());
+                groups.push((alert, message, vec![alert.user_id.as_str()], batch.sources));
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(alert, message, users, mut sources)| {
+            sources.sort_unstable();
+
+            let message = match alert.scope {
+                Scope::Pool => message,
+                Scope::Account if message.contains('\n') => {
+                    format!("{}:\n{}", users.join(", "), message)
+                }
+                Scope::Account => format!("{}: {}", users.join(", "), message),
+            };
+
+            Delivery {
+                room_id: alert.room_id.clone(),
+                message,
+                sources,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn alert(user_id: &str, room_id: &str, scope: Scope, message: &str) -> Alert {
+        Alert {
+            user_id: user_id.into(),
+            room_id: room_id.into(),
+            scope,
+            message: message.into(),
+            state: None,
+        }
+    }
+
+    fn delivery(room_id: &str, message: &str, sources: &[usize]) -> Delivery {
+        Delivery {
+            room_id: room_id.into(),
+            message: message.into(),
+            sources: sources.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_plan_pool_alerts() {
+        let alerts = vec![
+            alert("@alice:a.com", "!room1:a.com", Scope::Pool, "Block found"),
+            alert("@bob:a.com", "!room1:a.com", Scope::Pool, "Block found"),
+            alert("@carol:a.com", "!room2:a.com", Scope::Pool, "Block found"),
+        ];
+
+        assert_eq!(
+            plan(&alerts),
+            vec![
+                delivery("!room1:a.com", "Block found", &[0, 1]),
+                delivery("!room2:a.com", "Block found", &[2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_account_alerts() {
+        let alerts = vec![
+            alert(
+                "@alice:a.com",
+                "!room1:a.com",
+                Scope::Account,
+                "Worker s19 offline",
+            ),
+            alert(
+                "@bob:a.com",
+                "!room1:a.com",
+                Scope::Account,
+                "Worker s19 offline",
+            ),
+            alert(
+                "@alice:a.com",
+                "!room1:a.com",
+                Scope::Account,
+                "Worker s19 offline",
+            ),
+            alert(
+                "@bob:a.com",
+                "!room1:a.com",
+                Scope::Account,
+                "Worker s9 offline",
+            ),
+        ];
+
+        assert_eq!(
+            plan(&alerts),
+            vec![
+                delivery("!room1:a.com", "@alice:a.com: Worker s19 offline", &[0, 2]),
+                delivery(
+                    "!room1:a.com",
+                    "@bob:a.com:\n- Worker s19 offline\n- Worker s9 offline",
+                    &[1, 3]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_batched_alerts() {
+        let mut alerts: Vec<Alert> = Vec::new();
+        for user_id in ["@alice:a.com", "@bob:a.com"] {
+            for worker in ["s19", "s9", "l7"] {
+                alerts.push(alert(
+                    user_id,
+                    "!room1:a.com",
+                    Scope::Account,
+                    &format!("Worker {} offline", worker),
+                ));
+            }
+        }
+        alerts.push(alert(
+            "@alice:a.com",
+            "!room2:a.com",
+            Scope::Account,
+            "Worker s19 offline",
+        ));
+
+        assert_eq!(
+            plan(&alerts),
+            vec![
+                delivery(
+                    "!room1:a.com",
+                    "@alice:a.com, @bob:a.com:\n- Worker s19 offline\n- Worker s9 offline\n- Worker l7 offline",
+                    &[0, 1, 2, 3, 4, 5]
+                ),
+                delivery("!room2:a.com", "@alice:a.com: Worker s19 offline", &[6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_mixed_scopes() {
+        let alerts = vec![
+            alert("@alice:a.com", "!room1:a.com", Scope::Pool, "Block found"),
+            alert(
+                "@alice:a.com",
+                "!room1:a.com",
+                Scope::Account,
+                "Block found",
+            ),
+        ];
+
+        assert_eq!(plan(&alerts).len(), 2);
+        assert!(plan(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(
+            fingerprint(Scope::Pool, "Block found"),
+            fingerprint(Scope::Pool, "Block found\n")
+        );
+        assert_ne!(
+            fingerprint(Scope::Pool, "Block found"),
+            fingerprint(Scope::Account, "Block found")
+        );
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::io::Cursor;
+
+use chrono_tz::Tz;
+use image::{ImageOutputFormat, RgbImage};
+use plotters::prelude::*;
+
+use crate::db::HashrateSample;
+use crate::util;
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 400;
+
+#[derive(Debug)]
+pub enum Error {
+    Plot(String),
+    Image(image::ImageError),
+    InvalidBuffer,
+}
+
+pub fn hashrate_png(account: &str, samples: &[HashrateSample], tz: Tz) -> Result<Vec<u8>, Error> {
+    let mut buffer: Vec<u8> = vec![0; (WIDTH * HEIGHT * 3) as usize];
+
+    {
+        let root = BitMapBackend::with_buffer(&mut buffer, (WIDTH, HEIGHT)).into_drawing_area();
+        root.fill(&WHITE).map_err(plot_error)?;
+
+        let min_ts: i64 = samples.first().map(|s| s.timestamp).unwrap_or_default();
+        let max_ts: i64 = samples.last().map(|s| s.timestamp).unwrap_or_default();
+        let max_th: f64 = samples
+            .iter()
+            .map(|s| s.hash_rate / 1000.0)
+            .fold(0.0, f64::max);
+
+        let mut chart = ChartBuilder::on(&root)
+            .caption(format!("{} - Hashrate 5m", account), ("sans-serif", 24))
+            .margin(10)
+            .x_label_area_size(40)
+            .y_label_area_size(70)
+            .build_cartesian_2d(min_ts..max_ts.max(min_ts + 1), 0.0..(max_th * 1.1).max(1.0))
+            .map_err(plot_error)?;
+
+        chart
+            .configure_mesh()
+            .x_desc(format!("Time ({})", tz.name()))
+            .y_desc("Th/s")
+            .x_labels(6)
+            .x_label_formatter(&|ts| util::format_date_tz(*ts, "%m-%d %H:%M", tz))
+            .draw()
+            .map_err(plot_error)?;
+
+        chart
+            .draw_series(LineSeries::new(
+                samples.iter().map(|s| (s.timestamp, s.hash_rate / 1000.0)),
+                &BLUE,
+            ))
+            .map_err(plot_error)?;
+
+        root.present().map_err(plot_error)?;
+    }
+
+    let image = RgbImage::from_raw(WIDTH, HEIGHT, buffer).ok_or(Error::InvalidBuffer)?;
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .map_err(Error::Image)?;
+
+    Ok(png.into_inner())
+}
+
+fn plot_error<E: std::fmt::Display>(error: E) -> Error {
+    Error::Plot(error.to_string())
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Pending confirmations of destructive commands
+//!
+//! Kept in memory: a restart cancels them, which is the safe side.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Time to confirm a command
+pub const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+/// Expired confirmations are kept this long, to tell a late confirmation that it expired
+const EXPIRED_KEPT: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmation {
+    Confirmed,
+    /// Requested, but more than [`CONFIRMATION_TIMEOUT`] ago
+    Expired,
+    /// Never requested, or already confirmed
+    NotRequested,
+}
+
+#[derive(Clone, Default)]
+pub struct Confirmations(Arc<Mutex<HashMap<String, Instant>>>);
+
+impl Confirmations {
+    /// Start waiting for the confirmation of `action` by `user_id`
+    pub fn request(&self, user_id: &str, action: &str) {
+        self.request_at(user_id, action, Instant::now());
+    }
+
+    /// Check if `action` was requested by `user_id` and not expired.
+    /// The pending confirmation is consumed.
+    pub fn confirm(&self, user_id: &str, action: &str) -> Confirmation {
+        self.confirm_at(user_id, action, Instant::now())
+    }
+
+    fn request_at(&self, user_id: &str, action: &str, now: Instant) {
+        if let Ok(mut pending) = self.0.lock() {
+            pending.retain(|_, requested_at| {
+                now.saturating_duration_since(*requested_at) <= EXPIRED_KEPT
+            });
+            pending.insert(key(user_id, action), now);
+        }
+    }
+
+    fn confirm_at(&self, user_id: &str, action: &str, now: Instant) -> Confirmation {
+        match self.0.lock() {
+            Ok(mut pending) => match pending.remove(&key(user_id, action)) {
+                Some(requested_at) if is_expired(requested_at, now) => Confirmation::Expired,
+                Some(_) => Confirmation::Confirmed,
+                None => Confirmation::NotRequested,
+            },
+            Err(_) => Confirmation::NotRequested,
+        }
+    }
+}
+
+fn key(user_id: &str, action: &str) -> String {
+    format!("{}|{}", user_id, action)
+}
+
+fn is_expired(requested_at: Instant, now: Instant) -> bool {
+    now.saturating_duration_since(requested_at) > CONFIRMATION_TIMEOUT
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const USER_ID: &str = "@alice:example.com";
+
+    #[test]
+    fn test_confirmations() {
+        let confirmations = Confirmations::default();
+        let now = Instant::now();
+
+        // Nothing pending
+        assert_eq!(
+            confirmations.confirm_at(USER_ID, "unlink", now),
+            Confirmation::NotRequested
+        );
+
+        confirmations.request_at(USER_ID, "unlink", now);
+        assert_eq!(
+            confirmations.confirm_at("@bob:example.com", "unlink", now),
+            Confirmation::NotRequested
+        );
+        assert_eq!(
+            confirmations.confirm_at(USER_ID, "other", now),
+            Confirmation::NotRequested
+        );
+        assert_eq!(
+            confirmations.confirm_at(USER_ID, "unlink", now + Duration::from_secs(50)),
+            Confirmation::Confirmed
+        );
+
+        // Consumed
+        assert_eq!(
+            confirmations.confirm_at(USER_ID, "unlink", now),
+            Confirmation::NotRequested
+        );
+
+        // Expired, also consumed
+        confirmations.request_at(USER_ID, "unlink", now);
+        assert_eq!(
+            confirmations.confirm_at(USER_ID, "unlink", now + Duration::from_secs(61)),
+            Confirmation::Expired
+        );
+        assert_eq!(
+            confirmations.confirm_at(USER_ID, "unlink", now + Duration::from_secs(61)),
+            Confirmation::NotRequested
+        );
+
+        // Expired entries are pruned after a while
+        confirmations.request_at("@bob:example.com", "unlink", now);
+        confirmations.request_at(USER_ID, "unlink", now + Duration::from_secs(120));
+        assert_eq!(confirmations.0.lock().unwrap().len(), 2);
+        confirmations.request_at(USER_ID, "unlink", now + Duration::from_secs(3700));
+        assert_eq!(confirmations.0.lock().unwrap().len(), 1);
+    }
+}
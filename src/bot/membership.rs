@@ -0,0 +1,86 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::member::{MembershipState, RoomMemberEventContent};
+use matrix_sdk::ruma::events::OriginalSyncStateEvent;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Client;
+
+use super::{Bot, START_TIMESTAMP};
+use crate::i18n::{self, Lang, Msg};
+
+/// Welcome the room when the bot joins it, remove its subscriptions when the bot
+/// is kicked or banned from it
+pub async fn on_room_member(
+    bot: &Bot,
+    event: OriginalSyncStateEvent<RoomMemberEventContent>,
+    room: Room,
+    client: Client,
+) {
+    let user_id: Box<UserId> = match client.user_id().await {
+        Some(value) => value,
+        None => {
+            log::warn!("No user_id found");
+            return;
+        }
+    };
+
+    if event.state_key != *user_id {
+        return;
+    }
+
+    let reason: &str = match event.content.membership {
+        MembershipState::Join => return welcome(bot, &event, room.room_id().as_str()).await,
+        MembershipState::Leave if event.sender != *user_id => "kicked",
+        MembershipState::Leave => "left",
+        MembershipState::Ban => "banned",
+        _ => return,
+    };
+
+    log::warn!(
+        "Bot {} from room {} by {}",
+        reason,
+        room.room_id(),
+        event.sender
+    );
+
+    bot.remove_room_subscriptions(room.room_id().as_str());
+}
+
+/// Introduce the bot the first time it joins a room
+async fn welcome(bot: &Bot, event: &OriginalSyncStateEvent<RoomMemberEventContent>, room_id: &str) {
+    // Joins replayed by the sync after a restart, the rooms joined before the upgrade
+    if event.origin_server_ts < *START_TIMESTAMP || !bot.is_active_room(room_id) {
+        return;
+    }
+
+    // Also a change of the display name or avatar of the bot
+    match bot.store.is_room_welcomed(room_id) {
+        Ok(false) => (),
+        Ok(true) => return,
+        Err(error) => {
+            log::error!(
+                "Impossible to check welcome of room {}: {:?}",
+                room_id,
+                error
+            );
+            return;
+        }
+    }
+
+    let prefix: String = bot.command_prefix();
+    let msg: String = i18n::t(Msg::Welcome, Lang::default(), &[&prefix, &prefix]);
+
+    if bot.send_notification(room_id, &msg).await {
+        log::info!("Welcome message sent in room {}", room_id);
+        if let Err(error) = bot.write("welcome", room_id, |store| store.set_room_welcomed(room_id))
+        {
+            log::error!(
+                "Impossible to save welcome of room {}: {:?}",
+                room_id,
+                error
+            );
+        }
+    }
+}
@@ -1,381 +1,907 @@
 // Copyright (c) 2021-2022 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use std::time::Instant;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use braiinspool::Client as BraiinsPoolClient;
 use matrix_sdk::config::SyncSettings;
 use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::discovery::get_supported_versions;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::room::create_room::{self, v3::RoomPreset};
+use matrix_sdk::ruma::api::client::session::logout;
+use matrix_sdk::ruma::events::key::verification::key::ToDeviceKeyVerificationKeyEvent;
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
+use matrix_sdk::ruma::events::key::verification::start::ToDeviceKeyVerificationStartEvent;
+use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
+use matrix_sdk::ruma::events::room::member::RoomMemberEventContent;
 use matrix_sdk::ruma::events::room::message::{
     MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent, TextMessageEventContent,
 };
-use matrix_sdk::ruma::UserId;
+use matrix_sdk::ruma::events::room::tombstone::RoomTombstoneEventContent;
+use matrix_sdk::ruma::events::OriginalSyncStateEvent;
+use matrix_sdk::ruma::{IdParseError, MilliSecondsSinceUnixEpoch, RoomId, UserId};
 use matrix_sdk::store::{CryptoStore, StateStore};
-use matrix_sdk::{Client, ClientBuilder, Session};
+use matrix_sdk::{Client, ClientBuilder, HttpError, Session};
+use tokio::time::sleep;
+
+/// Log with structured fields, shown only by the JSON log format (see [`crate::logger::with_fields`]):
+/// `log_fields!(debug, { "room_id": room_id }, "Message received: {}", msg_body)`
+macro_rules! log_fields {
+    ($level:ident, { $($key:literal: $value:expr),* $(,)? }, $($arg:tt)+) => {
+        crate::logger::with_fields(
+            vec![$(($key, serde_json::json!($value))),*],
+            || log::$level!($($arg)+),
+        )
+    };
+}
 
+mod alert;
 mod autojoin;
+#[cfg(feature = "charts")]
+mod chart;
+mod command;
+mod confirm;
+mod membership;
+mod outbox;
+mod poller;
+mod responder;
+mod settings;
+mod tombstone;
+mod undecryptable;
+mod verification;
+
+use self::confirm::Confirmations;
+use self::outbox::{Outbox, Priority};
+use self::responder::{MatrixRoom, Responder};
+use self::undecryptable::WarnedRooms;
+use crate::config::{Config, ConfigHandle};
+use crate::db::{AuditEntry, DBStore};
+use crate::health;
+use crate::i18n::{self, Lang, Msg};
+use crate::pool::{self, PoolClientFactory};
+use crate::secret;
+
+lazy_static! {
+    static ref START_TIMESTAMP: MilliSecondsSinceUnixEpoch = MilliSecondsSinceUnixEpoch::now();
+}
 
-use crate::{util, CONFIG, STORE};
-
-pub struct Bot;
+const SYNC_RETRY_MIN_DELAY: Duration = Duration::from_secs(2);
+const SYNC_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+pub struct Bot {
+    config: ConfigHandle,
+    store: DBStore,
+    pool: Arc<dyn PoolClientFactory>,
+    outbox: Outbox,
+    warned_rooms: WarnedRooms,
+    confirmations: Confirmations,
+}
 
 #[derive(Debug)]
 pub enum Error {
-    Db(bpns_rocksdb::Error),
+    Db(crate::db::Error),
     Matrix(matrix_sdk::Error),
     MatrixClientBuilder(matrix_sdk::ClientBuildError),
     MatrixStore(matrix_sdk::StoreError),
     MatrixCryptoStore(matrix_sdk::store::OpenStoreError),
-    BraiinsPool(braiinspool::client::Error),
+    BraiinsPool(pool::Error),
+    InvalidUserId(IdParseError),
+    RoomNotJoined(String),
+    /// The bot can't write to the room anymore
+    RoomGone(String),
+    /// Message not delivered, even after retrying
+    SendFailed(matrix_sdk::Error),
+    OutboxClosed,
+    /// No saved session and no password (`matrix.password` or `matrix.password_file`) to login
+    PasswordRequired,
+    /// Logged in, but the session can't be read back to be saved: on the next
+    /// start the bot would login with a new device, without the keys of this one
+    SessionNotSaved,
+    /// The crypto store doesn't hold the keys of the device of the saved session
+    /// (ex. state directory deleted or restored from another backup). The keys
+    /// are generated again, so the device is no longer verified and the messages
+    /// of encrypted rooms sent before can't be decrypted.
+    DeviceKeysMissing(String),
+    /// `matrix.crypto_store_passphrase` is set, but the crypto store was created without it
+    CryptoStoreNotEncrypted,
+    /// The crypto store was created with a passphrase, but none is set
+    CryptoStorePassphraseRequired,
+    /// The crypto store can't be opened with the passphrase (ex. changed since its creation)
+    CryptoStoreWrongPassphrase(matrix_sdk::store::OpenStoreError),
 }
 
 impl Bot {
-    pub async fn run() -> Result<(), Error> {
-        let homeserver_url: &str = CONFIG.matrix.homeserver_url.as_str();
-        let user_id: &str = CONFIG.matrix.user_id.as_str();
-        let password: &str = CONFIG.matrix.password.as_str();
+    pub fn new(config: ConfigHandle, store: DBStore, pool: Arc<dyn PoolClientFactory>) -> Self {
+        Self {
+            config,
+            store,
+            pool,
+            outbox: Outbox::new(),
+            warned_rooms: WarnedRooms::default(),
+            confirmations: Confirmations::default(),
+        }
+    }
 
-        let user_id_boxed = Box::<UserId>::try_from(user_id).unwrap();
-        let state_store = StateStore::open_with_path(&CONFIG.matrix.state_path)?;
-        let crypto_store = CryptoStore::open_with_passphrase(&CONFIG.matrix.state_path, None)?;
+    /// Snapshot of the current config, see [`ConfigHandle`]
+    fn config(&self) -> Arc<Config> {
+        self.config.get()
+    }
 
-        let mut client_builder: ClientBuilder = Client::builder()
-            .homeserver_url(homeserver_url)
-            .crypto_store(Box::new(crypto_store))
-            .state_store(Box::new(state_store));
+    pub async fn run(&self) -> Result<(), Error> {
+        lazy_static::initialize(&START_TIMESTAMP);
 
-        if let Some(proxy) = &CONFIG.matrix.proxy {
-            client_builder = client_builder.proxy(proxy);
-        }
+        let config: Arc<Config> = self.config();
+
+        let user_id: &str = config.matrix.user_id.as_str();
+
+        let user_id_boxed = Box::<UserId>::try_from(user_id)?;
+        let new_state: bool = !config.matrix.state_path.exists();
+        // Before the state store, that creates the state directory
+        let crypto_store = self.open_crypto_store(&config)?;
+        let state_store = StateStore::open_with_path(&config.matrix.state_path)?;
+
+        let client: Client = client_builder(&config)
+            .crypto_store(Box::new(crypto_store))
+            .state_store(Box::new(state_store))
+            .build()
+            .await?;
 
-        let client: Client = client_builder.build().await?;
+        self.outbox.start(client.clone(), self.config.clone());
 
         log::debug!("Checking session...");
 
-        if STORE.session_exist(user_id) {
-            let session_store = STORE.get_session(user_id)?;
+        // Don't login again on a read error: a new device would lose the encryption keys
+        if self.store.session_exist(user_id)? {
+            let session_store = self.store.get_session(user_id)?;
+            // In use from now on
+            secret::register(session_store.access_token.expose());
 
             let session = Session {
-                access_token: session_store.access_token,
+                access_token: session_store.access_token.expose().clone(),
                 user_id: user_id_boxed,
                 device_id: session_store.device_id.into(),
             };
 
-            client.restore_login(session).await?;
+            self.retry_transient("Session restore", || client.restore_login(session.clone()))
+                .await?;
 
             log::debug!("Session restored from database");
+
+            if let Err(error) = self.check_device_keys(&client, &session).await {
+                log::warn!("{:?}: the crypto store at {:?} doesn't match the saved session. Messages in encrypted rooms can't be decrypted: verify the device again, or delete the session and the state directory to start with a new device.", error, config.matrix.state_path);
+            }
         } else {
             log::debug!("Session not found into database");
-            log::debug!("Login with credentials...");
-            let username = user_id_boxed.localpart();
-            client
-                .login(username, password, None, Some("BraiinsPool Bot"))
+            self.retry_transient("Login", || self.login(&client, None))
                 .await?;
+        }
 
-            log::debug!("Getting session data...");
-
-            if let Some(session) = client.session().await {
-                log::debug!("Saving session data into database...");
-                STORE.create_session(user_id, &session.access_token, session.device_id.as_ref())?;
-
-                log::debug!("Session saved to database");
-            } else {
-                log::error!("Impossible to get and save session");
-                log::warn!("The bot can continue to work without saving the session but if you are using an encrypted room, on the next restart, the bot will not be able to read the messages");
+        let set_display_name = || async {
+            client
+                .account()
+                .set_display_name(Some("BraiinsPool Bot"))
+                .await
+                .map_err(Error::from)
+        };
+
+        match self
+            .retry_transient("Session check", set_display_name)
+            .await
+        {
+            Ok(()) => (),
+            Err(Error::Matrix(error)) if Self::is_unknown_token(&error) => {
+                self.relogin(&client).await?;
+                self.retry_transient("Session check", set_display_name)
+                    .await?;
             }
+            Err(error) => return Err(error),
         }
 
-        client
-            .account()
-            .set_display_name(Some("BraiinsPool Bot"))
-            .await?;
-
         log::info!("Matrix Bot started");
 
+        self.send_to_admin_rooms(&format!(
+            "BraiinsPool Bot online (v{}) - {} subscribed users",
+            env!("CARGO_PKG_VERSION"),
+            self.store.count_users()
+        ))
+        .await;
+
+        health::set_logged_in();
+        poller::spawn(self.clone());
+
+        let bot = self.clone();
+        let tombstone_bot = self.clone();
+        let membership_bot = self.clone();
+        let request_bot = self.clone();
+        let start_bot = self.clone();
+        let key_bot = self.clone();
+        let encrypted_bot = self.clone();
+
         client
             .register_event_handler(autojoin::on_stripped_state_member)
             .await
             .register_event_handler(
-                move |event: OriginalSyncRoomMessageEvent, room: Room| async move {
-                    if let Err(error) = Self::on_room_message(event, &room).await {
+                move |event: OriginalSyncStateEvent<RoomTombstoneEventContent>,
+                      room: Room,
+                      client: Client| {
+                    let bot = tombstone_bot.clone();
+                    async move { tombstone::on_room_tombstone(&bot, event, room, client).await }
+                },
+            )
+            .await
+            .register_event_handler(
+                move |event: ToDeviceKeyVerificationRequestEvent, client: Client| {
+                    let bot = request_bot.clone();
+                    async move { verification::on_request(&bot, event, client).await }
+                },
+            )
+            .await
+            .register_event_handler(
+                move |event: ToDeviceKeyVerificationStartEvent, client: Client| {
+                    let bot = start_bot.clone();
+                    async move { verification::on_start(&bot, event, client).await }
+                },
+            )
+            .await
+            .register_event_handler(
+                move |event: ToDeviceKeyVerificationKeyEvent, client: Client| {
+                    let bot = key_bot.clone();
+                    async move { verification::on_key(&bot, event, client).await }
+                },
+            )
+            .await
+            .register_event_handler(verification::on_done)
+            .await
+            .register_event_handler(move |event: OriginalSyncRoomEncryptedEvent, room: Room| {
+                let bot = encrypted_bot.clone();
+                async move { undecryptable::on_room_encrypted(&bot, event, room).await }
+            })
+            .await
+            .register_event_handler(
+                move |event: OriginalSyncStateEvent<RoomMemberEventContent>,
+                      room: Room,
+                      client: Client| {
+                    let bot = membership_bot.clone();
+                    async move { membership::on_room_member(&bot, event, room, client).await }
+                },
+            )
+            .await
+            .register_event_handler(
+                move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+                    let bot = bot.clone();
+                    async move {
                         if let Room::Joined(room) = room {
-                            let _ = room
-                                .send(
-                                    RoomMessageEventContent::text_plain(format!("{:?}", error)),
-                                    None,
-                                )
-                                .await;
+                            let room = MatrixRoom {
+                                client,
+                                room,
+                                outbox: bot.outbox.clone(),
+                                dry_run: bot.config().dry_run,
+                            };
+                            let sender: String = event.sender.to_string();
+                            if let Err(error) = bot.on_room_message(event, &room).await {
+                                bot.on_command_error(&room, &sender, error).await;
+                            }
                         }
                     }
                 },
             )
             .await;
 
-        let settings = SyncSettings::default().full_state(true);
-        client.sync(settings).await;
+        // Only the first sync, the next ones get just the changes
+        let first_sync = SyncSettings::default().full_state(config.matrix.sync_full_state);
+        let mut settings = match self.store.get_sync_token() {
+            // The token is valid only together with the state store it was saved with
+            Some(_) if new_state => {
+                log::info!("New state directory, ignoring saved sync token");
+                first_sync
+            }
+            Some(token) => {
+                log::debug!("Resuming sync from saved token");
+                first_sync.token(token)
+            }
+            None => first_sync,
+        };
+
+        let mut retry_delay: Duration = SYNC_RETRY_MIN_DELAY;
+        let mut failing_since: Option<Instant> = None;
+
+        loop {
+            match client.sync_once(settings.clone()).await {
+                Ok(response) => {
+                    health::update_last_sync();
+
+                    if failing_since.take().is_some() {
+                        log::info!("Sync restored");
+                        retry_delay = SYNC_RETRY_MIN_DELAY;
+                    }
+
+                    if let Err(error) = self.store.set_sync_token(&response.next_batch) {
+                        log::error!("Impossible to save sync token: {:?}", error);
+                    }
+
+                    settings = SyncSettings::default()
+                        .token(response.next_batch)
+                        .timeout(Duration::from_secs(self.config().matrix.sync_timeout_secs));
+                }
+                Err(error) if Self::is_unknown_token(&error) => self.relogin(&client).await?,
+                Err(error) => {
+                    let since: Instant = *failing_since.get_or_insert_with(Instant::now);
+                    let max_retry_secs: u64 = self.config().matrix.sync_max_retry_secs;
+
+                    if max_retry_secs > 0 && since.elapsed().as_secs() >= max_retry_secs {
+                        log::error!("Sync failing for more than {}s, giving up", max_retry_secs);
+                        return Err(error.into());
+                    }
+
+                    log::error!(
+                        "Sync failed ({:?}), retrying in {}s",
+                        error,
+                        retry_delay.as_secs()
+                    );
+
+                    sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(SYNC_RETRY_MAX_DELAY);
+                }
+            }
+        }
+    }
+
+    async fn login(&self, client: &Client, device_id: Option<&str>) -> Result<(), Error> {
+        let config: Arc<Config> = self.config();
+        let user_id: &str = config.matrix.user_id.as_str();
+        let password: &str = config
+            .matrix
+            .password
+            .as_ref()
+            .map(|password| password.expose().as_str())
+            .ok_or(Error::PasswordRequired)?;
+        let user_id_boxed = Box::<UserId>::try_from(user_id)?;
+
+        log::debug!("Login with credentials...");
+        let username = user_id_boxed.localpart();
+        client
+            .login(username, password, device_id, Some("BraiinsPool Bot"))
+            .await?;
+
+        log::debug!("Getting session data...");
+
+        // The crypto store already holds the keys of the device: save the session
+        // only together with them, or the next start would create a new device
+        let session = client.session().await.ok_or(Error::SessionNotSaved)?;
+        let device_key: Option<String> = client.encryption().ed25519_key().await;
+
+        log::debug!("Saving session data into database...");
+        self.store.create_session(
+            user_id,
+            &session.access_token,
+            session.device_id.as_ref(),
+            device_key.as_deref(),
+        )?;
+
+        log::debug!("Session saved to database");
 
         Ok(())
     }
 
-    async fn on_room_message(
-        event: OriginalSyncRoomMessageEvent,
-        room: &Room,
-    ) -> Result<(), Error> {
-        if *event.sender.clone() == CONFIG.matrix.user_id {
-            return Ok(());
+    /// Open the crypto store, checking that the passphrase matches the one it was created with:
+    /// the SDK can't re-key an existing store
+    fn open_crypto_store(&self, config: &Config) -> Result<CryptoStore, Error> {
+        let path: &Path = &config.matrix.state_path;
+        let passphrase: Option<&str> = config
+            .matrix
+            .crypto_store_passphrase
+            .as_ref()
+            .map(|passphrase| passphrase.expose().as_str());
+
+        let encrypted: Option<bool> = if path.exists() {
+            // Stores created before the passphrase was supported aren't tracked
+            Some(self.store.get_crypto_store_encrypted().unwrap_or(false))
+        } else {
+            None
+        };
+
+        match (encrypted, passphrase) {
+            (Some(false), Some(_)) => return Err(Error::CryptoStoreNotEncrypted),
+            (Some(true), None) => return Err(Error::CryptoStorePassphraseRequired),
+            _ => (),
         }
 
-        if let Room::Joined(room) = room {
-            let msg_body = match event.content.msgtype {
-                MessageType::Text(TextMessageEventContent { body, .. }) => body,
-                _ => return Ok(()),
-            };
+        let crypto_store = CryptoStore::open_with_passphrase(path, passphrase).map_err(
+            |error| match encrypted {
+                Some(true) => Error::CryptoStoreWrongPassphrase(error),
+                _ => Error::MatrixCryptoStore(error),
+            },
+        )?;
 
-            log::debug!("Message received: {}", msg_body);
-
-            let start = Instant::now();
-
-            let user_id: &str = event.sender.as_str();
-
-            let proxy = CONFIG.proxy.as_deref();
-
-            let msg_splitted: Vec<&str> = msg_body.split(' ').collect();
-            let command: &str = msg_splitted[0];
-
-            let mut msg_content: &str = "";
-
-            match command {
-                "!userstatus" => {
-                    if STORE.user_exist(user_id) {
-                        let user = STORE.get_user(user_id)?;
-
-                        let client = BraiinsPoolClient::new(user.token.as_str(), proxy)?;
-
-                        let obj = client.user_profile().await?;
-
-                        let mut msg = String::from("User Status\n\n");
-                        msg.push_str(&format!(
-                            "Reward: {}\n",
-                            util::format_btc_to_sats(obj.confirmed_reward)
-                        ));
-                        msg.push_str(&format!(
-                            "Unconfirmed reward: {}\n",
-                            util::format_btc_to_sats(obj.unconfirmed_reward)
-                        ));
-                        msg.push_str(&format!(
-                            "Estimate reward (block): {}\n\n",
-                            util::format_btc_to_sats(obj.estimated_reward)
-                        ));
-
-                        msg.push_str(&format!(
-                            "Hashrate 5m: {}\n",
-                            util::format_gh_to_th(obj.hash_rate_5m)
-                        ));
-                        msg.push_str(&format!(
-                            "Hashrate 60m: {}\n",
-                            util::format_gh_to_th(obj.hash_rate_60m)
-                        ));
-                        msg.push_str(&format!(
-                            "Hashrate 24h: {}\n",
-                            util::format_gh_to_th(obj.hash_rate_24h)
-                        ));
-                        msg.push_str(&format!(
-                            "Hashrate scoring: {}\n",
-                            util::format_gh_to_th(obj.hash_rate_scoring)
-                        ));
-                        msg.push_str(&format!(
-                            "Hashrate yesterday: {}\n\n",
-                            util::format_gh_to_th(obj.hash_rate_yesterday)
-                        ));
-
-                        msg.push_str(&format!("Ok workers: {}\n", obj.ok_workers));
-                        msg.push_str(&format!("Low workers: {}\n", obj.low_workers));
-                        msg.push_str(&format!("Off workers: {}\n", obj.off_workers));
-                        msg.push_str(&format!("Disabled workers: {}", obj.dis_workers));
-
-                        let content = RoomMessageEventContent::text_plain(msg);
-                        room.send(content, None).await?;
-                    } else {
-                        msg_content = "This account in not subscribed.";
-                    }
-                }
-                "!workers" => {
-                    if STORE.user_exist(user_id) {
-                        let user = STORE.get_user(user_id)?;
+        if encrypted.is_none() {
+            self.store
+                .set_crypto_store_encrypted(passphrase.is_some())?;
+        }
 
-                        let client = BraiinsPoolClient::new(user.token.as_str(), proxy)?;
+        Ok(crypto_store)
+    }
 
-                        let obj = client.workers().await?;
+    /// Check that the crypto store holds the keys of the device of the restored session
+    async fn check_device_keys(&self, client: &Client, session: &Session) -> Result<(), Error> {
+        let local: Option<String> = client.encryption().ed25519_key().await;
+
+        match (self.store.get_device_key(), local) {
+            (Some(saved), Some(local)) if saved == local => Ok(()),
+            // Session saved before the device key was tracked
+            (None, Some(local)) => {
+                self.store.create_session(
+                    session.user_id.as_str(),
+                    &session.access_token,
+                    session.device_id.as_ref(),
+                    Some(&local),
+                )?;
+                Ok(())
+            }
+            _ => Err(Error::DeviceKeysMissing(session.device_id.to_string())),
+        }
+    }
 
-                        let mut msg = String::from("Workers\n\n");
+    /// Login again with credentials, keeping the same device
+    async fn relogin(&self, client: &Client) -> Result<(), Error> {
+        let config: Arc<Config> = self.config();
+        let user_id: &str = config.matrix.user_id.as_str();
 
-                        for (name, worker) in obj {
-                            let name_splitted: Vec<&str> = name.split('.').collect();
-                            if name_splitted.len() >= 2 {
-                                msg.push_str(&format!("Worker: {}\n", name_splitted[1]));
-                            }
+        log::warn!("Session is no longer valid, login again...");
 
-                            msg.push_str(&format!("Status: {}\n", worker.state));
-                            msg.push_str(&format!(
-                                "Last share: {}\n",
-                                util::format_date(worker.last_share as i64, "%Y-%m-%d %H:%M:%S")
-                            ));
-                            msg.push_str(&format!(
-                                "Hashrate scoring: {}\n",
-                                util::format_gh_to_th(worker.hash_rate_scoring)
-                            ));
-                            msg.push_str(&format!(
-                                "Hashrate 5m: {}\n",
-                                util::format_gh_to_th(worker.hash_rate_5m)
-                            ));
-                            msg.push_str(&format!(
-                                "Hashrate 60m: {}\n",
-                                util::format_gh_to_th(worker.hash_rate_60m)
-                            ));
-                            msg.push_str(&format!(
-                                "Hashrate 24h: {}\n\n",
-                                util::format_gh_to_th(worker.hash_rate_24h)
-                            ));
-                        }
+        let device_id: Option<String> = self
+            .store
+            .get_session(user_id)
+            .ok()
+            .map(|session| session.device_id);
 
-                        let content = RoomMessageEventContent::text_plain(msg);
-                        room.send(content, None).await?;
-                    } else {
-                        msg_content = "This account in not subscribed.";
-                    }
+        self.store.delete_session(user_id)?;
+        self.login(client, device_id.as_deref()).await?;
+
+        log::info!("Login completed, session renewed");
+
+        self.send_to_admin_rooms("Session was invalidated, logged in again")
+            .await;
+
+        Ok(())
+    }
+
+    /// Run a startup request, retrying with backoff while the homeserver is unreachable.
+    /// Other errors (e.g. invalid credentials) are returned immediately.
+    async fn retry_transient<T, E, F, Fut>(&self, what: &str, action: F) -> Result<T, Error>
+    where
+        E: Into<Error>,
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let max_retries: u32 = self.config().matrix.login_max_retries;
+        let mut retry_delay: Duration = SYNC_RETRY_MIN_DELAY;
+        let mut attempt: u32 = 0;
+
+        loop {
+            match action().await.map_err(Into::into) {
+                Err(error) if attempt < max_retries && Self::is_transient(&error) => {
+                    attempt += 1;
+                    log::warn!(
+                        "{} failed ({:?}), retrying in {}s ({}/{})",
+                        what,
+                        error,
+                        retry_delay.as_secs(),
+                        attempt,
+                        max_retries
+                    );
+
+                    sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(SYNC_RETRY_MAX_DELAY);
                 }
-                "!dailyrewards" => {
-                    if STORE.user_exist(user_id) {
-                        let user = STORE.get_user(user_id)?;
+                result => return result,
+            }
+        }
+    }
 
-                        let client = BraiinsPoolClient::new(user.token.as_str(), proxy)?;
+    /// Network errors, server errors and rate limits: worth retrying
+    fn is_transient(error: &Error) -> bool {
+        match error {
+            Error::Matrix(matrix_sdk::Error::Http(error)) => match error {
+                HttpError::Reqwest(_) => true,
+                HttpError::Server(status) => status.is_server_error(),
+                error => matches!(
+                    error.client_api_error_kind(),
+                    Some(ErrorKind::LimitExceeded { .. })
+                ),
+            },
+            _ => false,
+        }
+    }
 
-                        let obj = client.daily_rewards().await?;
+    fn is_unknown_token(error: &matrix_sdk::Error) -> bool {
+        match error {
+            matrix_sdk::Error::Http(error) => matches!(
+                error.client_api_error_kind(),
+                Some(ErrorKind::UnknownToken { .. })
+            ),
+            _ => false,
+        }
+    }
 
-                        let mut msg = String::from("Daily Rewards\n\n");
+    async fn on_room_message(
+        &self,
+        event: OriginalSyncRoomMessageEvent,
+        room: &MatrixRoom,
+    ) -> Result<(), Error> {
+        if *event.sender.clone() == self.config().matrix.user_id {
+            return Ok(());
+        }
 
-                        for reward in obj {
-                            msg.push_str(&format!(
-                                "{}: {}\n",
-                                util::format_date(reward.date as i64, "%Y-%m-%d"),
-                                util::format_btc_to_sats(reward.total_reward)
-                            ));
-                        }
+        // Skip messages sent before the bot started
+        if event.origin_server_ts < *START_TIMESTAMP {
+            return Ok(());
+        }
 
-                        let content = RoomMessageEventContent::text_plain(msg);
-                        room.send(content, None).await?;
-                    } else {
-                        msg_content = "This account in not subscribed.";
-                    }
-                }
-                "!poolstatus" => {
-                    if STORE.user_exist(user_id) {
-                        let user = STORE.get_user(user_id)?;
-
-                        let client = BraiinsPoolClient::new(user.token.as_str(), proxy)?;
-
-                        let obj = client.pool_stats().await?;
-
-                        let mut msg = String::from("Pool Status\n\n");
-                        msg.push_str(&format!("Luck 10 blocks: {}\n", obj.luck_b10));
-                        msg.push_str(&format!("Luck 50 blocks: {}\n", obj.luck_b50));
-                        msg.push_str(&format!("Luck 250 blocks: {}\n", obj.luck_b250));
-                        msg.push_str(&format!(
-                            "Hashrate scoring: {}\n",
-                            util::format_gh_to_th(obj.pool_scoring_hash_rate)
-                        ));
-                        msg.push_str(&format!(
-                            "Active workers: {}\n",
-                            util::format_number(obj.pool_active_workers as usize)
-                        ));
-                        msg.push_str(&format!("Round probability: {}\n", obj.round_probability));
-
-                        let content = RoomMessageEventContent::text_plain(msg);
-                        room.send(content, None).await?;
-                    } else {
-                        msg_content = "This account in not subscribed.";
-                    }
+        if !self.is_active_room(room.room_id()) {
+            log::trace!("Message ignored, {} not in active rooms", room.room_id());
+            return Ok(());
+        }
+
+        let msg_body = match event.content.msgtype {
+            MessageType::Text(TextMessageEventContent { body, .. }) => body,
+            _ => return Ok(()),
+        };
+
+        let parsed: Option<(String, Vec<&str>)> = self.parse_command(&msg_body);
+
+        // Tokens are registered only once stored: never log the argument of the token commands
+        let logged_body: String = match &parsed {
+            Some((command, msg_splitted))
+                if command::TOKEN_COMMANDS.contains(&command.as_str()) =>
+            {
+                match msg_splitted.get(1) {
+                    Some(token) if !token.is_empty() => msg_body.replace(token, secret::MASK),
+                    _ => msg_body.clone(),
                 }
-                "!subscribe" => {
-                    let room_id: &str = room.room_id().as_str();
+            }
+            _ => msg_body.clone(),
+        };
+
+        log_fields!(
+            debug,
+            { "user": event.sender.as_str(), "room_id": room.room_id() },
+            "Message received: {}",
+            logged_body
+        );
+
+        let command: Option<String> = parsed
+            .map(|(command, _)| command)
+            .filter(|command| command.starts_with('!'));
+
+        let start = Instant::now();
+        let result = self
+            .handle_command(room, event.sender.as_str(), &event.event_id, &msg_body)
+            .await;
+
+        if let Some(command) = command {
+            let latency: Duration = start.elapsed();
+            log_fields!(
+                debug,
+                {
+                    "command": command,
+                    "user": event.sender.as_str(),
+                    "room_id": room.room_id(),
+                    "latency_ms": latency.as_millis() as u64,
+                },
+                "Command {} processed",
+                command
+            );
+            self.audit(
+                command,
+                event.sender.as_str(),
+                room.room_id(),
+                &result,
+                latency,
+            );
+        }
 
-                    if !STORE.user_with_room_exist(user_id, room_id) {
-                        if msg_splitted.len() >= 2 {
-                            let token = msg_splitted[1];
+        result
+    }
 
-                            if !token.is_empty() {
-                                STORE.create_user(user_id, room_id, token)?;
+    /// Record the processed command in the audit log
+    fn audit(
+        &self,
+        command: String,
+        sender: &str,
+        room_id: &str,
+        result: &Result<(), Error>,
+        latency: Duration,
+    ) {
+        let max_entries: u64 = self.config().audit_log_max_entries;
+        if max_entries == 0 {
+            return;
+        }
 
-                                let _ = room.redact(&event.event_id, None, None).await;
-                                msg_content = "Subscribed";
-                            } else {
-                                msg_content =
-                                "Please provide a token.\nTo subscribe send: !subscribe <token>";
-                            }
-                        } else {
-                            msg_content =
-                                "Please provide a token.\nTo subscribe send: !subscribe <token>";
-                        }
-                    } else {
-                        msg_content = "This account is already subscribed";
-                    }
+        let entry = AuditEntry {
+            timestamp: chrono::Utc::now().timestamp(),
+            command,
+            sender: sender.into(),
+            room_id: room_id.into(),
+            outcome: match result {
+                Ok(()) => String::from("ok"),
+                Err(error) => error.variant().into(),
+            },
+            latency_ms: latency.as_millis() as u64,
+        };
+
+        if let Err(error) = self.store.add_audit_entry(&entry, max_entries) {
+            log::error!("Impossible to save audit entry: {:?}", error);
+        }
+    }
+
+    /// Report a command error in the room, unless sending is what failed
+    async fn on_command_error(&self, room: &MatrixRoom, user_id: &str, error: Error) {
+        match error {
+            Error::RoomGone(room_id) => {
+                log::warn!("Room {} is no longer writable, leaving", room_id);
+                if let Err(error) = room.room.leave().await {
+                    log::error!("Impossible to leave room {}: {:?}", room_id, error);
                 }
-                "!unlink" => {
-                    if STORE.user_exist(user_id) {
-                        STORE.delete_user(user_id)?;
-                        msg_content = "Unlinked";
-                    } else {
-                        msg_content = "No token linked to this account";
-                    }
+                self.remove_room_subscriptions(&room_id);
+            }
+            Error::SendFailed(_) | Error::RoomNotJoined(_) | Error::OutboxClosed => {
+                log::error!("Impossible to reply in {}: {:?}", room.room_id(), error);
+            }
+            Error::BraiinsPool(pool::Error::Timeout) => {
+                log_fields!(
+                    warn,
+                    { "user": user_id, "room_id": room.room_id() },
+                    "BraiinsPool request of {} timed out",
+                    user_id
+                );
+                let lang: Lang = self.store.get_settings(user_id).lang;
+                if let Err(error) = room.send_text(i18n::t(Msg::ApiTimeout, lang, &[])).await {
+                    log::error!(
+                        "Impossible to send timeout in {}: {:?}",
+                        room.room_id(),
+                        error
+                    );
                 }
-                "!checktor" => {
-                    let client = BraiinsPoolClient::new("", proxy)?;
-
-                    let is_tor: bool = client.check_tor_connection().await?;
-
-                    if is_tor {
-                        msg_content = "Connected to Tor Network";
-                    } else {
-                        msg_content = "NOT connected to Tor Network";
-                    }
+            }
+            Error::BraiinsPool(pool::Error::Unavailable(description)) => {
+                log::warn!("BraiinsPool unavailable: {}", description);
+                let lang: Lang = self.store.get_settings(user_id).lang;
+                if let Err(error) = room
+                    .send_text(i18n::t(Msg::PoolUnavailable, lang, &[]))
+                    .await
+                {
+                    log::error!(
+                        "Impossible to send pool unavailable in {}: {:?}",
+                        room.room_id(),
+                        error
+                    );
                 }
-                "!help" => {
-                    let mut msg = String::new();
-                    msg.push_str("!userstatus - Get user status\n");
-                    msg.push_str("!workers - Get workers\n");
-                    msg.push_str("!dailyrewards - Get daily rewards\n");
-                    msg.push_str("!poolstatus - Get pool status\n");
-                    msg.push_str("!subscribe <token> - Subscribe with token\n");
-                    msg.push_str("!unlink - Unlink account from token\n");
-                    msg.push_str("!checktor - Check Tor connection\n");
-                    msg.push_str("!help - Help");
-
-                    let content = RoomMessageEventContent::text_plain(msg);
-                    room.send(content, None).await?;
+            }
+            Error::BraiinsPool(pool::Error::RateLimited(retry_after)) => {
+                log::warn!(
+                    "BraiinsPool rate limit reached by {}, retry in {}s",
+                    user_id,
+                    retry_after.as_secs()
+                );
+                let lang: Lang = self.store.get_settings(user_id).lang;
+                // Rounded up, "0 seconds" would invite to retry immediately
+                let secs: u64 = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                if let Err(error) = room
+                    .send_text(i18n::t(Msg::PoolRateLimited, lang, &[&secs]))
+                    .await
+                {
+                    log::error!(
+                        "Impossible to send rate limited in {}: {:?}",
+                        room.room_id(),
+                        error
+                    );
                 }
-                _ => {
-                    msg_content = "Invalid command";
+            }
+            error => {
+                if let Err(send_error) = room.send_text(format!("{:?}", error)).await {
+                    log::error!(
+                        "Impossible to send error {:?} in {}: {:?}",
+                        error,
+                        room.room_id(),
+                        send_error
+                    );
                 }
-            };
+            }
+        }
+    }
 
-            if !msg_content.is_empty() {
-                let content = RoomMessageEventContent::text_plain(msg_content);
-                room.send(content, None).await?;
+    /// Delete the subscriptions delivered to a room the bot can't write to anymore
+    fn remove_room_subscriptions(&self, room_id: &str) {
+        match self.store.delete_room_users(room_id) {
+            Ok(user_ids) => {
+                for user_id in user_ids.iter() {
+                    log::info!(
+                        "Removed subscription of {} (room {} gone)",
+                        user_id,
+                        room_id
+                    );
+                }
             }
+            Err(error) => log::error!(
+                "Impossible to remove subscriptions of room {}: {:?}",
+                room_id,
+                error
+            ),
+        }
+    }
 
-            log::trace!(
-                "{} command processed in {} ms",
-                command,
-                start.elapsed().as_millis()
-            );
+    /// Save the changes of a command. In dry run they are only logged.
+    fn write<F>(&self, action: &str, user_id: &str, write: F) -> Result<(), Error>
+    where
+        F: FnOnce(&DBStore) -> Result<(), crate::db::Error>,
+    {
+        if self.config().dry_run {
+            log::info!("Dry run, not saved: {} of {}", action, user_id);
+            return Ok(());
         }
 
+        write(&self.store)?;
         Ok(())
     }
+
+    fn is_admin(&self, user_id: &str) -> bool {
+        self.config()
+            .matrix
+            .admins
+            .iter()
+            .any(|admin| admin.as_str() == user_id)
+    }
+
+    /// Rooms where the commands are processed: all, unless `matrix.active_rooms` is set
+    fn is_active_room(&self, room_id: &str) -> bool {
+        let active_rooms = &self.config().matrix.active_rooms;
+        active_rooms.is_empty() || active_rooms.iter().any(|active| active.as_str() == room_id)
+    }
+
+    async fn send_to_admin_rooms(&self, msg: &str) {
+        for room_id in self.config().matrix.admin_rooms.iter() {
+            self.send_notification(room_id, msg).await;
+        }
+    }
+
+    /// Queue a background notification, logging failures. Return `true` if delivered.
+    async fn send_notification(&self, room_id: &str, msg: &str) -> bool {
+        let room_id = match <&RoomId>::try_from(room_id) {
+            Ok(room_id) => room_id,
+            Err(error) => {
+                log::error!("Invalid room id {}: {:?}", room_id, error);
+                return false;
+            }
+        };
+
+        let content = RoomMessageEventContent::text_plain(msg);
+        match self
+            .outbox
+            .send(room_id, content, Priority::Notification)
+            .await
+        {
+            Ok(()) => return true,
+            Err(Error::RoomGone(room_id)) => {
+                log::warn!("Room {} is no longer writable", room_id);
+                self.remove_room_subscriptions(&room_id);
+            }
+            Err(error) => log::error!("Impossible to send message to {}: {:?}", room_id, error),
+        }
+
+        false
+    }
+}
+
+/// Create a direct room with the user and invite it, return the room id.
+/// Fails if the server of the user refuses the invite.
+async fn create_dm(client: &Client, user_id: &str) -> Result<String, Error> {
+    let invite: [Box<UserId>; 1] = [Box::<UserId>::try_from(user_id)?];
+
+    let mut request = create_room::v3::Request::new();
+    request.invite = &invite;
+    request.is_direct = true;
+    request.preset = Some(RoomPreset::TrustedPrivateChat);
+
+    let response = client.create_room(request).await?;
+    log::info!("Direct room {} created with {}", response.room_id, user_id);
+    Ok(response.room_id.to_string())
+}
+
+/// Client builder for the configured homeserver and proxy, without stores
+fn client_builder(config: &Config) -> ClientBuilder {
+    let mut builder: ClientBuilder =
+        Client::builder().homeserver_url(config.matrix.homeserver_url.as_str());
+
+    if let Some(proxy) = &config.matrix.proxy {
+        builder = builder.proxy(proxy);
+    }
+
+    builder
 }
 
-impl From<bpns_rocksdb::Error> for Error {
-    fn from(err: bpns_rocksdb::Error) -> Self {
+/// Check that the homeserver answers to `/versions`, without login and with in-memory stores
+pub async fn check_homeserver(config: &Config) -> Result<Duration, Error> {
+    let start = Instant::now();
+    let client: Client = client_builder(config).build().await?;
+    client
+        .send(get_supported_versions::Request::new(), None)
+        .await
+        .map_err(|error| Error::Matrix(error.into()))?;
+    Ok(start.elapsed())
+}
+
+/// Invalidate the saved session on the homeserver and delete it from the database.
+/// Return the device id of the session, `None` if there isn't a saved session.
+pub async fn logout(config: &Config, store: &DBStore) -> Result<Option<String>, Error> {
+    let user_id: &str = config.matrix.user_id.as_str();
+
+    if !store.session_exist(user_id)? {
+        return Ok(None);
+    }
+
+    // The stores are not needed to invalidate the access token
+    let client: Client = client_builder(config).build().await?;
+
+    let saved = store.get_session(user_id)?;
+    // In use from now on
+    secret::register(saved.access_token.expose());
+    client
+        .restore_login(Session {
+            access_token: saved.access_token.expose().clone(),
+            user_id: Box::<UserId>::try_from(user_id)?,
+            device_id: saved.device_id.clone().into(),
+        })
+        .await?;
+
+    match client.send(logout::v3::Request::new(), None).await {
+        Ok(_) => (),
+        Err(error)
+            if matches!(
+                error.client_api_error_kind(),
+                Some(ErrorKind::UnknownToken { .. })
+            ) =>
+        {
+            log::warn!("Session already invalidated on the homeserver");
+        }
+        Err(error) => return Err(error.into()),
+    }
+
+    store.delete_session(user_id)?;
+
+    Ok(Some(saved.device_id))
+}
+
+impl Error {
+    /// Variant name, for the audit log
+    fn variant(&self) -> &'static str {
+        match self {
+            Error::Db(_) => "Db",
+            Error::Matrix(_) => "Matrix",
+            Error::MatrixClientBuilder(_) => "MatrixClientBuilder",
+            Error::MatrixStore(_) => "MatrixStore",
+            Error::MatrixCryptoStore(_) => "MatrixCryptoStore",
+            Error::BraiinsPool(_) => "BraiinsPool",
+            Error::InvalidUserId(_) => "InvalidUserId",
+            Error::RoomNotJoined(_) => "RoomNotJoined",
+            Error::RoomGone(_) => "RoomGone",
+            Error::SendFailed(_) => "SendFailed",
+            Error::OutboxClosed => "OutboxClosed",
+            Error::PasswordRequired => "PasswordRequired",
+            Error::SessionNotSaved => "SessionNotSaved",
+            Error::DeviceKeysMissing(_) => "DeviceKeysMissing",
+            Error::CryptoStoreNotEncrypted => "CryptoStoreNotEncrypted",
+            Error::CryptoStorePassphraseRequired => "CryptoStorePassphraseRequired",
+            Error::CryptoStoreWrongPassphrase(_) => "CryptoStoreWrongPassphrase",
+        }
+    }
+}
+
+impl From<crate::db::Error> for Error {
+    fn from(err: crate::db::Error) -> Self {
         Error::Db(err)
     }
 }
@@ -386,6 +912,12 @@ impl From<matrix_sdk::Error> for Error {
     }
 }
 
+impl From<HttpError> for Error {
+    fn from(err: HttpError) -> Self {
+        Error::Matrix(matrix_sdk::Error::Http(err))
+    }
+}
+
 impl From<matrix_sdk::ClientBuildError> for Error {
     fn from(err: matrix_sdk::ClientBuildError) -> Self {
         Error::MatrixClientBuilder(err)
@@ -404,8 +936,14 @@ impl From<matrix_sdk::store::OpenStoreError> for Error {
     }
 }
 
-impl From<braiinspool::client::Error> for Error {
-    fn from(err: braiinspool::client::Error) -> Self {
+impl From<pool::Error> for Error {
+    fn from(err: pool::Error) -> Self {
         Error::BraiinsPool(err)
     }
 }
+
+impl From<IdParseError> for Error {
+    fn from(err: IdParseError) -> Self {
+        Error::InvalidUserId(err)
+    }
+}
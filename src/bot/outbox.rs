@@ -0,0 +1,240 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Outgoing message queue
+//!
+//! All text messages are sent by a single task, which applies the configured
+//! messages-per-second limit and retries sends rejected with `M_LIMIT_EXCEEDED`.
+//! Other transient failures are retried once. Command replies are always sent
+//! before background notifications. In dry run the messages are only logged.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Client;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+
+use super::Error;
+use crate::config::ConfigHandle;
+
+const MAX_RETRIES: u8 = 5;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+enum Failure {
+    /// `M_LIMIT_EXCEEDED`, retry after the delay
+    RateLimited(Duration),
+    /// The bot can't write to the room anymore (kicked, banned, room deleted, ...)
+    RoomGone,
+    /// Network errors, server errors, ...
+    Transient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Reply to a command
+    Reply,
+    /// Background notification (alerts, admin messages, ...)
+    Notification,
+}
+
+struct Message {
+    room_id: Box<RoomId>,
+    content: RoomMessageEventContent,
+    result: oneshot::Sender<Result<(), Error>>,
+}
+
+type Receivers = (UnboundedReceiver<Message>, UnboundedReceiver<Message>);
+
+#[derive(Clone)]
+pub struct Outbox {
+    replies: UnboundedSender<Message>,
+    notifications: UnboundedSender<Message>,
+    receivers: Arc<Mutex<Option<Receivers>>>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        let (replies, replies_rx) = mpsc::unbounded_channel();
+        let (notifications, notifications_rx) = mpsc::unbounded_channel();
+
+        Self {
+            replies,
+            notifications,
+            receivers: Arc::new(Mutex::new(Some((replies_rx, notifications_rx)))),
+            depth: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Spawn the sender task. Calls after the first one are ignored.
+    pub fn start(&self, client: Client, config: ConfigHandle) {
+        let receivers = match self.receivers.lock() {
+            Ok(mut receivers) => receivers.take(),
+            Err(_) => None,
+        };
+
+        let (mut replies, mut notifications) = match receivers {
+            Some(receivers) => receivers,
+            None => return,
+        };
+
+        let depth = self.depth.clone();
+
+        tokio::spawn(async move {
+            let mut last_sent: Option<Instant> = None;
+
+            loop {
+                let message: Message = tokio::select! {
+                    biased;
+                    Some(message) = replies.recv() => message,
+                    Some(message) = notifications.recv() => message,
+                    else => break,
+                };
+
+                let queued: usize = depth.fetch_sub(1, Ordering::SeqCst) - 1;
+                log::trace!("Outbox queue depth: {}", queued);
+
+                // Read at each message, to follow the config reloads
+                let current = config.get();
+
+                if current.dry_run {
+                    log::info!(
+                        "Dry run, not sent to {}: {}",
+                        message.room_id,
+                        message.content.msgtype.body()
+                    );
+                    let _ = message.result.send(Ok(()));
+                    continue;
+                }
+
+                let min_interval: Duration = min_interval(current.matrix.max_messages_per_sec);
+
+                if let Some(last_sent) = last_sent {
+                    let elapsed: Duration = last_sent.elapsed();
+                    if elapsed < min_interval {
+                        sleep(min_interval - elapsed).await;
+                    }
+                }
+
+                let result = deliver(&client, &message.room_id, message.content).await;
+                last_sent = Some(Instant::now());
+
+                let _ = message.result.send(result);
+            }
+
+            log::warn!("Outbox closed");
+        });
+    }
+
+    /// Queue a text message and wait until it's delivered
+    pub async fn send(
+        &self,
+        room_id: &RoomId,
+        content: RoomMessageEventContent,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let message = Message {
+            room_id: room_id.to_owned(),
+            content,
+            result: tx,
+        };
+
+        let queue = match priority {
+            Priority::Reply => &self.replies,
+            Priority::Notification => &self.notifications,
+        };
+
+        let queued: usize = self.depth.fetch_add(1, Ordering::SeqCst) + 1;
+        if queue.send(message).is_err() {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::OutboxClosed);
+        }
+
+        log::trace!("Outbox queue depth: {}", queued);
+
+        rx.await.map_err(|_| Error::OutboxClosed)?
+    }
+}
+
+/// Min interval between two messages, zero if not limited
+fn min_interval(max_messages_per_sec: u32) -> Duration {
+    if max_messages_per_sec > 0 {
+        Duration::from_secs(1) / max_messages_per_sec
+    } else {
+        Duration::ZERO
+    }
+}
+
+async fn deliver(
+    client: &Client,
+    room_id: &RoomId,
+    content: RoomMessageEventContent,
+) -> Result<(), Error> {
+    let room = client
+        .get_joined_room(room_id)
+        .ok_or_else(|| Error::RoomNotJoined(room_id.to_string()))?;
+
+    let mut attempt: u8 = 0;
+    let mut retried: bool = false;
+
+    loop {
+        match room.send(content.clone(), None).await {
+            Ok(_) => return Ok(()),
+            Err(error) => {
+                let retry_after: Duration = match classify(&error) {
+                    Failure::RateLimited(retry_after) => retry_after,
+                    Failure::RoomGone => return Err(Error::RoomGone(room_id.to_string())),
+                    Failure::Transient if !retried => {
+                        log::warn!("Failed to send to {} ({:?}), retrying once", room_id, error);
+                        retried = true;
+                        sleep(TRANSIENT_RETRY_DELAY).await;
+                        continue;
+                    }
+                    Failure::Transient => return Err(Error::SendFailed(error)),
+                };
+
+                attempt += 1;
+
+                if attempt > MAX_RETRIES {
+                    log::error!(
+                        "Rate limited sending to {}, giving up after {} retries",
+                        room_id,
+                        MAX_RETRIES
+                    );
+                    return Err(Error::SendFailed(error));
+                }
+
+                log::warn!(
+                    "Rate limited sending to {}, retrying in {}ms ({}/{})",
+                    room_id,
+                    retry_after.as_millis(),
+                    attempt,
+                    MAX_RETRIES
+                );
+
+                sleep(retry_after).await;
+            }
+        }
+    }
+}
+
+fn classify(error: &matrix_sdk::Error) -> Failure {
+    match error {
+        matrix_sdk::Error::Http(error) => match error.client_api_error_kind() {
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => {
+                Failure::RateLimited(retry_after_ms.unwrap_or(DEFAULT_RETRY_AFTER))
+            }
+            Some(ErrorKind::Forbidden) | Some(ErrorKind::NotFound) => Failure::RoomGone,
+            _ => Failure::Transient,
+        },
+        _ => Failure::Transient,
+    }
+}
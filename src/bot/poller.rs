@@ -0,0 +1,441 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use braiinspool::model::Worker;
+use chrono::NaiveDate;
+use rand::Rng;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use super::alert::{self, Alert, AlertState, Scope};
+use super::{Bot, Error};
+use crate::db::{User, UserSettings};
+use crate::i18n::{self, Msg};
+use crate::pool;
+use crate::util;
+
+pub fn spawn(bot: Bot) {
+    tokio::spawn(async move {
+        log::info!("Background poller started");
+
+        loop {
+            // Read at each cycle, to follow the config reloads
+            let interval = Duration::from_secs(bot.config().poller.interval_secs);
+            let cycle_start = Instant::now();
+            let users: Vec<(String, User)> = bot.store.iter_users().collect();
+            let mut alerts: Vec<Alert> = Vec::new();
+
+            let offsets: Vec<Duration> = stagger(users.len(), interval);
+            log::debug!(
+                "Polling {} users over {}s, one every {}ms",
+                users.len(),
+                interval.as_secs(),
+                offsets.get(1).map(|step| step.as_millis()).unwrap_or(0)
+            );
+
+            for ((user_id, user), offset) in users.iter().zip(offsets.into_iter()) {
+                sleep_until(cycle_start + offset).await;
+
+                match sample_hashrate(&bot, user_id, user.token.expose()).await {
+                    Ok(()) => {
+                        if let Err(error) = bot.store.reset_auth_failures(user_id) {
+                            log::error!("Impossible to reset auth failures: {:?}", error);
+                        }
+
+                        if let Err(error) = save_rewards(&bot, user_id, user.token.expose()).await {
+                            log::error!("Impossible to save rewards of {}: {:?}", user_id, error);
+                        }
+                    }
+                    Err(Error::BraiinsPool(error)) if pool::is_auth_error(&error) => {
+                        match on_auth_failure(&bot, user_id, user) {
+                            Ok(Some(alert)) => alerts.push(alert),
+                            Ok(None) => (),
+                            Err(error) => {
+                                log::error!("Impossible to track auth failure: {:?}", error)
+                            }
+                        }
+                    }
+                    Err(Error::BraiinsPool(pool::Error::RateLimited(retry_after))) => {
+                        // The token is in cooldown: the next calls fail without reaching the pool
+                        log::warn!(
+                            "Polling of {} rate limited, skipped for {}s",
+                            user_id,
+                            retry_after.as_secs()
+                        );
+                        continue;
+                    }
+                    Err(error) => {
+                        log::error!("Impossible to sample hashrate of {}: {:?}", user_id, error)
+                    }
+                }
+
+                let settings: UserSettings = bot.store.get_settings(user_id);
+                if bot.config().poller.worker_alerts && settings.notifications {
+                    match check_workers(&bot, user_id, user, &settings).await {
+                        Ok(worker_alerts) => alerts.extend(worker_alerts),
+                        Err(error) => {
+                            log::error!("Impossible to check workers of {}: {:?}", user_id, error)
+                        }
+                    }
+                }
+            }
+
+            if let Some((_, user)) = users.first().filter(|_| bot.config().poller.pool_alerts) {
+                match round_started(&bot, user.token.expose()).await {
+                    Ok(round_started) => {
+                        alerts.extend(new_block_alerts(&bot, &users, round_started))
+                    }
+                    Err(error) => log::error!("Impossible to check pool stats: {:?}", error),
+                }
+            }
+
+            let now: i64 = chrono::Utc::now().timestamp();
+            alerts.retain(|alert| !bot.store.get_settings(&alert.user_id).is_muted(now));
+
+            for delivery in alert::plan(&alerts) {
+                if !bot
+                    .send_notification(&delivery.room_id, &delivery.message)
+                    .await
+                {
+                    // Not saved, so the alert is retried on the next cycle
+                    continue;
+                }
+
+                for alert in delivery.sources.iter().map(|i| &alerts[*i]) {
+                    if let Some(state) = &alert.state {
+                        let result = match state.value {
+                            Some(value) => {
+                                bot.store
+                                    .set_notification(&alert.user_id, &state.key, value)
+                            }
+                            None => bot.store.delete_notification(&alert.user_id, &state.key),
+                        };
+
+                        if let Err(error) = result {
+                            log::error!("Impossible to save notification state: {:?}", error);
+                        }
+                    }
+                }
+            }
+
+            let jitter = Duration::from_secs(
+                rand::thread_rng().gen_range(0..=bot.config().poller.jitter_secs),
+            );
+            log::debug!("Next poll in {}s", (interval + jitter).as_secs());
+            sleep_until(cycle_start + interval + jitter).await;
+        }
+    });
+}
+
+async fn sample_hashrate(bot: &Bot, user_id: &str, token: &str) -> Result<(), Error> {
+    let client = bot.pool.client(token)?;
+    let obj = client.user_profile().await?;
+    bot.store.add_hashrate_sample(user_id, obj.hash_rate_5m)?;
+    log::debug!("Hashrate sample saved for {}", user_id);
+    Ok(())
+}
+
+/// Copy the daily rewards returned by the API to the local history, to keep them
+/// after they leave the API window. Only the completed days are saved, and the API is
+/// called only if yesterday is missing.
+async fn save_rewards(bot: &Bot, user_id: &str, token: &str) -> Result<(), Error> {
+    let today: NaiveDate = chrono::Utc::now().naive_utc().date();
+
+    if bot.store.last_reward_date(user_id) >= today.pred_opt() {
+        return Ok(());
+    }
+
+    let client = bot.pool.client(token)?;
+    let mut saved: usize = 0;
+
+    for reward in client.daily_rewards().await?.into_iter() {
+        match util::timestamp_to_utc_date(reward.date as i64) {
+            Some(day) if day < today => {
+                bot.store.upsert_reward(user_id, day, reward.total_reward)?;
+                saved += 1;
+            }
+            Some(_) => (),
+            None => log::warn!("Invalid reward date {} for {}", reward.date, user_id),
+        }
+    }
+
+    log::debug!("{} daily rewards saved for {}", saved, user_id);
+    Ok(())
+}
+
+/// Spread the polls of `users` evenly over `interval`, to avoid bursts of requests
+fn stagger(users: usize, interval: Duration) -> Vec<Duration> {
+    (0..users)
+        .map(|i| interval * i as u32 / users as u32)
+        .collect()
+}
+
+/// Check the stale shares (if enabled by the user) and the zero hashrate of the workers
+async fn check_workers(
+    bot: &Bot,
+    user_id: &str,
+    user: &User,
+    settings: &UserSettings,
+) -> Result<Vec<Alert>, Error> {
+    let client = bot.pool.client(user.token.expose())?;
+    let mut workers: Vec<(String, Worker)> = client.workers().await?.into_iter().collect();
+    workers.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut alerts: Vec<Alert> = Vec::new();
+
+    if settings.stale_alert_mins > 0 {
+        alerts.extend(stale_share_alerts(bot, user_id, user, settings, &workers)?);
+    }
+
+    alerts.extend(zero_hashrate_alerts(
+        bot, user_id, user, settings, &workers,
+    )?);
+
+    Ok(alerts)
+}
+
+/// Alert for the workers with a stale last share, regardless of the reported state.
+/// A worker is alerted once until its last share changes.
+fn stale_share_alerts(
+    bot: &Bot,
+    user_id: &str,
+    user: &User,
+    settings: &UserSettings,
+    workers: &[(String, Worker)],
+) -> Result<Vec<Alert>, Error> {
+    let now: i64 = chrono::Utc::now().timestamp();
+    let mut alerts: Vec<Alert> = Vec::new();
+
+    for (name, worker) in workers.iter() {
+        let last_share: i64 = worker.last_share as i64;
+        let key: String = format!("stale:{}", name);
+        let fired: Option<i64> = bot
+            .store
+            .get_notification(user_id, &key)
+            .map(|notification| notification.value);
+
+        match stale_age(last_share, now, settings.stale_alert_mins) {
+            Some(age_mins) if fired != Some(last_share) => alerts.push(Alert {
+                user_id: user_id.into(),
+                room_id: user.room_id.clone(),
+                scope: Scope::Account,
+                message: i18n::t(
+                    Msg::StaleShareAlert,
+                    settings.lang,
+                    &[
+                        &worker_name(name),
+                        &util::format_duration(age_mins as u64 * 60),
+                    ],
+                ),
+                state: Some(AlertState {
+                    key,
+                    value: Some(last_share),
+                }),
+            }),
+            Some(_) => (),
+            // Recovered: the state is deleted only once the recovery is delivered,
+            // then the next stale share alerts again
+            None if fired.is_some() && settings.recovery_alerts => alerts.push(Alert {
+                user_id: user_id.into(),
+                room_id: user.room_id.clone(),
+                scope: Scope::Account,
+                message: i18n::t(Msg::WorkerRecovered, settings.lang, &[&worker_name(name)]),
+                state: Some(AlertState { key, value: None }),
+            }),
+            None if fired.is_some() => bot.store.delete_notification(user_id, &key)?,
+            None => (),
+        }
+    }
+
+    Ok(alerts)
+}
+
+/// Alert for the workers with a 5m hashrate at or below `threshold` (Gh/s), even if
+/// their state is `ok`. A worker is alerted once until its hashrate goes above it.
+fn zero_hashrate_alerts(
+    bot: &Bot,
+    user_id: &str,
+    user: &User,
+    settings: &UserSettings,
+    workers: &[(String, Worker)],
+) -> Result<Vec<Alert>, Error> {
+    let threshold: u64 = bot.config().poller.zero_hashrate_threshold;
+    let mut alerts: Vec<Alert> = Vec::new();
+
+    for (name, worker) in workers.iter() {
+        let hash_rate: f64 = worker.hash_rate_5m;
+        let key: String = format!("zero:{}", name);
+        let fired: bool = bot.store.get_notification(user_id, &key).is_some();
+
+        match (is_zero_hashrate(hash_rate, threshold), fired) {
+            (true, false) => alerts.push(Alert {
+                user_id: user_id.into(),
+                room_id: user.room_id.clone(),
+                scope: Scope::Account,
+                message: i18n::t(
+                    Msg::ZeroHashrateAlert,
+                    settings.lang,
+                    &[&worker_name(name), &util::format_hashrate(hash_rate)],
+                ),
+                state: Some(AlertState {
+                    key,
+                    value: Some(1),
+                }),
+            }),
+            (false, true) if settings.recovery_alerts => alerts.push(Alert {
+                user_id: user_id.into(),
+                room_id: user.room_id.clone(),
+                scope: Scope::Account,
+                message: i18n::t(Msg::WorkerRecovered, settings.lang, &[&worker_name(name)]),
+                state: Some(AlertState { key, value: None }),
+            }),
+            (false, true) => bot.store.delete_notification(user_id, &key)?,
+            _ => (),
+        }
+    }
+
+    Ok(alerts)
+}
+
+/// A 5m hashrate (Gh/s) at or below `threshold` is reported as zero: the worker may be
+/// `ok` but it's not hashing
+pub(super) fn is_zero_hashrate(hash_rate_5m: f64, threshold: u64) -> bool {
+    hash_rate_5m <= threshold as f64
+}
+
+/// Return the age in minutes of the last share, if older than `threshold_mins`
+fn stale_age(last_share: i64, now: i64, threshold_mins: u32) -> Option<i64> {
+    let age_mins: i64 = (now - last_share) / 60;
+
+    if age_mins > threshold_mins as i64 {
+        Some(age_mins)
+    } else {
+        None
+    }
+}
+
+/// Strip the account name from `account.worker`
+pub(super) fn worker_name(name: &str) -> &str {
+    name.split_once('.')
+        .map(|(_, worker)| worker)
+        .unwrap_or(name)
+}
+
+/// Start of the current pool round: it changes when a block is found
+async fn round_started(bot: &Bot, token: &str) -> Result<i64, Error> {
+    let client = bot.pool.client(token)?;
+    let obj = client.pool_stats().await?;
+    Ok(obj.round_started as i64)
+}
+
+/// Alert the users that haven't been notified of the current round yet.
+/// New users start from the current round, without alert.
+fn new_block_alerts(bot: &Bot, users: &[(String, User)], round_started: i64) -> Vec<Alert> {
+    let mut alerts: Vec<Alert> = Vec::new();
+
+    for (user_id, user) in users.iter() {
+        if !bot.store.get_settings(user_id).notifications {
+            continue;
+        }
+
+        match bot.store.get_notification(user_id, "block") {
+            Some(notification) if notification.value != round_started => alerts.push(Alert {
+                user_id: user_id.clone(),
+                room_id: user.room_id.clone(),
+                scope: Scope::Pool,
+                message: String::from("New block found by the pool!"),
+                state: Some(AlertState {
+                    key: String::from("block"),
+                    value: Some(round_started),
+                }),
+            }),
+            Some(_) => (),
+            None => {
+                if let Err(error) = bot.store.set_notification(user_id, "block", round_started) {
+                    log::error!("Impossible to save notification state: {:?}", error);
+                }
+            }
+        }
+    }
+
+    alerts
+}
+
+/// Count the auth failure and, once the threshold is reached, warn the user
+/// (and unlink the token if enabled)
+fn on_auth_failure(bot: &Bot, user_id: &str, user: &User) -> Result<Option<Alert>, Error> {
+    let failures: u32 = bot.store.increment_auth_failures(user_id)?;
+    let threshold: u32 = bot.config().braiinspool.auth_failure_threshold;
+
+    log::warn!("Auth failure {} for {}", failures, user_id);
+
+    if failures != threshold {
+        return Ok(None);
+    }
+
+    let mut message = String::from("Your token appears invalid; please re-subscribe");
+
+    if bot.config().braiinspool.auth_failure_unlink {
+        bot.store.delete_user(user_id)?;
+        message.push_str(" (token unlinked)");
+        log::info!(
+            "Token of {} unlinked after {} auth failures",
+            user_id,
+            failures
+        );
+    }
+
+    Ok(Some(Alert {
+        user_id: user_id.into(),
+        room_id: user.room_id.clone(),
+        scope: Scope::Account,
+        message,
+        state: None,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stale_age() {
+        let now: i64 = 10_000;
+
+        assert_eq!(stale_age(now - 40 * 60, now, 30), Some(40));
+        assert_eq!(stale_age(now - 30 * 60, now, 30), None);
+        assert_eq!(stale_age(now - 60, now, 30), None);
+        assert_eq!(stale_age(now, now, 0), None);
+    }
+
+    #[test]
+    fn test_stagger() {
+        let interval = Duration::from_secs(300);
+
+        assert!(stagger(0, interval).is_empty());
+        assert_eq!(stagger(1, interval), vec![Duration::ZERO]);
+        assert_eq!(
+            stagger(4, interval),
+            vec![
+                Duration::ZERO,
+                Duration::from_secs(75),
+                Duration::from_secs(150),
+                Duration::from_secs(225)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_zero_hashrate() {
+        assert!(is_zero_hashrate(0.0, 0));
+        assert!(!is_zero_hashrate(0.1, 0));
+        assert!(is_zero_hashrate(500.0, 1000));
+        assert!(is_zero_hashrate(1000.0, 1000));
+        assert!(!is_zero_hashrate(96000.0, 1000));
+    }
+
+    #[test]
+    fn test_worker_name() {
+        assert_eq!(worker_name("alice.s19"), "s19");
+        assert_eq!(worker_name("s19"), "s19");
+    }
+}
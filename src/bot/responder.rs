@@ -0,0 +1,202 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use async_trait::async_trait;
+use matrix_sdk::attachment::AttachmentConfig;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::api::client::account::whoami;
+use matrix_sdk::ruma::api::client::message::send_message_event;
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::{EventId, RoomId, TransactionId, UserId};
+use matrix_sdk::Client;
+use mime::Mime;
+
+use super::outbox::{Outbox, Priority};
+use super::{create_dm, Error};
+
+/// Power level of the room admins
+const ADMIN_POWER_LEVEL: i64 = 100;
+
+/// Room where a command was received
+#[async_trait]
+pub trait Responder: Send + Sync {
+    fn room_id(&self) -> &str;
+    async fn send_text(&self, msg: String) -> Result<(), Error>;
+    /// Message with a plain text body and an HTML formatted body
+    async fn send_html(&self, plain: String, html: String) -> Result<(), Error>;
+    async fn send_file(&self, name: &str, content_type: &Mime, data: &[u8]) -> Result<(), Error>;
+    async fn redact(&self, event_id: &EventId) -> Result<(), Error>;
+    /// Check that the homeserver is reachable and the session is valid
+    async fn whoami(&self) -> Result<(), Error>;
+    /// Canonical alias or name of a room known by the bot, if any
+    async fn room_name(&self, room_id: &str) -> Option<String>;
+    /// Check if the user has the admin power level in this room
+    async fn is_room_admin(&self, user_id: &str) -> bool;
+    /// More than two members, and not marked as direct room
+    async fn is_group(&self) -> bool;
+    /// Check that the bot is in the direct room and the user joined or is invited
+    async fn is_dm_open(&self, room_id: &str, user_id: &str) -> bool;
+    /// Create a direct room with the user, return its id
+    async fn create_dm(&self, user_id: &str) -> Result<String, Error>;
+    /// Reply in another room, ex. a direct room
+    async fn send_text_in(&self, room_id: &str, msg: String) -> Result<(), Error>;
+}
+
+pub struct MatrixRoom {
+    pub client: Client,
+    pub room: Joined,
+    pub outbox: Outbox,
+    /// Log the uploads and redactions instead of sending them. Text messages
+    /// are handled by the [`Outbox`].
+    pub dry_run: bool,
+}
+
+#[async_trait]
+impl Responder for MatrixRoom {
+    fn room_id(&self) -> &str {
+        self.room.room_id().as_str()
+    }
+
+    async fn send_text(&self, msg: String) -> Result<(), Error> {
+        let content = RoomMessageEventContent::text_plain(msg);
+        self.outbox
+            .send(self.room.room_id(), content, Priority::Reply)
+            .await
+    }
+
+    async fn send_html(&self, plain: String, html: String) -> Result<(), Error> {
+        let content = RoomMessageEventContent::text_html(plain, html);
+        self.outbox
+            .send(self.room.room_id(), content, Priority::Reply)
+            .await
+    }
+
+    async fn send_file(&self, name: &str, content_type: &Mime, data: &[u8]) -> Result<(), Error> {
+        if self.dry_run {
+            log::info!(
+                "Dry run, not sent to {}: file {} ({}, {} bytes)",
+                self.room_id(),
+                name,
+                content_type,
+                data.len()
+            );
+            return Ok(());
+        }
+
+        self.room
+            .send_attachment(name, content_type, &mut &data[..], AttachmentConfig::new())
+            .await
+            .map_err(Error::SendFailed)?;
+        Ok(())
+    }
+
+    async fn redact(&self, event_id: &EventId) -> Result<(), Error> {
+        if self.dry_run {
+            log::info!(
+                "Dry run, not sent to {}: redaction of {}",
+                self.room_id(),
+                event_id
+            );
+            return Ok(());
+        }
+
+        self.room.redact(event_id, None, None).await?;
+        Ok(())
+    }
+
+    async fn whoami(&self) -> Result<(), Error> {
+        self.client.send(whoami::v3::Request::new(), None).await?;
+        Ok(())
+    }
+
+    async fn room_name(&self, room_id: &str) -> Option<String> {
+        let room_id = <&RoomId>::try_from(room_id).ok()?;
+        let room = self.client.get_room(room_id)?;
+
+        match room.canonical_alias() {
+            Some(alias) => Some(alias.to_string()),
+            None => room.name(),
+        }
+    }
+
+    async fn is_room_admin(&self, user_id: &str) -> bool {
+        let user_id = match <&UserId>::try_from(user_id) {
+            Ok(user_id) => user_id,
+            Err(_) => return false,
+        };
+
+        match self.room.get_member(user_id).await {
+            Ok(Some(member)) => member.power_level() >= ADMIN_POWER_LEVEL,
+            Ok(None) => false,
+            Err(error) => {
+                log::error!("Impossible to get member {}: {:?}", user_id, error);
+                false
+            }
+        }
+    }
+
+    async fn is_group(&self) -> bool {
+        !self.room.is_direct() && self.room.joined_members_count() > 2
+    }
+
+    async fn is_dm_open(&self, room_id: &str, user_id: &str) -> bool {
+        let (room_id, user_id) = match (<&RoomId>::try_from(room_id), <&UserId>::try_from(user_id))
+        {
+            (Ok(room_id), Ok(user_id)) => (room_id, user_id),
+            _ => return false,
+        };
+
+        let room = match self.client.get_joined_room(room_id) {
+            Some(room) => room,
+            None => return false,
+        };
+
+        match room.get_member(user_id).await {
+            Ok(Some(member)) => matches!(
+                member.membership(),
+                MembershipState::Join | MembershipState::Invite
+            ),
+            Ok(None) => false,
+            Err(error) => {
+                log::error!("Impossible to get member {}: {:?}", user_id, error);
+                false
+            }
+        }
+    }
+
+    async fn create_dm(&self, user_id: &str) -> Result<String, Error> {
+        if self.dry_run {
+            log::info!("Dry run, not created: direct room with {}", user_id);
+            return Ok(self.room_id().to_string());
+        }
+
+        create_dm(&self.client, user_id).await
+    }
+
+    async fn send_text_in(&self, room_id: &str, msg: String) -> Result<(), Error> {
+        let room_id = <&RoomId>::try_from(room_id)?;
+        let content = RoomMessageEventContent::text_plain(msg);
+
+        if self.client.get_joined_room(room_id).is_some() {
+            return self.outbox.send(room_id, content, Priority::Reply).await;
+        }
+
+        // Just created: the client knows it only after the next sync, that can't start
+        // before this command is processed
+        if self.dry_run {
+            log::info!(
+                "Dry run, not sent to {}: {}",
+                room_id,
+                content.msgtype.body()
+            );
+            return Ok(());
+        }
+
+        let txn_id = TransactionId::new();
+        let request = send_message_event::v3::Request::new(room_id, &txn_id, &content)
+            .map_err(matrix_sdk::Error::from)?;
+        self.client.send(request, None).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Parsing and formatting of the `!settings` keys
+
+use std::str::FromStr;
+
+use chrono_tz::Tz;
+
+use crate::db::UserSettings;
+use crate::i18n::Lang;
+use crate::util::{self, Unit};
+
+/// Available setting keys
+pub const KEYS: &[&str] = &[
+    "notifications",
+    "lang",
+    "unit",
+    "stale_alert_mins",
+    "recovery_alerts",
+    "timezone",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    UnknownKey,
+    /// Invalid value, contains the accepted values
+    InvalidValue(&'static str),
+}
+
+/// Update a setting, validating the value
+pub fn set(settings: &mut UserSettings, key: &str, value: &str) -> Result<(), Error> {
+    match key {
+        "notifications" => {
+            settings.notifications = parse_bool(value).ok_or(Error::InvalidValue("on, off"))?
+        }
+        "lang" => {
+            settings.lang = Lang::from_str(value).map_err(|_| Error::InvalidValue(Lang::CODES))?
+        }
+        "unit" => {
+            settings.unit = Unit::from_str(value).map_err(|_| Error::InvalidValue(Unit::CODES))?
+        }
+        "stale_alert_mins" => {
+            settings.stale_alert_mins =
+                parse_minutes(value).ok_or(Error::InvalidValue("<minutes>, off"))?
+        }
+        "recovery_alerts" => {
+            settings.recovery_alerts = parse_bool(value).ok_or(Error::InvalidValue("on, off"))?
+        }
+        "timezone" => {
+            settings.timezone =
+                match value.to_lowercase().as_str() {
+                    "default" => None,
+                    _ => Some(Tz::from_str(value).map_err(|_| {
+                        Error::InvalidValue("<IANA name, ex. Europe/Rome>, default")
+                    })?),
+                }
+        }
+        _ => return Err(Error::UnknownKey),
+    }
+
+    Ok(())
+}
+
+/// Format all settings as `key: value` lines
+pub fn describe(settings: &UserSettings) -> String {
+    let mut msg = String::new();
+    msg.push_str(&format!(
+        "notifications: {}\n",
+        format_bool(settings.notifications)
+    ));
+    msg.push_str(&format!("lang: {}\n", settings.lang.code()));
+    msg.push_str(&format!("unit: {}\n", settings.unit.code()));
+    msg.push_str(&format!(
+        "stale_alert_mins: {}\n",
+        format_minutes(settings.stale_alert_mins)
+    ));
+    msg.push_str(&format!(
+        "recovery_alerts: {}\n",
+        format_bool(settings.recovery_alerts)
+    ));
+    msg.push_str(&format!(
+        "timezone: {}\n",
+        settings.timezone.map(|tz| tz.name()).unwrap_or("default")
+    ));
+    msg
+}
+
+/// Parse a number of minutes or a duration (ex. `1h30m`), `off` = `0`
+pub fn parse_minutes(value: &str) -> Option<u32> {
+    match value.to_lowercase().as_str() {
+        "off" => Some(0),
+        value => match value.parse() {
+            Ok(mins) => Some(mins),
+            Err(_) => {
+                let duration = util::parse_duration(value).ok()?;
+                u32::try_from(duration.as_secs() / 60).ok()
+            }
+        },
+    }
+}
+
+fn format_minutes(value: u32) -> String {
+    match value {
+        0 => String::from("off"),
+        value => value.to_string(),
+    }
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn format_bool(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut settings = UserSettings::default();
+        assert!(settings.notifications);
+
+        assert_eq!(set(&mut settings, "notifications", "off"), Ok(()));
+        assert!(!settings.notifications);
+
+        assert_eq!(set(&mut settings, "notifications", "ON"), Ok(()));
+        assert!(settings.notifications);
+
+        assert_eq!(
+            set(&mut settings, "notifications", "maybe"),
+            Err(Error::InvalidValue("on, off"))
+        );
+        assert_eq!(set(&mut settings, "lang", "it"), Ok(()));
+        assert_eq!(settings.lang, Lang::It);
+        assert_eq!(
+            set(&mut settings, "lang", "xx"),
+            Err(Error::InvalidValue("en, it"))
+        );
+
+        assert_eq!(set(&mut settings, "stale_alert_mins", "30"), Ok(()));
+        assert_eq!(settings.stale_alert_mins, 30);
+        assert_eq!(set(&mut settings, "stale_alert_mins", "1h30m"), Ok(()));
+        assert_eq!(settings.stale_alert_mins, 90);
+        assert_eq!(set(&mut settings, "stale_alert_mins", "off"), Ok(()));
+        assert_eq!(settings.stale_alert_mins, 0);
+        assert_eq!(
+            set(&mut settings, "stale_alert_mins", "-1"),
+            Err(Error::InvalidValue("<minutes>, off"))
+        );
+
+        assert_eq!(set(&mut settings, "timezone", "Europe/Rome"), Ok(()));
+        assert_eq!(settings.timezone, Some(Tz::Europe__Rome));
+        assert_eq!(
+            set(&mut settings, "timezone", "Mars/Olympus"),
+            Err(Error::InvalidValue("<IANA name, ex. Europe/Rome>, default"))
+        );
+        assert_eq!(settings.timezone, Some(Tz::Europe__Rome));
+        assert_eq!(set(&mut settings, "timezone", "Default"), Ok(()));
+        assert_eq!(settings.timezone, None);
+
+        assert_eq!(set(&mut settings, "foo", "bar"), Err(Error::UnknownKey));
+    }
+
+    #[test]
+    fn test_describe() {
+        let settings = UserSettings {
+            notifications: false,
+            lang: Lang::It,
+            unit: Unit::Btc,
+            stale_alert_mins: 30,
+            recovery_alerts: false,
+            muted_until: None,
+            timezone: Some(Tz::Europe__Rome),
+        };
+        assert_eq!(
+            describe(&settings),
+            "notifications: off\nlang: it\nunit: btc\nstale_alert_mins: 30\nrecovery_alerts: off\ntimezone: Europe/Rome\n"
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::tombstone::RoomTombstoneEventContent;
+use matrix_sdk::ruma::events::OriginalSyncStateEvent;
+use matrix_sdk::ruma::{RoomOrAliasId, ServerName};
+use matrix_sdk::Client;
+
+use super::Bot;
+
+/// Follow a room upgrade: join the replacement room and move the subscriptions
+pub async fn on_room_tombstone(
+    bot: &Bot,
+    event: OriginalSyncStateEvent<RoomTombstoneEventContent>,
+    room: Room,
+    client: Client,
+) {
+    let old_room_id: &str = room.room_id().as_str();
+    let new_room_id = event.content.replacement_room;
+
+    log::info!("Room {} upgraded to {}", old_room_id, new_room_id);
+
+    // The sender of the tombstone is in the new room, its server can be used to join
+    let via: Vec<Box<ServerName>> = vec![event.sender.server_name().to_owned()];
+    let alias = <&RoomOrAliasId>::from(&*new_room_id);
+
+    if let Err(error) = client.join_room_by_id_or_alias(alias, &via).await {
+        log::error!(
+            "Impossible to join upgraded room {}: {:?}",
+            new_room_id,
+            error
+        );
+        return;
+    }
+
+    let mut migrated: usize = 0;
+
+    for (user_id, user) in bot.store.iter_users() {
+        if user.room_id != old_room_id {
+            continue;
+        }
+
+        match bot.store.update_user_room(&user_id, new_room_id.as_str()) {
+            Ok(()) => migrated += 1,
+            Err(error) => log::error!(
+                "Impossible to move {} to room {}: {:?}",
+                user_id,
+                new_room_id,
+                error
+            ),
+        }
+    }
+
+    log::info!(
+        "Moved {} subscriptions from {} to {}",
+        migrated,
+        old_room_id,
+        new_room_id
+    );
+
+    bot.send_notification(new_room_id.as_str(), "Followed room upgrade")
+        .await;
+}
@@ -0,0 +1,68 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Hint for the messages the bot can't decrypt
+//!
+//! Decrypted messages are delivered as regular room messages, so an
+//! `m.room.encrypted` event reaching the handler is one that failed to decrypt.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
+
+use super::{Bot, START_TIMESTAMP};
+use crate::i18n::{self, Msg};
+
+/// Rooms already warned since the start
+#[derive(Clone, Default)]
+pub struct WarnedRooms(Arc<Mutex<HashSet<String>>>);
+
+impl WarnedRooms {
+    /// Return `true` only the first time for each room
+    fn first_warning(&self, room_id: &str) -> bool {
+        match self.0.lock() {
+            Ok(mut rooms) => rooms.insert(room_id.to_string()),
+            Err(_) => false,
+        }
+    }
+}
+
+pub async fn on_room_encrypted(bot: &Bot, event: OriginalSyncRoomEncryptedEvent, room: Room) {
+    if *event.sender == bot.config().matrix.user_id || event.origin_server_ts < *START_TIMESTAMP {
+        return;
+    }
+
+    let room_id: &str = room.room_id().as_str();
+
+    log::warn!(
+        "Impossible to decrypt event {} from {} in {}",
+        event.event_id,
+        event.sender,
+        room_id
+    );
+
+    if !bot.warned_rooms.first_warning(room_id) {
+        return;
+    }
+
+    let lang = bot.store.get_settings(event.sender.as_str()).lang;
+    bot.send_notification(room_id, &i18n::t(Msg::UndecryptableHint, lang, &[]))
+        .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_first_warning() {
+        let warned = WarnedRooms::default();
+
+        assert!(warned.first_warning("!a:example.com"));
+        assert!(!warned.first_warning("!a:example.com"));
+        assert!(warned.first_warning("!b:example.com"));
+        assert!(!warned.clone().first_warning("!b:example.com"));
+    }
+}
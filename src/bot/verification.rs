@@ -0,0 +1,199 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! SAS (emoji) verification of the bot device
+//!
+//! Requests are accepted only if `matrix.auto_verify` is enabled and the sender
+//! is trusted. The emoji are confirmed without operator input, so the flag is
+//! meant to be enabled only while verifying the bot.
+
+use matrix_sdk::encryption::verification::{SasVerification, Verification};
+use matrix_sdk::ruma::events::key::verification::done::ToDeviceKeyVerificationDoneEvent;
+use matrix_sdk::ruma::events::key::verification::key::ToDeviceKeyVerificationKeyEvent;
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
+use matrix_sdk::ruma::events::key::verification::start::ToDeviceKeyVerificationStartEvent;
+use matrix_sdk::Client;
+
+use super::Bot;
+
+pub async fn on_request(bot: &Bot, event: ToDeviceKeyVerificationRequestEvent, client: Client) {
+    let sender: &str = event.sender.as_str();
+    let device_id: &str = event.content.from_device.as_str();
+
+    log::info!(
+        "Verification request {} from {} (device {})",
+        event.content.transaction_id,
+        sender,
+        device_id
+    );
+
+    if !bot.config().matrix.auto_verify {
+        log::warn!("Verification request ignored: `matrix.auto_verify` is disabled");
+        return;
+    }
+
+    let request = match client
+        .encryption()
+        .get_verification_request(&event.sender, &event.content.transaction_id)
+        .await
+    {
+        Some(request) => request,
+        None => {
+            log::error!(
+                "Verification request {} not found",
+                event.content.transaction_id
+            );
+            return;
+        }
+    };
+
+    let result = if is_trusted(&bot.config().matrix.verification_trusted, sender, device_id) {
+        request.accept().await
+    } else {
+        log::warn!(
+            "Verification request from untrusted {} (device {}) cancelled",
+            sender,
+            device_id
+        );
+        request.cancel().await
+    };
+
+    if let Err(error) = result {
+        log::error!("Impossible to answer verification request: {:?}", error);
+    }
+}
+
+pub async fn on_start(bot: &Bot, event: ToDeviceKeyVerificationStartEvent, client: Client) {
+    let device_id: &str = event.content.from_device.as_str();
+
+    log::info!(
+        "Verification {} started by {} (device {})",
+        event.content.transaction_id,
+        event.sender,
+        device_id
+    );
+
+    if !bot.config().matrix.auto_verify
+        || !is_trusted(
+            &bot.config().matrix.verification_trusted,
+            event.sender.as_str(),
+            device_id,
+        )
+    {
+        return;
+    }
+
+    if let Some(sas) = get_sas(
+        &client,
+        &event.sender,
+        event.content.transaction_id.as_str(),
+    )
+    .await
+    {
+        if let Err(error) = sas.accept().await {
+            log::error!("Impossible to accept verification: {:?}", error);
+        }
+    }
+}
+
+pub async fn on_key(bot: &Bot, event: ToDeviceKeyVerificationKeyEvent, client: Client) {
+    if !bot.config().matrix.auto_verify {
+        return;
+    }
+
+    let sas = match get_sas(
+        &client,
+        &event.sender,
+        event.content.transaction_id.as_str(),
+    )
+    .await
+    {
+        Some(sas) => sas,
+        None => return,
+    };
+
+    // Accepted only for trusted devices, checked again in case the request came from another one
+    let device_id: String = sas.other_device().device_id().to_string();
+    if !is_trusted(
+        &bot.config().matrix.verification_trusted,
+        event.sender.as_str(),
+        &device_id,
+    ) {
+        log::warn!(
+            "Verification with untrusted device {} of {} cancelled",
+            device_id,
+            event.sender
+        );
+        if let Err(error) = sas.cancel().await {
+            log::error!("Impossible to cancel verification: {:?}", error);
+        }
+        return;
+    }
+
+    if let Some(emoji) = sas.emoji() {
+        let emoji: Vec<String> = emoji
+            .iter()
+            .map(|emoji| format!("{} ({})", emoji.symbol, emoji.description))
+            .collect();
+        log::info!(
+            "Verification emoji with {} (device {}): {}",
+            event.sender,
+            device_id,
+            emoji.join(" ")
+        );
+    }
+
+    if let Err(error) = sas.confirm().await {
+        log::error!("Impossible to confirm verification: {:?}", error);
+    }
+}
+
+pub async fn on_done(event: ToDeviceKeyVerificationDoneEvent) {
+    log::info!(
+        "Verification {} with {} done",
+        event.content.transaction_id,
+        event.sender
+    );
+}
+
+async fn get_sas(
+    client: &Client,
+    sender: &matrix_sdk::ruma::UserId,
+    transaction_id: &str,
+) -> Option<SasVerification> {
+    match client
+        .encryption()
+        .get_verification(sender, transaction_id)
+        .await
+    {
+        Some(Verification::SasV1(sas)) => Some(sas),
+        _ => None,
+    }
+}
+
+/// Entries are user ids, or `user_id/device_id` to trust a single device
+fn is_trusted(trusted: &[String], user_id: &str, device_id: &str) -> bool {
+    trusted.iter().any(|entry| match entry.split_once('/') {
+        Some((user, device)) => user == user_id && device == device_id,
+        None => entry == user_id,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_trusted() {
+        let trusted = vec![
+            String::from("@admin:example.com"),
+            String::from("@operator:example.com/ABCDEF"),
+        ];
+
+        assert!(is_trusted(&trusted, "@admin:example.com", "ANYDEVICE"));
+        assert!(is_trusted(&trusted, "@operator:example.com", "ABCDEF"));
+        assert!(!is_trusted(&trusted, "@operator:example.com", "OTHER"));
+        assert!(!is_trusted(&trusted, "@mallory:example.com", "ABCDEF"));
+        assert!(!is_trusted(&[], "@admin:example.com", "ANYDEVICE"));
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! `check` subcommand: validate config and connectivity without login, sync or state changes
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bot;
+use crate::config::Config;
+use crate::db::DBStore;
+use crate::pool::{self, BraiinsPool, LimitedPool, PoolClientFactory};
+
+const PROBE_FILE_NAME: &str = ".braiinspool_bot_check";
+
+/// Run all the checks, print a summary and return `true` if all passed
+pub async fn run(config: &Config, check_tokens: bool) -> bool {
+    let mut results: Vec<(&str, Result<String, String>)> =
+        vec![("Config", Ok(String::from("valid")))];
+
+    results.push((
+        "Homeserver",
+        match bot::check_homeserver(config).await {
+            Ok(latency) => Ok(format!(
+                "{} answered in {} ms",
+                config.matrix.homeserver_url,
+                latency.as_millis()
+            )),
+            Err(error) => Err(format!("{}: {:?}", config.matrix.homeserver_url, error)),
+        },
+    ));
+
+    results.push((
+        "Database",
+        check_writable(&config.matrix.db_path)
+            .map(|dir| format!("{:?} is writable", dir))
+            .map_err(|error| format!("{:?} is not writable: {}", config.matrix.db_path, error)),
+    ));
+
+    if check_tokens {
+        results.push(("BraiinsPool token", check_token(config).await));
+    }
+
+    let mut ok: bool = true;
+
+    for (name, result) in results.iter() {
+        match result {
+            Ok(message) => println!("✅ {}: {}", name, message),
+            Err(message) => {
+                ok = false;
+                println!("❌ {}: {}", name, message);
+            }
+        }
+    }
+
+    if ok {
+        println!("All checks passed");
+    } else {
+        println!("Some checks failed");
+    }
+
+    ok
+}
+
+/// Check that files can be created in `path`, or in its nearest existing parent
+/// if it doesn't exist yet. Return the checked directory.
+fn check_writable(path: &Path) -> io::Result<PathBuf> {
+    let dir: &Path = path
+        .ancestors()
+        .find(|dir| dir.is_dir())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no existing parent directory"))?;
+
+    let probe: PathBuf = dir.join(PROBE_FILE_NAME);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    std::fs::remove_file(&probe)?;
+
+    Ok(dir.to_path_buf())
+}
+
+/// Call the BraiinsPool API with the token of the first stored user
+async fn check_token(config: &Config) -> Result<String, String> {
+    if !config.matrix.db_path.exists() {
+        return Ok(String::from("no database yet, skipped"));
+    }
+
+    let encryption_key: Option<&[u8; 32]> = config.db_encryption_key.as_ref().map(|key| &key.0);
+    let store = DBStore::open(&config.matrix.db_path, encryption_key)
+        .map_err(|error| format!("impossible to open database: {:?}", error))?;
+
+    let (user_id, user) = match store.iter_users().next() {
+        Some(user) => user,
+        None => return Ok(String::from("no subscribed users, skipped")),
+    };
+
+    let client = LimitedPool::new(
+        Arc::new(BraiinsPool::new(config.braiinspool.proxy_url())),
+        1,
+        Duration::from_secs(config.braiinspool.api_timeout_secs),
+        Duration::from_secs(config.braiinspool.rate_limit_max_wait_secs),
+    )
+    .client(user.token.expose())
+    .map_err(|error| format!("{:?}", error))?;
+
+    match client.user_profile().await {
+        Ok(_) => Ok(format!("token of {} accepted", user_id)),
+        Err(error) if pool::is_auth_error(&error) => Err(format!("token of {} rejected", user_id)),
+        Err(error) => Err(format!("BraiinsPool API unreachable: {:?}", error)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_check_writable() {
+        let dir = TempDir::new().unwrap();
+
+        assert_eq!(check_writable(dir.path()).unwrap(), dir.path());
+
+        // Not created yet: the parent is checked and nothing is left behind
+        let db_path = dir.path().join("matrix").join("db");
+        assert_eq!(check_writable(&db_path).unwrap(), dir.path());
+        assert!(!db_path.exists());
+        assert!(!dir.path().join(PROBE_FILE_NAME).exists());
+    }
+}
@@ -1,88 +1,496 @@
 // Copyright (c) 2021-2022 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use clap::Parser;
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
 use dirs::home_dir;
 use log::Level;
 
+use crate::secret::Secret;
+
 pub mod model;
+mod reload;
+mod template;
+mod validate;
 
 use model::*;
+use validate::expand_path;
 
 pub use model::Config;
+pub use reload::ConfigHandle;
+pub use template::write_template;
+
+/// Lower values would hit the BraiinsPool API rate limits
+const MIN_POLL_INTERVAL_SECS: u64 = 60;
 
 fn default_dir() -> PathBuf {
     let home: PathBuf = home_dir().unwrap_or_else(|| {
-        log::error!("Unknown home directory");
+        eprintln!("Unknown home directory: set `main_path` in config file");
         std::process::exit(1)
     });
     home.join(".braiinspool_bot")
 }
 
-fn default_config_file() -> PathBuf {
+pub fn default_config_file() -> PathBuf {
     let mut default = default_dir().join("config");
     default.set_extension("toml");
     default
 }
 
+/// Read the db encryption key from the config value or from the key file
+fn parse_encryption_key(
+    value: Option<String>,
+    file: Option<PathBuf>,
+) -> Result<Option<EncryptionKey>, String> {
+    let encoded: String = match (value, file) {
+        // Both set is rejected by `validate`
+        (Some(value), _) => value,
+        (None, Some(path)) => std::fs::read_to_string(expand_path(&path)).map_err(|error| {
+            format!(
+                "`db_encryption_key_file`: impossible to read {:?}: {}",
+                path, error
+            )
+        })?,
+        (None, None) => return Ok(None),
+    };
+
+    match base64::decode(encoded.trim()) {
+        Ok(key) if key.len() == 32 => {
+            let mut bytes = [0u8; 32];
+            bytes.copy_from_slice(&key);
+            Ok(Some(EncryptionKey(bytes)))
+        }
+        _ => Err(String::from("`db_encryption_key`: expected 32 bytes encoded in base64 (ex. `openssl rand -base64 32`)")),
+    }
+}
+
+/// Read a secret from the config value or from the file set by `{key}_file`
+fn read_secret(
+    key: &str,
+    value: Option<String>,
+    file: Option<PathBuf>,
+) -> Result<Option<String>, String> {
+    match (value, file) {
+        // Both set is rejected by `validate`
+        (Some(value), _) => Ok(Some(value)),
+        (None, Some(path)) => match std::fs::read_to_string(expand_path(&path)) {
+            Ok(content) => Ok(Some(content.trim().to_string())),
+            Err(error) => Err(format!(
+                "`{}_file`: impossible to read {:?}: {}",
+                key, path, error
+            )),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+/// `[braiinspool]` with the defaults of the keys not set
+fn parse_braiinspool(braiinspool: ConfigFileBraiinsPool) -> BraiinsPool {
+    BraiinsPool {
+        proxy: braiinspool.proxy,
+        proxy_username: braiinspool.proxy_username,
+        proxy_password: braiinspool.proxy_password.map(Secret::masked),
+        auth_failure_threshold: braiinspool.auth_failure_threshold.unwrap_or(3),
+        auth_failure_unlink: braiinspool.auth_failure_unlink.unwrap_or(false),
+        max_concurrent_api_calls: braiinspool.max_concurrent_api_calls.unwrap_or(4),
+        api_timeout_secs: braiinspool.api_timeout_secs.unwrap_or(30),
+        rate_limit_max_wait_secs: braiinspool.rate_limit_max_wait_secs.unwrap_or(5),
+    }
+}
+
+/// `[poller]` with the defaults of the keys not set
+fn parse_poller(poller: ConfigFilePoller) -> Poller {
+    Poller {
+        interval_secs: poller.interval_secs.unwrap_or(300),
+        jitter_secs: poller.jitter_secs.unwrap_or(30),
+        worker_alerts: poller.worker_alerts.unwrap_or(true),
+        zero_hashrate_threshold: poller.zero_hashrate_threshold.unwrap_or(0),
+        payout_notifications: poller.payout_notifications.unwrap_or(true),
+        pool_alerts: poller.pool_alerts.unwrap_or(true),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
-    #[clap(short, long, parse(from_os_str))]
+pub struct Args {
+    #[clap(short, long, global = true, parse(from_os_str))]
     config_file: Option<PathBuf>,
+    /// Write a commented config template (default: ~/.braiinspool_bot/config.toml) and exit
+    #[clap(long, value_name = "PATH", parse(from_os_str))]
+    pub init: Option<Option<PathBuf>>,
+    /// Overwrite the existing config file with `--init`
+    #[clap(long, requires = "init")]
+    pub force: bool,
+    /// Same as the `check` subcommand, kept for compatibility
+    #[clap(long, hide = true)]
+    check: bool,
+    #[clap(long, hide = true, requires = "check")]
+    check_tokens: bool,
+    /// Same as the `logout` subcommand, kept for compatibility
+    #[clap(long, hide = true)]
+    logout: bool,
+    #[clap(long, hide = true, requires = "logout")]
+    purge_state: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-impl Config {
-    pub fn from_args() -> Self {
-        let args: Args = Args::parse();
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Start the bot (default)
+    Run,
+    /// Validate config, homeserver connectivity and database path, then exit
+    Check {
+        /// Also verify a stored BraiinsPool token
+        #[clap(long)]
+        tokens: bool,
+    },
+    /// Export the database as JSON, without starting the bot
+    Export {
+        #[clap(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Import a JSON backup created with `export`
+    Import {
+        #[clap(short, long, parse(from_os_str))]
+        input: PathBuf,
+        /// Overwrite existing records
+        #[clap(long)]
+        force: bool,
+    },
+    /// Log out the saved session from the homeserver and delete it from the database
+    Logout {
+        /// Also delete the state directory (crypto keys and room state)
+        #[clap(long)]
+        purge_state: bool,
+    },
+    /// List the subscribed users, without starting the bot
+    ListUsers {
+        /// Print as JSON
+        #[clap(long)]
+        json: bool,
+    },
+}
 
-        let config_file_path: PathBuf = match args.config_file {
-            Some(path) => path,
+/// Reasons the config file can't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    /// Problems found by the validation, each one starting with the offending key
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(error) => write!(f, "{}", error),
+            Self::Parse(error) => write!(f, "{}", error),
+            Self::Invalid(problems) => {
+                for problem in problems.iter() {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        LoadError::Read(err)
+    }
+}
+
+impl From<toml::de::Error> for LoadError {
+    fn from(err: toml::de::Error) -> Self {
+        LoadError::Parse(err)
+    }
+}
+
+impl Args {
+    /// Path of the config file, `--config-file` or the default one
+    pub fn config_file_path(&self) -> PathBuf {
+        match &self.config_file {
+            Some(path) => path.clone(),
             None => default_config_file(),
-        };
+        }
+    }
+
+    /// Subcommand to run: `run` if none is given, like before the subcommands
+    pub fn command(&self) -> Command {
+        match &self.command {
+            Some(command) => command.clone(),
+            None if self.check => Command::Check {
+                tokens: self.check_tokens,
+            },
+            None if self.logout => Command::Logout {
+                purge_state: self.purge_state,
+            },
+            None => Command::Run,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(args: &Args) -> Self {
+        let config_file_path: PathBuf = args.config_file_path();
 
-        let config_file: ConfigFile = match Self::read_config_file(&config_file_path) {
-            Ok(data) => data,
+        let (config, warnings) = match Self::load(&config_file_path) {
+            Ok(loaded) => loaded,
+            Err(LoadError::Invalid(problems)) => {
+                eprintln!("Invalid config file at {:?}:", config_file_path);
+                for problem in problems.iter() {
+                    eprintln!("  - {}", problem);
+                }
+                std::process::exit(1);
+            }
             Err(error) => {
-                log::error!("Impossible to read config file at {:?}", config_file_path);
-                panic!("{}", error);
+                eprintln!(
+                    "Impossible to read config file at {:?}: {}",
+                    config_file_path, error
+                );
+                std::process::exit(1);
             }
         };
 
+        // Logger not initialized yet
+        for warning in warnings.iter() {
+            eprintln!("Warning: {}", warning);
+        }
+
+        config
+    }
+
+    /// Read and validate the config file. Return the config and the warnings about its keys.
+    pub fn load(path: &Path) -> Result<(Self, Vec<String>), LoadError> {
+        let content: String = std::fs::read_to_string(path)?;
+        let unknown_keys: Vec<String> = validate::unknown_keys(&content);
+        let config_file: ConfigFile = toml::from_str(&content)?;
+
+        let mut warnings: Vec<String> = unknown_keys
+            .iter()
+            .map(|key| format!("unknown key `{}` in config file, ignored", key))
+            .collect();
+        warnings.extend(validate::deprecated_keys(&config_file).into_iter().map(
+            |(key, replacement)| {
+                format!(
+                    "`{}` in config file is deprecated, use `{}` instead",
+                    key, replacement
+                )
+            },
+        ));
+
+        let mut problems: Vec<String> = validate::validate(&config_file);
+
+        if !problems.is_empty() {
+            return Err(LoadError::Invalid(problems));
+        }
+
+        let db_encryption_key = parse_encryption_key(
+            config_file.db_encryption_key,
+            config_file.db_encryption_key_file,
+        );
+        let password = read_secret(
+            "matrix.password",
+            config_file.matrix.password,
+            config_file.matrix.password_file,
+        );
+        let crypto_store_passphrase = read_secret(
+            "matrix.crypto_store_passphrase",
+            config_file.matrix.crypto_store_passphrase,
+            config_file.matrix.crypto_store_passphrase_file,
+        );
+
+        let (db_encryption_key, password, crypto_store_passphrase) =
+            match (db_encryption_key, password, crypto_store_passphrase) {
+                (Ok(db_encryption_key), Ok(password), Ok(crypto_store_passphrase)) => {
+                    (db_encryption_key, password, crypto_store_passphrase)
+                }
+                (db_encryption_key, password, crypto_store_passphrase) => {
+                    problems.extend(db_encryption_key.err());
+                    problems.extend(password.err());
+                    problems.extend(crypto_store_passphrase.err());
+                    return Err(LoadError::Invalid(problems));
+                }
+            };
+
+        let mut braiinspool_file: ConfigFileBraiinsPool =
+            config_file.braiinspool.unwrap_or_default();
+        // The deprecated root key is the fallback
+        braiinspool_file.proxy = braiinspool_file.proxy.or(config_file.proxy);
+        let braiinspool: BraiinsPool = parse_braiinspool(braiinspool_file);
+
+        // The encoded form is the one that can end up in an error of the proxy url
+        if let Some(password) = &braiinspool.proxy_password {
+            crate::secret::register(&encode_userinfo(password.expose()));
+        }
+
+        let tor_only: bool = config_file.tor_only.unwrap_or(false);
+
         let main_path: PathBuf = match config_file.main_path {
-            Some(path) => path,
+            Some(path) => expand_path(&path),
             None => default_dir(),
         };
 
-        let log_level: Level = match config_file.log_level {
-            Some(log_level) => Level::from_str(log_level.as_str()).unwrap_or(Level::Info),
-            None => Level::Info,
+        let (log_level, log_modules): (Level, Vec<(String, Level)>) = match config_file.log_level {
+            Some(ConfigFileLogLevel::Level(log_level)) => (
+                Level::from_str(log_level.as_str()).unwrap_or(Level::Info),
+                Vec::new(),
+            ),
+            Some(ConfigFileLogLevel::Modules(modules)) => {
+                let mut default: Level = Level::Info;
+                let mut log_modules: Vec<(String, Level)> = Vec::new();
+                for (module, level) in modules.into_iter() {
+                    if let Ok(level) = Level::from_str(&level) {
+                        if module == "default" {
+                            default = level;
+                        } else {
+                            log_modules.push((module, level));
+                        }
+                    }
+                }
+                (default, log_modules)
+            }
+            None => (Level::Info, Vec::new()),
+        };
+
+        let mut poller_file: ConfigFilePoller = config_file.poller.unwrap_or_default();
+        // The deprecated root keys are the fallback
+        poller_file.interval_secs = poller_file.interval_secs.or(config_file.poll_interval_secs);
+        poller_file.jitter_secs = poller_file.jitter_secs.or(config_file.poll_jitter_secs);
+        let poller: Poller = parse_poller(poller_file);
+
+        let admins: Vec<String> = config_file.matrix.admins.unwrap_or_default();
+
+        let health: Health = match config_file.health {
+            Some(health) => Health {
+                bind_addr: health.bind_addr,
+                max_sync_age_mins: health.max_sync_age_mins.unwrap_or(5),
+            },
+            None => Health {
+                bind_addr: None,
+                max_sync_age_mins: 5,
+            },
         };
 
         let config = Self {
             main_path: main_path.clone(),
             log_level,
-            proxy: config_file.proxy,
+            log_modules,
+            log_format: config_file
+                .log_format
+                .and_then(|format| LogFormat::from_str(&format).ok())
+                .unwrap_or(LogFormat::Text),
+            log_file: config_file.log_file.map(|path| expand_path(&path)),
+            log_file_max_size_mb: config_file.log_file_max_size_mb.unwrap_or(10),
+            log_file_keep: config_file.log_file_keep.unwrap_or(5),
+            tor_only,
+            charts: config_file.charts.unwrap_or(false),
+            dry_run: config_file.dry_run.unwrap_or(false),
+            timezone: config_file
+                .timezone
+                .and_then(|timezone| Tz::from_str(&timezone).ok())
+                .unwrap_or(Tz::UTC),
+            command_namespace: config_file.command_namespace,
+            enabled_commands: config_file.enabled_commands.map(|commands| {
+                commands
+                    .iter()
+                    .map(|command| command.trim_start_matches('!').to_string())
+                    .collect()
+            }),
+            audit_log_max_entries: config_file.audit_log_max_entries.unwrap_or(5000),
+            db_encryption_key,
+            braiinspool,
+            poller,
             matrix: Matrix {
                 db_path: main_path.join("matrix/db"),
                 state_path: main_path.join("matrix/state"),
                 homeserver_url: config_file.matrix.homeserver_url,
                 proxy: config_file.matrix.proxy,
                 user_id: config_file.matrix.user_id,
-                password: config_file.matrix.password,
+                password: password.map(Secret::masked),
+                crypto_store_passphrase: crypto_store_passphrase.map(Secret::masked),
+                admin_rooms: config_file.matrix.admin_rooms.unwrap_or_default(),
+                active_rooms: config_file.matrix.active_rooms.unwrap_or_default(),
+                admins: admins.clone(),
+                sync_max_retry_secs: config_file.matrix.sync_max_retry_secs.unwrap_or(0),
+                sync_timeout_secs: config_file.matrix.sync_timeout_secs.unwrap_or(30),
+                sync_full_state: config_file.matrix.sync_full_state.unwrap_or(true),
+                login_max_retries: config_file.matrix.login_max_retries.unwrap_or(5),
+                max_messages_per_sec: config_file.matrix.max_messages_per_sec.unwrap_or(5),
+                auto_verify: config_file.matrix.auto_verify.unwrap_or(false),
+                verification_trusted: config_file.matrix.verification_trusted.unwrap_or(admins),
+                subscribe_in_dm: config_file.matrix.subscribe_in_dm.unwrap_or(true),
+            },
+            health,
+            templates: match config_file.templates {
+                Some(templates) => Templates {
+                    userstatus: templates.userstatus,
+                    workers: templates.workers,
+                    dailyrewards: templates.dailyrewards,
+                    poolstatus: templates.poolstatus,
+                },
+                None => Templates::default(),
             },
         };
 
-        println!("{:?}", config);
-
-        config
+        Ok((config, warnings))
     }
 
-    fn read_config_file(path: &Path) -> std::io::Result<ConfigFile> {
-        let content = std::fs::read_to_string(&path)?;
-        Ok(toml::from_str(&content)?)
+    #[cfg(test)]
+    pub fn for_test(main_path: PathBuf) -> Self {
+        Self {
+            main_path: main_path.clone(),
+            log_level: Level::Info,
+            log_modules: Vec::new(),
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_file_max_size_mb: 10,
+            log_file_keep: 5,
+            tor_only: false,
+            charts: false,
+            dry_run: false,
+            timezone: Tz::UTC,
+            command_namespace: None,
+            enabled_commands: None,
+            audit_log_max_entries: 100,
+            db_encryption_key: None,
+            braiinspool: parse_braiinspool(ConfigFileBraiinsPool::default()),
+            // No random delay in the tests
+            poller: Poller {
+                jitter_secs: 0,
+                ..parse_poller(ConfigFilePoller::default())
+            },
+            matrix: Matrix {
+                db_path: main_path.join("matrix/db"),
+                state_path: main_path.join("matrix/state"),
+                homeserver_url: String::from("http://localhost:8008"),
+                proxy: None,
+                user_id: String::from("@bot:example.com"),
+                password: None,
+                crypto_store_passphrase: None,
+                admin_rooms: Vec::new(),
+                active_rooms: Vec::new(),
+                admins: vec![String::from("@admin:example.com")],
+                sync_max_retry_secs: 0,
+                sync_timeout_secs: 30,
+                sync_full_state: true,
+                login_max_retries: 0,
+                max_messages_per_sec: 0,
+                auto_verify: false,
+                verification_trusted: vec![String::from("@admin:example.com")],
+                subscribe_in_dm: true,
+            },
+            health: Health {
+                bind_addr: None,
+                max_sync_age_mins: 5,
+            },
+            templates: Templates::default(),
+        }
     }
 }
@@ -0,0 +1,299 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Config shared by all the tasks, reloaded from the config file on SIGHUP
+//!
+//! Only the settings read again at each use are applied. The ones used to
+//! build long-lived clients and stores (homeserver, paths, credentials) keep
+//! their current value until the next restart.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use super::model::Config;
+use super::LoadError;
+
+#[derive(Clone)]
+pub struct ConfigHandle {
+    current: Arc<ArcSwap<Config>>,
+    path: PathBuf,
+}
+
+/// Keys of the settings that differ after a reload
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub applied: Vec<&'static str>,
+    /// Changed, but they need a restart: the current values are kept
+    pub ignored: Vec<&'static str>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: Config, path: PathBuf) -> Self {
+        Self {
+            current: Arc::new(ArcSwap::from_pointee(config)),
+            path,
+        }
+    }
+
+    /// Snapshot of the current config
+    pub fn get(&self) -> Arc<Config> {
+        self.current.load_full()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the config file again and apply the hot-reloadable settings.
+    /// On error the current config is kept.
+    pub fn reload(&self) -> Result<(Changes, Vec<String>), LoadError> {
+        let (new, warnings) = Config::load(&self.path)?;
+        let (merged, changes) = merge(&self.get(), new);
+        self.current.store(Arc::new(merged));
+        Ok((changes, warnings))
+    }
+}
+
+fn merge(current: &Config, mut new: Config) -> (Config, Changes) {
+    let mut changes = Changes::default();
+
+    let mut applied = |key: &'static str, changed: bool| {
+        if changed {
+            changes.applied.push(key);
+        }
+    };
+
+    applied(
+        "log_level",
+        current.log_level != new.log_level || current.log_modules != new.log_modules,
+    );
+    applied("charts", current.charts != new.charts);
+    applied("dry_run", current.dry_run != new.dry_run);
+    applied("timezone", current.timezone != new.timezone);
+    applied(
+        "command_namespace",
+        current.command_namespace != new.command_namespace,
+    );
+    applied(
+        "enabled_commands",
+        current.enabled_commands != new.enabled_commands,
+    );
+    applied(
+        "audit_log_max_entries",
+        current.audit_log_max_entries != new.audit_log_max_entries,
+    );
+    applied(
+        "braiinspool.auth_failure_threshold",
+        current.braiinspool.auth_failure_threshold != new.braiinspool.auth_failure_threshold,
+    );
+    applied(
+        "braiinspool.auth_failure_unlink",
+        current.braiinspool.auth_failure_unlink != new.braiinspool.auth_failure_unlink,
+    );
+    applied("poller", current.poller != new.poller);
+    applied(
+        "matrix.admin_rooms",
+        current.matrix.admin_rooms != new.matrix.admin_rooms,
+    );
+    applied(
+        "matrix.active_rooms",
+        current.matrix.active_rooms != new.matrix.active_rooms,
+    );
+    applied("matrix.admins", current.matrix.admins != new.matrix.admins);
+    applied(
+        "matrix.sync_max_retry_secs",
+        current.matrix.sync_max_retry_secs != new.matrix.sync_max_retry_secs,
+    );
+    applied(
+        "matrix.sync_timeout_secs",
+        current.matrix.sync_timeout_secs != new.matrix.sync_timeout_secs,
+    );
+    applied(
+        "matrix.login_max_retries",
+        current.matrix.login_max_retries != new.matrix.login_max_retries,
+    );
+    applied(
+        "matrix.max_messages_per_sec",
+        current.matrix.max_messages_per_sec != new.matrix.max_messages_per_sec,
+    );
+    applied(
+        "matrix.auto_verify",
+        current.matrix.auto_verify != new.matrix.auto_verify,
+    );
+    applied(
+        "matrix.verification_trusted",
+        current.matrix.verification_trusted != new.matrix.verification_trusted,
+    );
+    applied(
+        "matrix.subscribe_in_dm",
+        current.matrix.subscribe_in_dm != new.matrix.subscribe_in_dm,
+    );
+    applied(
+        "health.max_sync_age_mins",
+        current.health.max_sync_age_mins != new.health.max_sync_age_mins,
+    );
+    applied("templates", current.templates != new.templates);
+
+    let ignored = &mut changes.ignored;
+
+    keep(ignored, "main_path", &current.main_path, &mut new.main_path);
+    new.matrix.db_path = current.matrix.db_path.clone();
+    new.matrix.state_path = current.matrix.state_path.clone();
+    keep(ignored, "tor_only", &current.tor_only, &mut new.tor_only);
+    keep(
+        ignored,
+        "log_format",
+        &current.log_format,
+        &mut new.log_format,
+    );
+    keep(ignored, "log_file", &current.log_file, &mut new.log_file);
+    keep(
+        ignored,
+        "log_file_max_size_mb",
+        &current.log_file_max_size_mb,
+        &mut new.log_file_max_size_mb,
+    );
+    keep(
+        ignored,
+        "log_file_keep",
+        &current.log_file_keep,
+        &mut new.log_file_keep,
+    );
+
+    let current_key: Option<[u8; 32]> = current.db_encryption_key.as_ref().map(|key| key.0);
+    let new_key: Option<[u8; 32]> = new.db_encryption_key.as_ref().map(|key| key.0);
+    if current_key != new_key {
+        ignored.push("db_encryption_key");
+        new.db_encryption_key = current.db_encryption_key.clone();
+    }
+
+    keep(
+        ignored,
+        "braiinspool.proxy",
+        &current.braiinspool.proxy,
+        &mut new.braiinspool.proxy,
+    );
+    keep(
+        ignored,
+        "braiinspool.proxy_username",
+        &current.braiinspool.proxy_username,
+        &mut new.braiinspool.proxy_username,
+    );
+    keep(
+        ignored,
+        "braiinspool.proxy_password",
+        &current.braiinspool.proxy_password,
+        &mut new.braiinspool.proxy_password,
+    );
+    keep(
+        ignored,
+        "braiinspool.max_concurrent_api_calls",
+        &current.braiinspool.max_concurrent_api_calls,
+        &mut new.braiinspool.max_concurrent_api_calls,
+    );
+    keep(
+        ignored,
+        "braiinspool.api_timeout_secs",
+        &current.braiinspool.api_timeout_secs,
+        &mut new.braiinspool.api_timeout_secs,
+    );
+    keep(
+        ignored,
+        "braiinspool.rate_limit_max_wait_secs",
+        &current.braiinspool.rate_limit_max_wait_secs,
+        &mut new.braiinspool.rate_limit_max_wait_secs,
+    );
+    keep(
+        ignored,
+        "matrix.homeserver_url",
+        &current.matrix.homeserver_url,
+        &mut new.matrix.homeserver_url,
+    );
+    keep(
+        ignored,
+        "matrix.proxy",
+        &current.matrix.proxy,
+        &mut new.matrix.proxy,
+    );
+    keep(
+        ignored,
+        "matrix.user_id",
+        &current.matrix.user_id,
+        &mut new.matrix.user_id,
+    );
+    keep(
+        ignored,
+        "matrix.password",
+        &current.matrix.password,
+        &mut new.matrix.password,
+    );
+    keep(
+        ignored,
+        "matrix.sync_full_state",
+        &current.matrix.sync_full_state,
+        &mut new.matrix.sync_full_state,
+    );
+    keep(
+        ignored,
+        "matrix.crypto_store_passphrase",
+        &current.matrix.crypto_store_passphrase,
+        &mut new.matrix.crypto_store_passphrase,
+    );
+    keep(
+        ignored,
+        "health.bind_addr",
+        &current.health.bind_addr,
+        &mut new.health.bind_addr,
+    );
+
+    (new, changes)
+}
+
+/// Restore the current value of a setting that needs a restart
+fn keep<T: PartialEq + Clone>(
+    ignored: &mut Vec<&'static str>,
+    key: &'static str,
+    current: &T,
+    new: &mut T,
+) {
+    if current != new {
+        ignored.push(key);
+        *new = current.clone();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let current = Config::for_test(PathBuf::from("/data"));
+
+        let mut new = Config::for_test(PathBuf::from("/other"));
+        new.log_level = Level::Debug;
+        new.poller.interval_secs = 600;
+        new.matrix.admins.push(String::from("@bob:example.com"));
+        new.matrix.homeserver_url = String::from("https://matrix.example.com");
+
+        let (merged, changes) = merge(&current, new);
+
+        assert_eq!(
+            changes,
+            Changes {
+                applied: vec!["log_level", "poller", "matrix.admins"],
+                ignored: vec!["main_path", "matrix.homeserver_url"],
+            }
+        );
+        assert_eq!(merged.log_level, Level::Debug);
+        assert_eq!(merged.poller.interval_secs, 600);
+        assert_eq!(merged.matrix.admins.len(), 2);
+        assert_eq!(merged.main_path, PathBuf::from("/data"));
+        assert_eq!(merged.matrix.db_path, current.matrix.db_path);
+        assert_eq!(merged.matrix.homeserver_url, "http://localhost:8008");
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Commented config template, generated from the [`ConfigFile`] model
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::model::*;
+
+const HEADER: &str = "##
+## BraiinsPool Matrix Bot configuration file. Lines beginning with # are comments.
+##
+";
+
+/// Comment of each key (`""` is the root table), with an example for the keys without default
+const DOCS: &[(&str, &str, &str, Option<&str>)] = &[
+    ("", "main_path", "Directory where data should be stored", None),
+    ("", "log_level", "Console log level: TRACE, DEBUG, INFO, WARN, ERROR. Or a level per module, ex. { default = \"INFO\", braiinspool_matrix_bot = \"DEBUG\", matrix_sdk = \"WARN\" }", None),
+    ("", "log_format", "Log format: \"text\" or \"json\" (one object per line, with timestamp, level, target, message and the fields of the record)", None),
+    ("", "log_file", "Also write the log to this file, with the same level", Some("log_file = \"~/.braiinspool_bot/bot.log\"")),
+    ("", "log_file_max_size_mb", "Size of the log file before it's rotated to <log_file>.1", None),
+    ("", "log_file_keep", "Number of rotated log files kept, 0 = none", None),
+    ("", "tor_only", "Refuse to start if neither braiinspool.proxy nor matrix.proxy is set", None),
+    ("", "charts", "Enable the !chart command. Require the `charts` feature at build time", None),
+    ("", "dry_run", "Process the commands, but only log the messages the bot would send and the changes it would save (for staging)", None),
+    ("", "timezone", "Timezone of the displayed dates (IANA name, ex. \"Europe/Rome\"), users can set their own with !settings timezone", None),
+    ("", "command_namespace", "Namespace commands to avoid collisions with other bots (ex. \"bp\" -> \"!bp workers\")", Some("command_namespace = \"bp\"")),
+    ("", "enabled_commands", "Commands allowed in this deployment, the others answer that they are disabled (default: all)", Some("enabled_commands = [\"userstatus\", \"workers\", \"poolstatus\", \"help\"]")),
+    ("", "audit_log_max_entries", "Number of processed commands kept in the audit log (see !audit), 0 = disabled", None),
+    ("", "db_encryption_key", "Encrypt the BraiinsPool tokens stored in the database: 32 bytes encoded in base64 (`openssl rand -base64 32`)", Some("db_encryption_key = \"\"")),
+    ("", "db_encryption_key_file", "Or read the db encryption key from a file", Some("db_encryption_key_file = \"/path/to/db_encryption_key\"")),
+    ("braiinspool", "proxy", "Proxy used ONLY for BraiinsPool API calls. Supported schemes: http, https, socks5, socks5h (use socks5h for Tor)", Some("proxy = \"socks5h://127.0.0.1:9050\"")),
+    ("braiinspool", "proxy_username", "Proxy credentials, ex. for Tor stream isolation (IsolateSOCKSAuth)", Some("proxy_username = \"braiinspool\"")),
+    ("braiinspool", "proxy_password", "Proxy password, needs `proxy_username`", Some("proxy_password = \"\"")),
+    ("braiinspool", "auth_failure_threshold", "Warn the user after this many consecutive auth failures of its token", None),
+    ("braiinspool", "auth_failure_unlink", "Also unlink the token when the threshold is reached", None),
+    ("braiinspool", "max_concurrent_api_calls", "Max number of BraiinsPool API calls in flight at the same time", None),
+    ("braiinspool", "api_timeout_secs", "Give up a BraiinsPool API call without response after this many seconds", None),
+    ("braiinspool", "rate_limit_max_wait_secs", "When rate limited, wait the `Retry-After` of BraiinsPool and retry once if it's at most this many seconds, 0 = never wait", None),
+    ("poller", "interval_secs", "Interval between background polls of the BraiinsPool API, min 60", None),
+    ("poller", "jitter_secs", "Random delay added to each poll cycle, 0 = disabled", None),
+    ("poller", "worker_alerts", "Check the workers of the users with notifications enabled: zero hashrate and, with `stale_alert_mins` set, stale shares", None),
+    ("poller", "zero_hashrate_threshold", "Workers with a 5m hashrate (Gh/s) at or below this are flagged as not hashing, in !workers and in the alerts", None),
+    ("poller", "payout_notifications", "Notify the users of new payouts", None),
+    ("poller", "pool_alerts", "Notify the users of the blocks found by the pool", None),
+    ("matrix", "homeserver_url", "Your local, onion or clearnet homeserver url", None),
+    ("matrix", "proxy", "Needed if you want use onion homeserver url. Supported schemes: http, https, socks5, socks5h", Some("proxy = \"socks5h://127.0.0.1:9050\"")),
+    ("matrix", "user_id", "Matrix Bot user_id", None),
+    ("matrix", "password", "Matrix Bot password, needed only for the first login", None),
+    ("matrix", "password_file", "Or read the password from a file", Some("password_file = \"/run/secrets/matrix_pw\"")),
+    ("matrix", "crypto_store_passphrase", "Encrypt the E2E keys stored on disk. Set it before the first start: an existing store can't be re-keyed", Some("crypto_store_passphrase = \"\"")),
+    ("matrix", "crypto_store_passphrase_file", "Or read the crypto store passphrase from a file", Some("crypto_store_passphrase_file = \"/run/secrets/matrix_store\"")),
+    ("matrix", "admin_rooms", "Rooms where the bot send service notifications, like the startup message", None),
+    ("matrix", "active_rooms", "Process the commands only in these rooms, ex. an ops room (default: all). The bot still joins the rooms it's invited to", Some("active_rooms = [\"!roomid:example.com\"]")),
+    ("matrix", "admins", "Users allowed to run admin commands, like !health", None),
+    ("matrix", "sync_max_retry_secs", "Stop retrying the sync after this many seconds of consecutive failures, 0 = retry forever", None),
+    ("matrix", "sync_timeout_secs", "How long the homeserver holds a sync request open waiting for new events. Longer = fewer requests, but slower to notice a dropped connection", None),
+    ("matrix", "sync_full_state", "Request the full state of the joined rooms in the first sync after a start. Keeps the room state consistent after a long downtime, but makes the start slower and heavier on big accounts: disable it to only get the changes since the last run", None),
+    ("matrix", "login_max_retries", "Retries of the startup login when the homeserver is unreachable, with exponential backoff", None),
+    ("matrix", "max_messages_per_sec", "Max messages sent per second, 0 = unlimited", None),
+    ("matrix", "auto_verify", "Accept and confirm the SAS verification requests of the trusted users", None),
+    ("matrix", "verification_trusted", "Users, or single devices as \"user_id/device_id\", allowed to verify the bot (default: admins)", Some("verification_trusted = [\"@admin:example.com\"]")),
+    ("matrix", "subscribe_in_dm", "Answer !subscribe in a group room by opening a direct room with the user, instead of subscribing there", None),
+    ("health", "bind_addr", "Start an HTTP server for liveness/readiness probes (/healthz, /readyz)", Some("bind_addr = \"127.0.0.1:8080\"")),
+    ("health", "max_sync_age_mins", "Max age of the last Matrix sync for /healthz", None),
+    ("templates", "userstatus", "Layout of !userstatus. Placeholders: {reward}, {unconfirmed_reward}, {estimated_reward}, {hashrate_5m}, {total_worker_hashrate}, {hashrate_60m}, {hashrate_24h}, {hashrate_scoring}, {hashrate_yesterday}, {ok_workers}, {low_workers}, {off_workers}, {dis_workers}", Some("userstatus = \"Rewards: {reward} (estimated {estimated_reward})\\nHashrate: {hashrate_5m}\"")),
+    ("templates", "workers", "Layout of each worker of !workers. Placeholders: {name}, {state}, {last_share}, {hashrate_scoring}, {hashrate_5m}, {hashrate_60m}, {hashrate_24h}", Some("workers = \"{name} ({state}): {hashrate_5m}\"")),
+    ("templates", "dailyrewards", "Layout of each day of !dailyrewards. Placeholders: {date}, {reward}", Some("dailyrewards = \"{date}: {reward}\"")),
+    ("templates", "poolstatus", "Layout of !poolstatus. Placeholders: {luck_10}, {luck_50}, {luck_250}, {hashrate_scoring}, {active_workers}, {round_probability}", Some("poolstatus = \"Pool hashrate: {hashrate_scoring}\\nLuck: {luck_10} / {luck_50} / {luck_250}\"")),
+];
+
+impl ConfigFile {
+    /// Default values, secrets left blank
+    fn template() -> Self {
+        Self {
+            main_path: Some(PathBuf::from("~/.braiinspool_bot")),
+            log_level: Some(ConfigFileLogLevel::Level(String::from("INFO"))),
+            log_format: Some(String::from("text")),
+            log_file: None,
+            log_file_max_size_mb: Some(10),
+            log_file_keep: Some(5),
+            proxy: None,
+            tor_only: Some(false),
+            charts: Some(false),
+            dry_run: Some(false),
+            timezone: Some(String::from("UTC")),
+            command_namespace: None,
+            enabled_commands: None,
+            poll_interval_secs: None,
+            poll_jitter_secs: None,
+            audit_log_max_entries: Some(5000),
+            db_encryption_key: None,
+            db_encryption_key_file: None,
+            braiinspool: Some(ConfigFileBraiinsPool {
+                proxy: None,
+                proxy_username: None,
+                proxy_password: None,
+                auth_failure_threshold: Some(3),
+                auth_failure_unlink: Some(false),
+                max_concurrent_api_calls: Some(4),
+                api_timeout_secs: Some(30),
+                rate_limit_max_wait_secs: Some(5),
+            }),
+            poller: Some(ConfigFilePoller {
+                interval_secs: Some(300),
+                jitter_secs: Some(30),
+                worker_alerts: Some(true),
+                zero_hashrate_threshold: Some(0),
+                payout_notifications: Some(true),
+                pool_alerts: Some(true),
+            }),
+            matrix: ConfigFileMatrix {
+                homeserver_url: String::from("http://localhost:8008"),
+                proxy: None,
+                user_id: String::from("@username:example.com"),
+                password: Some(String::new()),
+                password_file: None,
+                crypto_store_passphrase: None,
+                crypto_store_passphrase_file: None,
+                admin_rooms: Some(Vec::new()),
+                active_rooms: None,
+                admins: Some(Vec::new()),
+                sync_max_retry_secs: Some(0),
+                sync_timeout_secs: Some(30),
+                sync_full_state: Some(true),
+                login_max_retries: Some(5),
+                max_messages_per_sec: Some(5),
+                auto_verify: Some(false),
+                verification_trusted: None,
+                subscribe_in_dm: Some(true),
+            },
+            health: Some(ConfigFileHealth {
+                bind_addr: None,
+                max_sync_age_mins: Some(5),
+            }),
+            templates: Some(ConfigFileTemplates {
+                userstatus: None,
+                workers: None,
+                dailyrewards: None,
+                poolstatus: None,
+            }),
+        }
+    }
+}
+
+/// Commented TOML of [`ConfigFile::template`]. Keys without default are commented out.
+pub fn template() -> String {
+    let content: String = toml::to_string_pretty(&ConfigFile::template())
+        .expect("config template must be serializable");
+
+    let mut output = String::from(HEADER);
+    let mut table = String::new();
+
+    for line in content.lines() {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            push_commented_keys(&mut output, &table);
+            table = name.to_string();
+            output.push('\n');
+            output.push_str(line);
+            output.push('\n');
+            continue;
+        }
+
+        if let Some((key, _)) = line.split_once(" = ") {
+            if let Some((_, _, doc, _)) = DOCS.iter().find(|(t, k, ..)| *t == table && *k == key) {
+                output.push_str(&format!("\n# {}\n", doc));
+            }
+        }
+
+        output.push_str(line);
+        output.push('\n');
+    }
+
+    push_commented_keys(&mut output, &table);
+
+    output
+}
+
+/// Append the keys of `table` without default, commented out
+fn push_commented_keys(output: &mut String, table: &str) {
+    for (_, _, doc, example) in DOCS.iter().filter(|(t, ..)| *t == table) {
+        if let Some(example) = example {
+            output.push_str(&format!("\n# {}\n# {}\n", doc, example));
+        }
+    }
+}
+
+/// Write the template, refusing to overwrite an existing file unless `force` is set
+pub fn write_template(path: &Path, force: bool) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .create_new(!force)
+        .truncate(true)
+        .open(path)?;
+
+    file.write_all(template().as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::super::validate::{self, KNOWN_KEYS};
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        let content: String = template();
+
+        let config_file: ConfigFile = toml::from_str(&content).unwrap();
+        assert!(validate::validate(&config_file).is_empty());
+        assert!(validate::unknown_keys(&content).is_empty());
+
+        // Every key is documented, except the deprecated ones
+        for (table, keys) in KNOWN_KEYS.iter() {
+            for key in keys.iter() {
+                let deprecated: bool = table.is_empty()
+                    && ["proxy", "poll_interval_secs", "poll_jitter_secs"].contains(key);
+                let is_table: bool = KNOWN_KEYS.iter().any(|(t, _)| t == key);
+
+                if !deprecated && !is_table {
+                    assert!(
+                        DOCS.iter().any(|(t, k, ..)| t == table && k == key),
+                        "`{}.{}` not documented",
+                        table,
+                        key
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_template() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+
+        write_template(&path, false).unwrap();
+        assert!(write_template(&path, false).is_err());
+        assert!(write_template(&path, true).is_ok());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), template());
+    }
+}
@@ -0,0 +1,672 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Config file checks, reported all together with the offending keys
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono_tz::Tz;
+use dirs::home_dir;
+use log::Level;
+use matrix_sdk::ruma::{RoomId, UserId};
+
+use super::model::{ConfigFile, ConfigFileLogLevel, LogFormat, ProxyScheme, Templates};
+use super::MIN_POLL_INTERVAL_SECS;
+use crate::util;
+
+/// Keys of each table of the config file (`""` is the root), kept in sync with the
+/// `ConfigFile` fields by `test_known_keys`
+pub(super) const KNOWN_KEYS: &[(&str, &[&str])] = &[
+    (
+        "",
+        &[
+            "main_path",
+            "log_level",
+            "log_format",
+            "log_file",
+            "log_file_max_size_mb",
+            "log_file_keep",
+            "proxy",
+            "tor_only",
+            "charts",
+            "dry_run",
+            "timezone",
+            "command_namespace",
+            "enabled_commands",
+            "poll_interval_secs",
+            "poll_jitter_secs",
+            "audit_log_max_entries",
+            "db_encryption_key",
+            "db_encryption_key_file",
+            "braiinspool",
+            "poller",
+            "matrix",
+            "health",
+            "templates",
+        ],
+    ),
+    (
+        "braiinspool",
+        &[
+            "proxy",
+            "proxy_username",
+            "proxy_password",
+            "auth_failure_threshold",
+            "auth_failure_unlink",
+            "max_concurrent_api_calls",
+            "api_timeout_secs",
+            "rate_limit_max_wait_secs",
+        ],
+    ),
+    (
+        "poller",
+        &[
+            "interval_secs",
+            "jitter_secs",
+            "worker_alerts",
+            "zero_hashrate_threshold",
+            "payout_notifications",
+            "pool_alerts",
+        ],
+    ),
+    (
+        "matrix",
+        &[
+            "homeserver_url",
+            "proxy",
+            "user_id",
+            "password",
+            "password_file",
+            "crypto_store_passphrase",
+            "crypto_store_passphrase_file",
+            "admin_rooms",
+            "active_rooms",
+            "admins",
+            "sync_max_retry_secs",
+            "sync_timeout_secs",
+            "sync_full_state",
+            "login_max_retries",
+            "max_messages_per_sec",
+            "auto_verify",
+            "verification_trusted",
+            "subscribe_in_dm",
+        ],
+    ),
+    ("health", &["bind_addr", "max_sync_age_mins"]),
+    (
+        "templates",
+        &["userstatus", "workers", "dailyrewards", "poolstatus"],
+    ),
+];
+
+/// Deprecated keys set in the config file, with the ones that replace them
+pub fn deprecated_keys(config_file: &ConfigFile) -> Vec<(&'static str, &'static str)> {
+    let mut deprecated: Vec<(&str, &str)> = Vec::new();
+
+    if config_file.proxy.is_some() {
+        deprecated.push(("proxy", "braiinspool.proxy"));
+    }
+
+    if config_file.poll_interval_secs.is_some() {
+        deprecated.push(("poll_interval_secs", "poller.interval_secs"));
+    }
+
+    if config_file.poll_jitter_secs.is_some() {
+        deprecated.push(("poll_jitter_secs", "poller.jitter_secs"));
+    }
+
+    deprecated
+}
+
+/// Keys not used by the bot, probably typos
+pub fn unknown_keys(content: &str) -> Vec<String> {
+    let root: toml::Value = match toml::from_str(content) {
+        Ok(value) => value,
+        // Reported with more context when parsing the `ConfigFile`
+        Err(_) => return Vec::new(),
+    };
+
+    let mut unknown: Vec<String> = Vec::new();
+
+    for (table, known) in KNOWN_KEYS.iter() {
+        let value: Option<&toml::Value> = if table.is_empty() {
+            Some(&root)
+        } else {
+            root.get(table)
+        };
+
+        if let Some(toml::Value::Table(table_value)) = value {
+            for key in table_value.keys() {
+                if !known.contains(&key.as_str()) {
+                    if table.is_empty() {
+                        unknown.push(key.clone());
+                    } else {
+                        unknown.push(format!("{}.{}", table, key));
+                    }
+                }
+            }
+        }
+    }
+
+    unknown
+}
+
+/// Collect all the problems of the config file
+pub fn validate(config_file: &ConfigFile) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    let matrix = &config_file.matrix;
+
+    problems.extend(check_http_url(
+        "matrix.homeserver_url",
+        &matrix.homeserver_url,
+    ));
+
+    if Box::<UserId>::try_from(matrix.user_id.as_str()).is_err() {
+        problems.push(format!(
+            "`matrix.user_id`: invalid user id {:?} (expected format: @username:example.com)",
+            matrix.user_id
+        ));
+    }
+
+    if matrix.password.is_some() && matrix.password_file.is_some() {
+        problems.push(String::from(
+            "`matrix.password`: set only one of `matrix.password` and `matrix.password_file`",
+        ));
+    }
+
+    if matrix.crypto_store_passphrase.is_some() && matrix.crypto_store_passphrase_file.is_some() {
+        problems.push(String::from(
+            "`matrix.crypto_store_passphrase`: set only one of `matrix.crypto_store_passphrase` and `matrix.crypto_store_passphrase_file`",
+        ));
+    }
+
+    for room_id in matrix.active_rooms.iter().flatten() {
+        if <&RoomId>::try_from(room_id.as_str()).is_err() {
+            problems.push(format!(
+                "`matrix.active_rooms`: invalid room id {:?} (expected format: !roomid:example.com, aliases are not supported)",
+                room_id
+            ));
+        }
+    }
+
+    if config_file.db_encryption_key.is_some() && config_file.db_encryption_key_file.is_some() {
+        problems.push(String::from(
+            "`db_encryption_key`: set only one of `db_encryption_key` and `db_encryption_key_file`",
+        ));
+    }
+
+    let (pool_proxy_key, pool_proxy) = match &config_file.braiinspool {
+        Some(braiinspool) if braiinspool.proxy.is_some() => {
+            ("braiinspool.proxy", braiinspool.proxy.as_deref())
+        }
+        _ => ("proxy", config_file.proxy.as_deref()),
+    };
+
+    problems.extend(pool_proxy.and_then(|proxy| check_proxy(pool_proxy_key, proxy)));
+    problems.extend(
+        matrix
+            .proxy
+            .as_deref()
+            .and_then(|proxy| check_proxy("matrix.proxy", proxy)),
+    );
+
+    if let Some(braiinspool) = &config_file.braiinspool {
+        if braiinspool.proxy_username.is_some() && pool_proxy.is_none() {
+            problems.push(String::from(
+                "`braiinspool.proxy_username`: set `braiinspool.proxy` to use the proxy credentials",
+            ));
+        }
+
+        if braiinspool.proxy_password.is_some() && braiinspool.proxy_username.is_none() {
+            problems.push(String::from(
+                "`braiinspool.proxy_password`: set also `braiinspool.proxy_username`",
+            ));
+        }
+    }
+
+    if config_file.tor_only.unwrap_or(false) && pool_proxy.is_none() && matrix.proxy.is_none() {
+        problems.push(String::from(
+            "`tor_only`: enabled but neither `braiinspool.proxy` nor `matrix.proxy` is set",
+        ));
+    }
+
+    let log_levels: Vec<(String, &String)> = match &config_file.log_level {
+        Some(ConfigFileLogLevel::Level(log_level)) => vec![(String::from("log_level"), log_level)],
+        Some(ConfigFileLogLevel::Modules(modules)) => modules
+            .iter()
+            .map(|(module, log_level)| (format!("log_level.{}", module), log_level))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    for (key, log_level) in log_levels.into_iter() {
+        if Level::from_str(log_level).is_err() {
+            problems.push(format!(
+                "`{}`: unknown level {:?} (expected: TRACE, DEBUG, INFO, WARN, ERROR)",
+                key, log_level
+            ));
+        }
+    }
+
+    if let Some(log_format) = &config_file.log_format {
+        if LogFormat::from_str(log_format).is_err() {
+            problems.push(format!(
+                "`log_format`: unknown format {:?} (expected: text, json)",
+                log_format
+            ));
+        }
+    }
+
+    if config_file.log_file_max_size_mb == Some(0) {
+        problems.push(String::from(
+            "`log_file_max_size_mb`: must be greater than 0",
+        ));
+    }
+
+    if let Some(timezone) = &config_file.timezone {
+        if Tz::from_str(timezone).is_err() {
+            problems.push(format!(
+                "`timezone`: unknown timezone {:?} (expected an IANA name, ex. Europe/Rome or UTC)",
+                timezone
+            ));
+        }
+    }
+
+    if let Some(braiinspool) = &config_file.braiinspool {
+        if braiinspool.max_concurrent_api_calls == Some(0) {
+            problems.push(String::from(
+                "`braiinspool.max_concurrent_api_calls`: must be greater than 0",
+            ));
+        }
+
+        if braiinspool.api_timeout_secs == Some(0) {
+            problems.push(String::from(
+                "`braiinspool.api_timeout_secs`: must be greater than 0",
+            ));
+        }
+    }
+
+    // The deprecated root key is the fallback of the `[poller]` one
+    let interval = config_file
+        .poller
+        .as_ref()
+        .and_then(|poller| poller.interval_secs)
+        .map(|interval_secs| ("poller.interval_secs", interval_secs))
+        .or_else(|| {
+            config_file
+                .poll_interval_secs
+                .map(|interval_secs| ("poll_interval_secs", interval_secs))
+        });
+
+    if let Some((interval_key, interval_secs)) = interval {
+        if interval_secs < MIN_POLL_INTERVAL_SECS {
+            problems.push(format!(
+                "`{}`: must be at least {}",
+                interval_key, MIN_POLL_INTERVAL_SECS
+            ));
+        }
+    }
+
+    if let Some(templates) = &config_file.templates {
+        let templates = [
+            ("userstatus", &templates.userstatus),
+            ("workers", &templates.workers),
+            ("dailyrewards", &templates.dailyrewards),
+            ("poolstatus", &templates.poolstatus),
+        ];
+
+        for (name, tmpl) in templates.into_iter() {
+            if let Some(tmpl) = tmpl {
+                problems.extend(check_template(name, tmpl));
+            }
+        }
+    }
+
+    let paths = [
+        ("main_path", config_file.main_path.as_ref()),
+        ("log_file", config_file.log_file.as_ref()),
+        (
+            "db_encryption_key_file",
+            config_file.db_encryption_key_file.as_ref(),
+        ),
+        ("matrix.password_file", matrix.password_file.as_ref()),
+        (
+            "matrix.crypto_store_passphrase_file",
+            matrix.crypto_store_passphrase_file.as_ref(),
+        ),
+    ];
+
+    for (key, path) in paths.into_iter() {
+        if let Some(path) = path {
+            if !expand_path(path).is_absolute() {
+                problems.push(format!(
+                    "`{}`: path {:?} must be absolute or start with `~/`",
+                    key, path
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Replace a leading `~` with the home directory
+pub fn expand_path(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(relative) => match home_dir() {
+            Some(home) => home.join(relative),
+            None => path.to_path_buf(),
+        },
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+fn check_http_url(key: &str, url: &str) -> Option<String> {
+    let address: &str = match url.split_once("://") {
+        Some(("http", address)) | Some(("https", address)) => address,
+        _ => {
+            return Some(format!(
+                "`{}`: {:?} is not an http(s) url (ex. https://matrix.example.com)",
+                key, url
+            ))
+        }
+    };
+
+    let host: &str = address.split('/').next().unwrap_or_default();
+
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Some(format!("`{}`: invalid host in {:?}", key, url));
+    }
+
+    None
+}
+
+/// Check that the template uses only the placeholders available for its command
+fn check_template(name: &str, tmpl: &str) -> Option<String> {
+    let available: &[&str] = Templates::PLACEHOLDERS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, placeholders)| *placeholders)
+        .unwrap_or_default();
+
+    let unknown: Vec<String> = util::template_placeholders(tmpl)
+        .into_iter()
+        .filter(|placeholder| !available.contains(placeholder))
+        .map(|placeholder| format!("{{{}}}", placeholder))
+        .collect();
+
+    if unknown.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "`templates.{}`: unknown placeholders {} (available: {})",
+        name,
+        unknown.join(", "),
+        available.join(", ")
+    ))
+}
+
+/// Check that proxy url has a scheme supported by both Matrix and BraiinsPool clients
+fn check_proxy(key: &str, proxy: &str) -> Option<String> {
+    let (scheme, address) = proxy.split_once("://").unwrap_or(("", proxy));
+
+    if ProxyScheme::from_str(scheme).is_err() {
+        return Some(format!(
+            "`{}`: unsupported proxy scheme {:?} (supported: http, https, socks5, socks5h)",
+            key, scheme
+        ));
+    }
+
+    if address.is_empty() || address.contains(char::is_whitespace) {
+        return Some(format!("`{}`: missing or invalid proxy address", key));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::model::{
+        ConfigFileBraiinsPool, ConfigFileHealth, ConfigFileMatrix, ConfigFilePoller,
+        ConfigFileTemplates,
+    };
+    use super::*;
+
+    const CONFIG: &str = r#"
+        tor_only = true
+        log_level = "LOUD"
+        log_format = "logfmt"
+        log_file = "bot.log"
+        log_file_max_size_mb = 0
+        timezone = "Europe/Atlantis"
+
+        [braiinspool]
+        proxy = "socks4://127.0.0.1:9050"
+        max_concurrent_api_calls = 0
+        api_timeout_secs = 0
+
+        [poller]
+        interval_secs = 10
+
+        [matrix]
+        homserver_url = "http://localhost:8008"
+        homeserver_url = "localhost:8008"
+        user_id = "bot"
+        password = "password"
+        password_file = "relative/path"
+        active_rooms = ["ops:example.com"]
+    "#;
+
+    /// Every field set: a field added to `ConfigFile` must be added here, then to `KNOWN_KEYS`
+    fn full_config_file() -> ConfigFile {
+        ConfigFile {
+            main_path: Some(PathBuf::from("/data")),
+            log_level: Some(ConfigFileLogLevel::Level(String::from("info"))),
+            log_format: Some(String::from("text")),
+            log_file: Some(PathBuf::from("/data/bot.log")),
+            log_file_max_size_mb: Some(10),
+            log_file_keep: Some(5),
+            proxy: Some(String::from("socks5h://127.0.0.1:9050")),
+            tor_only: Some(true),
+            charts: Some(true),
+            dry_run: Some(false),
+            timezone: Some(String::from("UTC")),
+            command_namespace: Some(String::from("pool")),
+            enabled_commands: Some(vec![String::from("ping")]),
+            poll_interval_secs: Some(300),
+            poll_jitter_secs: Some(30),
+            audit_log_max_entries: Some(5000),
+            db_encryption_key: Some(String::from("key")),
+            db_encryption_key_file: Some(PathBuf::from("/data/key")),
+            braiinspool: Some(ConfigFileBraiinsPool {
+                proxy: Some(String::from("socks5h://127.0.0.1:9050")),
+                proxy_username: Some(String::from("bot")),
+                proxy_password: Some(String::from("password")),
+                auth_failure_threshold: Some(3),
+                auth_failure_unlink: Some(false),
+                max_concurrent_api_calls: Some(4),
+                api_timeout_secs: Some(30),
+                rate_limit_max_wait_secs: Some(5),
+            }),
+            poller: Some(ConfigFilePoller {
+                interval_secs: Some(300),
+                jitter_secs: Some(30),
+                worker_alerts: Some(true),
+                zero_hashrate_threshold: Some(0),
+                payout_notifications: Some(true),
+                pool_alerts: Some(true),
+            }),
+            matrix: ConfigFileMatrix {
+                homeserver_url: String::from("http://localhost:8008"),
+                proxy: Some(String::from("http://127.0.0.1:3128")),
+                user_id: String::from("@bot:example.com"),
+                password: Some(String::from("password")),
+                password_file: Some(PathBuf::from("/data/password")),
+                crypto_store_passphrase: Some(String::from("passphrase")),
+                crypto_store_passphrase_file: Some(PathBuf::from("/data/passphrase")),
+                admin_rooms: Some(vec![String::from("!admin:example.com")]),
+                active_rooms: Some(vec![String::from("!room:example.com")]),
+                admins: Some(vec![String::from("@admin:example.com")]),
+                sync_max_retry_secs: Some(0),
+                sync_timeout_secs: Some(30),
+                sync_full_state: Some(true),
+                login_max_retries: Some(5),
+                max_messages_per_sec: Some(5),
+                auto_verify: Some(false),
+                verification_trusted: Some(vec![String::from("@admin:example.com")]),
+                subscribe_in_dm: Some(true),
+            },
+            health: Some(ConfigFileHealth {
+                bind_addr: Some("127.0.0.1:8080".parse().unwrap()),
+                max_sync_age_mins: Some(5),
+            }),
+            templates: Some(ConfigFileTemplates {
+                userstatus: Some(String::from("{reward}")),
+                workers: Some(String::from("{name}")),
+                dailyrewards: Some(String::from("{date}")),
+                poolstatus: Some(String::from("{luck_10}")),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_known_keys() {
+        let content: String = toml::to_string(&full_config_file()).unwrap();
+        assert_eq!(unknown_keys(&content), Vec::<String>::new());
+
+        // No leftovers of removed fields
+        let root: toml::Value = toml::from_str(&content).unwrap();
+        for (table, keys) in KNOWN_KEYS.iter() {
+            let table_value: &toml::Value = if table.is_empty() {
+                &root
+            } else {
+                &root[*table]
+            };
+            for key in keys.iter() {
+                assert!(
+                    table_value.get(*key).is_some(),
+                    "`{}` in KNOWN_KEYS is not a field of ConfigFile",
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        assert_eq!(unknown_keys(CONFIG), vec!["matrix.homserver_url"]);
+        assert!(unknown_keys("not toml = = =").is_empty());
+    }
+
+    #[test]
+    fn test_deprecated_keys() {
+        let config_file: ConfigFile = toml::from_str(CONFIG).unwrap();
+        assert!(deprecated_keys(&config_file).is_empty());
+
+        // Also with a `[poller]` section
+        let config: String = CONFIG.replace("tor_only = true", "poll_jitter_secs = 10");
+        let config_file: ConfigFile = toml::from_str(&config).unwrap();
+        assert_eq!(
+            deprecated_keys(&config_file),
+            vec![("poll_jitter_secs", "poller.jitter_secs")]
+        );
+
+        // Also with a `[braiinspool]` section
+        let config: String =
+            CONFIG.replace("tor_only = true", "proxy = \"socks5h://127.0.0.1:9050\"");
+        let config_file: ConfigFile = toml::from_str(&config).unwrap();
+        assert_eq!(
+            deprecated_keys(&config_file),
+            vec![("proxy", "braiinspool.proxy")]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let config_file: ConfigFile = toml::from_str(CONFIG).unwrap();
+        let problems: Vec<String> = validate(&config_file);
+        let keys: Vec<&str> = problems
+            .iter()
+            .map(|problem| problem.split('`').nth(1).unwrap())
+            .collect();
+
+        assert_eq!(
+            keys,
+            vec![
+                "matrix.homeserver_url",
+                "matrix.user_id",
+                "matrix.password",
+                "matrix.active_rooms",
+                "braiinspool.proxy",
+                "log_level",
+                "log_format",
+                "log_file_max_size_mb",
+                "timezone",
+                "braiinspool.max_concurrent_api_calls",
+                "braiinspool.api_timeout_secs",
+                "poller.interval_secs",
+                "log_file",
+                "matrix.password_file"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_log_modules() {
+        let config: String = CONFIG.replace(
+            "log_level = \"LOUD\"",
+            "log_level = { default = \"info\", braiinspool_matrix_bot = \"debug\", matrix_sdk = \"LOUD\" }",
+        );
+        let config_file: ConfigFile = toml::from_str(&config).unwrap();
+        let problems: Vec<String> = validate(&config_file);
+
+        assert!(problems.contains(&String::from(
+            "`log_level.matrix_sdk`: unknown level \"LOUD\" (expected: TRACE, DEBUG, INFO, WARN, ERROR)"
+        )));
+        assert!(!problems
+            .iter()
+            .any(|problem| problem.starts_with("`log_level`")
+                || problem.starts_with("`log_level.default`")
+                || problem.starts_with("`log_level.braiinspool_matrix_bot`")));
+    }
+
+    #[test]
+    fn test_check_template() {
+        assert_eq!(check_template("dailyrewards", "{date}: {reward}"), None);
+        assert_eq!(check_template("dailyrewards", "{{literal}}"), None);
+        assert_eq!(
+            check_template("dailyrewards", "{date}: {rewards} {luck}"),
+            Some(String::from(
+                "`templates.dailyrewards`: unknown placeholders {rewards}, {luck} (available: date, reward)"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_http_url() {
+        assert_eq!(check_http_url("url", "http://localhost:8008"), None);
+        assert_eq!(check_http_url("url", "https://matrix.example.com/"), None);
+        assert!(check_http_url("url", "matrix.example.com").is_some());
+        assert!(check_http_url("url", "ftp://matrix.example.com").is_some());
+        assert!(check_http_url("url", "https://").is_some());
+        assert!(check_http_url("url", "https://matrix example.com").is_some());
+    }
+
+    #[test]
+    fn test_check_proxy() {
+        assert_eq!(check_proxy("proxy", "socks5h://127.0.0.1:9050"), None);
+        assert!(check_proxy("proxy", "127.0.0.1:9050").is_some());
+        assert!(check_proxy("proxy", "socks5h://").is_some());
+    }
+
+    #[test]
+    fn test_expand_path() {
+        assert_eq!(expand_path(Path::new("/data")), PathBuf::from("/data"));
+        assert_eq!(expand_path(Path::new("data")), PathBuf::from("data"));
+        if let Some(home) = home_dir() {
+            assert_eq!(expand_path(Path::new("~/data")), home.join("data"));
+        }
+    }
+}
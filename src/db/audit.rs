@@ -0,0 +1,175 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Capped log of the processed commands
+//!
+//! Entries are keyed by a zero-padded sequence number, so they are iterated in
+//! insertion order. The oldest entries are pruned on every write.
+
+use bpns_rocksdb::IteratorMode;
+
+use super::{DBStore, Error};
+
+const AUDIT_SEQ_KEY: &str = "audit_seq";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: i64,
+    /// Name only, never the arguments: they can be tokens
+    pub command: String,
+    pub sender: String,
+    pub room_id: String,
+    /// `ok` or the error variant
+    pub outcome: String,
+    pub latency_ms: u64,
+}
+
+fn audit_key(seq: u64) -> String {
+    format!("{:020}", seq)
+}
+
+impl DBStore {
+    /// Append an entry, keeping only the last `max_entries`
+    pub fn add_audit_entry(&self, entry: &AuditEntry, max_entries: u64) -> Result<(), Error> {
+        // Not poisoned by a panic elsewhere: it guards no data
+        let _guard = self
+            .audit_lock
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+
+        let seq: u64 = self
+            .db
+            .get_deserialized(self.meta_cf(), AUDIT_SEQ_KEY)
+            .unwrap_or(0);
+
+        let mut batch = self.batch();
+        batch.put(self.audit_cf(), &audit_key(seq), entry)?;
+        batch.put(self.meta_cf(), AUDIT_SEQ_KEY, &(seq + 1))?;
+
+        // Also drops the leftovers of a previous, bigger, limit
+        let first_kept: String = audit_key((seq + 1).saturating_sub(max_entries));
+        for (key, _) in self
+            .db
+            .db
+            .iterator_cf(&self.audit_cf(), IteratorMode::Start)
+        {
+            let key: String = String::from_utf8_lossy(&key).to_string();
+            if key >= first_kept {
+                break;
+            }
+            batch.delete(self.audit_cf(), &key);
+        }
+
+        batch.commit()
+    }
+
+    /// Last `limit` entries, newest first, optionally only the ones sent by `sender`
+    pub fn get_audit_entries(&self, sender: Option<&str>, limit: usize) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = Vec::new();
+
+        for (key, _) in self.db.db.iterator_cf(&self.audit_cf(), IteratorMode::End) {
+            let key: String = String::from_utf8_lossy(&key).to_string();
+            let entry: AuditEntry = match self.db.get_deserialized(self.audit_cf(), &key) {
+                Ok(entry) => entry,
+                Err(error) => {
+                    log::error!("Impossible to deserialize audit entry {}: {:?}", key, error);
+                    continue;
+                }
+            };
+
+            if sender.map_or(true, |sender| entry.sender == sender) {
+                entries.push(entry);
+                if entries.len() >= limit {
+                    break;
+                }
+            }
+        }
+
+        entries
+    }
+
+    #[cfg(test)]
+    pub fn count_audit_entries(&self) -> usize {
+        self.db
+            .db
+            .iterator_cf(&self.audit_cf(), IteratorMode::Start)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn entry(i: i64, sender: &str) -> AuditEntry {
+        AuditEntry {
+            timestamp: i,
+            command: String::from("!userstatus"),
+            sender: sender.into(),
+            room_id: String::from("!room:example.com"),
+            outcome: String::from("ok"),
+            latency_ms: 10,
+        }
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        for i in 0..30 {
+            let sender = if i % 3 == 0 {
+                "@bob:example.com"
+            } else {
+                "@alice:example.com"
+            };
+            store.add_audit_entry(&entry(i, sender), 25).unwrap();
+        }
+
+        // Pruned to the last 25
+        assert_eq!(store.count_audit_entries(), 25);
+
+        let entries = store.get_audit_entries(None, 20);
+        assert_eq!(entries.len(), 20);
+        assert_eq!(entries[0].timestamp, 29);
+        assert_eq!(entries[19].timestamp, 10);
+
+        let entries = store.get_audit_entries(Some("@bob:example.com"), 20);
+        let timestamps: Vec<i64> = entries.iter().map(|entry| entry.timestamp).collect();
+        assert_eq!(timestamps, vec![27, 24, 21, 18, 15, 12, 9, 6]);
+
+        // Lower limit
+        store
+            .add_audit_entry(&entry(30, "@bob:example.com"), 5)
+            .unwrap();
+        assert_eq!(store.count_audit_entries(), 5);
+        assert_eq!(store.get_audit_entries(None, 20)[4].timestamp, 26);
+    }
+
+    #[test]
+    fn test_concurrent_audit_entries() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for i in 0..10 {
+                        store
+                            .add_audit_entry(&entry(thread * 10 + i, "@alice:example.com"), 100)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles.into_iter() {
+            handle.join().unwrap();
+        }
+
+        // No entry overwritten by another with the same sequence number
+        assert_eq!(store.count_audit_entries(), 40);
+    }
+}
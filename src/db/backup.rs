@@ -0,0 +1,296 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! JSON backup of the database
+//!
+//! Records are exported as stored: if token encryption is enabled, tokens stay
+//! encrypted and the backup can be imported only with the same key.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use bpns_rocksdb::{BoundColumnFamily, IteratorMode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{
+    Batch, DBStore, Error, HashrateSample, Notification, RoomCommands, Session, User, UserSettings,
+    SCHEMA_VERSION,
+};
+
+/// Version of the backup document
+pub const BACKUP_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub schema_version: u32,
+    pub token_encryption: bool,
+    pub users: BTreeMap<String, User>,
+    pub sessions: BTreeMap<String, Session>,
+    pub settings: BTreeMap<String, UserSettings>,
+    pub hashrate: BTreeMap<String, Vec<HashrateSample>>,
+    pub auth_failures: BTreeMap<String, u32>,
+    #[serde(default)]
+    pub notifications: BTreeMap<String, Notification>,
+    #[serde(default)]
+    pub room_commands: BTreeMap<String, RoomCommands>,
+    #[serde(default)]
+    pub rewards_history: BTreeMap<String, f64>,
+    /// Rooms where the welcome message was sent, with the timestamp
+    #[serde(default)]
+    pub welcomed_rooms: BTreeMap<String, i64>,
+    /// Direct room opened with each user
+    #[serde(default)]
+    pub dm_rooms: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Db(Error),
+    UnsupportedVersion(u32),
+    /// Backup created with a different schema version
+    SchemaMismatch(u32),
+    /// Backup and database disagree on token encryption
+    EncryptionMismatch,
+    /// Keys already in the database, pass `force` to overwrite them
+    ExistingKeys(usize),
+}
+
+impl Backup {
+    /// Number of records by column family
+    pub fn summary(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("users", self.users.len()),
+            ("sessions", self.sessions.len()),
+            ("settings", self.settings.len()),
+            ("hashrate", self.hashrate.len()),
+            ("auth_failures", self.auth_failures.len()),
+            ("notifications", self.notifications.len()),
+            ("room_commands", self.room_commands.len()),
+            ("rewards_history", self.rewards_history.len()),
+            ("welcomed_rooms", self.welcomed_rooms.len()),
+            ("dm_rooms", self.dm_rooms.len()),
+        ]
+    }
+}
+
+impl DBStore {
+    pub fn export(&self) -> Backup {
+        Backup {
+            version: BACKUP_VERSION,
+            schema_version: self.get_schema_version(),
+            token_encryption: self.is_token_encryption_enabled(),
+            users: self.dump(self.user_cf()),
+            sessions: self.dump(self.session_cf()),
+            settings: self.dump(self.settings_cf()),
+            hashrate: self.dump(self.hashrate_cf()),
+            auth_failures: self.dump(self.auth_failure_cf()),
+            notifications: self.dump(self.notification_cf()),
+            room_commands: self.dump(self.room_commands_cf()),
+            rewards_history: self.dump(self.rewards_history_cf()),
+            welcomed_rooms: self.dump(self.welcome_cf()),
+            dm_rooms: self.dump(self.dm_room_cf()),
+        }
+    }
+
+    /// Import a backup. Existing keys are overwritten only if `force` is set.
+    pub fn import(&self, backup: &Backup, force: bool) -> Result<(), ImportError> {
+        if backup.version != BACKUP_VERSION {
+            return Err(ImportError::UnsupportedVersion(backup.version));
+        }
+
+        if backup.schema_version != SCHEMA_VERSION {
+            return Err(ImportError::SchemaMismatch(backup.schema_version));
+        }
+
+        if backup.token_encryption != self.is_token_encryption_enabled() {
+            return Err(ImportError::EncryptionMismatch);
+        }
+
+        if !force {
+            let existing: usize = self.count_existing(self.user_cf(), &backup.users)?
+                + self.count_existing(self.session_cf(), &backup.sessions)?
+                + self.count_existing(self.settings_cf(), &backup.settings)?
+                + self.count_existing(self.hashrate_cf(), &backup.hashrate)?
+                + self.count_existing(self.auth_failure_cf(), &backup.auth_failures)?
+                + self.count_existing(self.notification_cf(), &backup.notifications)?
+                + self.count_existing(self.room_commands_cf(), &backup.room_commands)?
+                + self.count_existing(self.rewards_history_cf(), &backup.rewards_history)?
+                + self.count_existing(self.welcome_cf(), &backup.welcomed_rooms)?
+                + self.count_existing(self.dm_room_cf(), &backup.dm_rooms)?;
+
+            if existing > 0 {
+                return Err(ImportError::ExistingKeys(existing));
+            }
+        }
+
+        // All or nothing: a failure doesn't leave a half-imported database
+        let mut batch = self.batch();
+        restore(&mut batch, self.user_cf(), &backup.users)?;
+        restore(&mut batch, self.session_cf(), &backup.sessions)?;
+        restore(&mut batch, self.settings_cf(), &backup.settings)?;
+        restore(&mut batch, self.hashrate_cf(), &backup.hashrate)?;
+        restore(&mut batch, self.auth_failure_cf(), &backup.auth_failures)?;
+        restore(&mut batch, self.notification_cf(), &backup.notifications)?;
+        restore(&mut batch, self.room_commands_cf(), &backup.room_commands)?;
+        restore(
+            &mut batch,
+            self.rewards_history_cf(),
+            &backup.rewards_history,
+        )?;
+        restore(&mut batch, self.welcome_cf(), &backup.welcomed_rooms)?;
+        restore(&mut batch, self.dm_room_cf(), &backup.dm_rooms)?;
+        batch.commit()?;
+
+        Ok(())
+    }
+
+    fn dump<T: DeserializeOwned>(&self, cf: Arc<BoundColumnFamily>) -> BTreeMap<String, T> {
+        let mut records: BTreeMap<String, T> = BTreeMap::new();
+
+        for (key, _) in self.db.db.iterator_cf(&cf, IteratorMode::Start) {
+            let key: String = String::from_utf8_lossy(&key).to_string();
+            match self.db.get_deserialized(cf.clone(), &key) {
+                Ok(value) => {
+                    records.insert(key, value);
+                }
+                Err(error) => log::error!("Impossible to export {}: {:?}", key, error),
+            }
+        }
+
+        records
+    }
+
+    fn count_existing<T>(
+        &self,
+        cf: Arc<BoundColumnFamily>,
+        records: &BTreeMap<String, T>,
+    ) -> Result<usize, Error> {
+        let mut count: usize = 0;
+
+        for key in records.keys() {
+            let value = self
+                .db
+                .db
+                .get_pinned_cf(&cf, key)
+                .map_err(bpns_rocksdb::Error::from)?;
+
+            if value.is_some() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+fn restore<T: Serialize>(
+    batch: &mut Batch,
+    cf: Arc<BoundColumnFamily>,
+    records: &BTreeMap<String, T>,
+) -> Result<(), Error> {
+    for (key, value) in records.iter() {
+        batch.put(cf.clone(), key, value)?;
+    }
+
+    Ok(())
+}
+
+impl From<Error> for ImportError {
+    fn from(err: Error) -> Self {
+        ImportError::Db(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const USER_ID: &str = "@alice:example.com";
+
+    #[test]
+    fn test_export_import() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        store.add_hashrate_sample(USER_ID, 100.0).unwrap();
+        store
+            .set_settings(USER_ID, &UserSettings::default())
+            .unwrap();
+        store
+            .set_room_command("!room:example.com", "subscribe", false)
+            .unwrap();
+        store
+            .upsert_reward(USER_ID, NaiveDate::from_ymd(2022, 4, 15), 0.0078125)
+            .unwrap();
+
+        let backup = store.export();
+        assert_eq!(
+            backup.summary(),
+            vec![
+                ("users", 1),
+                ("sessions", 0),
+                ("settings", 1),
+                ("hashrate", 1),
+                ("auth_failures", 0),
+                ("notifications", 0),
+                ("room_commands", 1),
+                ("rewards_history", 1),
+                ("welcomed_rooms", 0),
+                ("dm_rooms", 0)
+            ]
+        );
+
+        // Round trip through JSON
+        let json: String = serde_json::to_string(&backup).unwrap();
+        let backup: Backup = serde_json::from_str(&json).unwrap();
+
+        let dir2 = TempDir::new().unwrap();
+        let store2 = DBStore::open(dir2.path(), None).unwrap();
+        store2.import(&backup, false).unwrap();
+        assert_eq!(store2.get_user(USER_ID).unwrap().token.expose(), "token");
+        assert_eq!(store2.get_hashrate_samples(USER_ID).len(), 1);
+        assert_eq!(
+            store2.last_reward_date(USER_ID).unwrap().to_string(),
+            "2022-04-15"
+        );
+        assert_eq!(
+            store2
+                .get_room_commands("!room:example.com")
+                .get("subscribe"),
+            Some(&false)
+        );
+
+        // Existing keys
+        assert!(matches!(
+            store2.import(&backup, false),
+            Err(ImportError::ExistingKeys(5))
+        ));
+        assert!(store2.import(&backup, true).is_ok());
+    }
+
+    #[test]
+    fn test_import_mismatch() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        let mut backup = store.export();
+        backup.token_encryption = true;
+        assert!(matches!(
+            store.import(&backup, false),
+            Err(ImportError::EncryptionMismatch)
+        ));
+
+        backup.schema_version = SCHEMA_VERSION + 1;
+        assert!(matches!(
+            store.import(&backup, false),
+            Err(ImportError::SchemaMismatch(_))
+        ));
+    }
+}
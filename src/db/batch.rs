@@ -0,0 +1,125 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Atomic multi-record writes
+//!
+//! Puts and deletes are collected in a RocksDB write batch and applied all
+//! together on [`Batch::commit`]. Dropping the batch discards them.
+
+use std::sync::Arc;
+
+use bpns_rocksdb::{BoundColumnFamily, WriteBatch};
+use serde::Serialize;
+
+use super::{DBStore, Error};
+
+pub struct Batch<'a> {
+    store: &'a DBStore,
+    batch: WriteBatch,
+}
+
+impl DBStore {
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            store: self,
+            batch: WriteBatch::default(),
+        }
+    }
+}
+
+impl<'a> Batch<'a> {
+    /// Values are encoded like `Store::put_serialized`, so they can be read with `get_deserialized`
+    pub fn put<V: Serialize>(
+        &mut self,
+        cf: Arc<BoundColumnFamily>,
+        key: &str,
+        value: &V,
+    ) -> Result<(), Error> {
+        let value: Vec<u8> = bincode::serialize(value)?;
+        self.batch.put_cf(&cf, key, value);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, cf: Arc<BoundColumnFamily>, key: &str) {
+        self.batch.delete_cf(&cf, key);
+    }
+
+    pub fn commit(self) -> Result<(), Error> {
+        self.store
+            .db
+            .db
+            .write(self.batch)
+            .map_err(bpns_rocksdb::Error::from)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::super::{User, UserSettings};
+    use super::*;
+    use crate::secret::Secret;
+
+    const USER_ID: &str = "@alice:example.com";
+
+    #[test]
+    fn test_commit() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+        store.increment_auth_failures(USER_ID).unwrap();
+
+        let user = User {
+            room_id: String::from("!room:example.com"),
+            token: Secret::new("token"),
+            created_at: 1,
+        };
+
+        let mut batch = store.batch();
+        batch.put(store.user_cf(), USER_ID, &user).unwrap();
+        batch
+            .put(store.settings_cf(), USER_ID, &UserSettings::default())
+            .unwrap();
+        batch.delete(store.auth_failure_cf(), USER_ID);
+
+        // Nothing visible before the commit
+        assert!(!store.user_exist(USER_ID).unwrap());
+        assert_eq!(store.get_auth_failures(USER_ID), 1);
+
+        batch.commit().unwrap();
+
+        assert_eq!(store.get_user(USER_ID).unwrap().token.expose(), "token");
+        assert_eq!(store.get_settings(USER_ID), UserSettings::default());
+        assert_eq!(store.get_auth_failures(USER_ID), 0);
+    }
+
+    #[test]
+    fn test_discard() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        let batch = store
+            .subscribe_batch(USER_ID, "!room:example.com", "token")
+            .unwrap();
+
+        // Failure before the commit
+        drop(batch);
+
+        assert!(!store.user_exist(USER_ID).unwrap());
+        assert!(!store
+            .exist::<UserSettings>(store.settings_cf(), USER_ID)
+            .unwrap());
+        assert_eq!(store.get_subscriptions_count(), 0);
+
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        store
+            .create_user(USER_ID, "!room:example.com", "token2")
+            .unwrap();
+        assert_eq!(store.get_user(USER_ID).unwrap().token.expose(), "token2");
+        assert_eq!(store.get_settings(USER_ID), UserSettings::default());
+        assert_eq!(store.get_subscriptions_count(), 1);
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Encryption of the BraiinsPool tokens at rest
+//!
+//! Tokens are encrypted with XChaCha20-Poly1305 using a random nonce for each
+//! record and stored as `enc:<base64(nonce || ciphertext)>`.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+const PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug)]
+pub enum Error {
+    Encrypt,
+    Decrypt,
+    NotEncrypted,
+    Base64(base64::DecodeError),
+}
+
+#[derive(Clone)]
+pub struct TokenCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl TokenCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    pub fn encrypt(&self, token: &str) -> Result<String, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext: Vec<u8> = self
+            .cipher
+            .encrypt(&nonce, token.as_bytes())
+            .map_err(|_| Error::Encrypt)?;
+
+        let mut data: Vec<u8> = nonce.to_vec();
+        data.extend(ciphertext);
+
+        Ok(format!("{}{}", PREFIX, base64::encode(data)))
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String, Error> {
+        let data: &str = value.strip_prefix(PREFIX).ok_or(Error::NotEncrypted)?;
+        let data: Vec<u8> = base64::decode(data)?;
+
+        if data.len() < NONCE_LEN {
+            return Err(Error::Decrypt);
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext: Vec<u8> = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::Decrypt)?;
+
+        String::from_utf8(plaintext).map_err(|_| Error::Decrypt)
+    }
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(err: base64::DecodeError) -> Self {
+        Error::Base64(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cipher = TokenCipher::new(&[7; 32]);
+
+        let encrypted: String = cipher.encrypt("token").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("token"));
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "token");
+
+        // Random nonce for each record
+        assert_ne!(cipher.encrypt("token").unwrap(), encrypted);
+    }
+
+    #[test]
+    fn test_decrypt_errors() {
+        let cipher = TokenCipher::new(&[7; 32]);
+        let encrypted: String = cipher.encrypt("token").unwrap();
+
+        let wrong_key = TokenCipher::new(&[8; 32]);
+        assert!(matches!(wrong_key.decrypt(&encrypted), Err(Error::Decrypt)));
+        assert!(matches!(cipher.decrypt("token"), Err(Error::NotEncrypted)));
+        assert!(matches!(cipher.decrypt("enc:AAAA"), Err(Error::Decrypt)));
+    }
+}
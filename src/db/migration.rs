@@ -0,0 +1,218 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Schema migrations
+//!
+//! `MIGRATIONS[n]` upgrades the records from schema version `n` to `n + 1`.
+//! Migrations must be idempotent: if the bot stops before the new version is
+//! saved, the same migration will run again on the next start.
+
+use std::collections::HashMap;
+
+use bpns_rocksdb::IteratorMode;
+
+use super::{DBStore, Error, OpenError, User};
+use crate::secret::Secret;
+
+type Migration = fn(&DBStore) -> Result<(), Error>;
+
+const MIGRATIONS: &[Migration] = &[v0_to_v1, v1_to_v2];
+
+/// Current schema version
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub(super) fn run(store: &DBStore) -> Result<(), OpenError> {
+    let version: u32 = store.get_schema_version();
+
+    if version > SCHEMA_VERSION {
+        return Err(OpenError::UnsupportedSchemaVersion(version));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to: u32 = from as u32 + 1;
+
+        log::info!("Migrating database from v{} to v{}", from, to);
+
+        migration(store).map_err(|error| OpenError::Migration(to, error))?;
+        store
+            .set_schema_version(to)
+            .map_err(|error| OpenError::Migration(to, error))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Serialize)]
+struct UserV0 {
+    room_id: String,
+    token: String,
+}
+
+/// Add `created_at` to users
+fn v0_to_v1(store: &DBStore) -> Result<(), Error> {
+    let now: i64 = chrono::Utc::now().timestamp();
+    let keys: Vec<Box<[u8]>> = store
+        .db
+        .db
+        .iterator_cf(&store.user_cf(), IteratorMode::Start)
+        .map(|(key, _)| key)
+        .collect();
+
+    for key in keys.into_iter() {
+        let user_id: String = String::from_utf8_lossy(&key).to_string();
+
+        // Migrated by a previous run: a v0 record is too short to be read as `User`
+        if store
+            .db
+            .get_deserialized::<&str, User>(store.user_cf(), &user_id)
+            .is_ok()
+        {
+            continue;
+        }
+
+        let old: UserV0 = store.db.get_deserialized(store.user_cf(), &user_id)?;
+
+        let user = User {
+            room_id: old.room_id,
+            token: Secret::new(old.token),
+            created_at: now,
+        };
+
+        store.db.put_serialized(store.user_cf(), &user_id, &user)?;
+    }
+
+    Ok(())
+}
+
+/// Auth failures are now keyed by user id instead of token: move the counters of
+/// the subscribed tokens, drop the others
+fn v1_to_v2(store: &DBStore) -> Result<(), Error> {
+    // Tokens not encrypted yet: encryption is enabled after the migrations
+    let user_ids: HashMap<String, String> = store
+        .iter_users()
+        .map(|(user_id, user)| (user.token.expose().clone(), user_id))
+        .collect();
+
+    let keys: Vec<Box<[u8]>> = store
+        .db
+        .db
+        .iterator_cf(&store.auth_failure_cf(), IteratorMode::Start)
+        .map(|(key, _)| key)
+        .collect();
+
+    let mut batch = store.batch();
+
+    for key in keys.into_iter() {
+        let key: String = String::from_utf8_lossy(&key).to_string();
+
+        // Moved by a previous run
+        if store.user_exist(&key)? {
+            continue;
+        }
+
+        if let Some(user_id) = user_ids.get(&key) {
+            batch.put(
+                store.auth_failure_cf(),
+                user_id,
+                &store.get_auth_failures(&key),
+            )?;
+        }
+        batch.delete(store.auth_failure_cf(), &key);
+    }
+
+    batch.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_new_database() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+        assert_eq!(store.get_schema_version(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_v0_to_v1() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        // Write records as v0
+        let old = UserV0 {
+            room_id: String::from("!room:example.com"),
+            token: String::from("token"),
+        };
+        store
+            .db
+            .put_serialized(store.user_cf(), "@alice:example.com", &old)
+            .unwrap();
+        store.set_schema_version(0).unwrap();
+
+        run(&store).unwrap();
+
+        assert_eq!(store.get_schema_version(), SCHEMA_VERSION);
+
+        let user = store.get_user("@alice:example.com").unwrap();
+        assert_eq!(user.room_id, "!room:example.com");
+        assert_eq!(user.token.expose(), "token");
+        assert!(user.created_at > 0);
+
+        // Running it again changes nothing
+        let user = User {
+            created_at: 1,
+            ..user
+        };
+        store
+            .db
+            .put_serialized(store.user_cf(), "@alice:example.com", &user)
+            .unwrap();
+        v0_to_v1(&store).unwrap();
+        assert_eq!(store.get_user("@alice:example.com").unwrap().created_at, 1);
+    }
+
+    #[test]
+    fn test_v1_to_v2() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        store
+            .create_user("@alice:example.com", "!room:example.com", "token")
+            .unwrap();
+        store
+            .db
+            .put_serialized(store.auth_failure_cf(), "token", &2u32)
+            .unwrap();
+        store
+            .db
+            .put_serialized(store.auth_failure_cf(), "unsubscribed", &3u32)
+            .unwrap();
+        store.set_schema_version(1).unwrap();
+
+        run(&store).unwrap();
+
+        assert_eq!(store.get_schema_version(), 2);
+        assert_eq!(store.get_auth_failures("@alice:example.com"), 2);
+        assert_eq!(store.get_auth_failures("token"), 0);
+        assert_eq!(store.get_auth_failures("unsubscribed"), 0);
+
+        // Running it again changes nothing
+        v1_to_v2(&store).unwrap();
+        assert_eq!(store.get_auth_failures("@alice:example.com"), 2);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+        store.set_schema_version(SCHEMA_VERSION + 1).unwrap();
+
+        assert!(matches!(
+            run(&store),
+            Err(OpenError::UnsupportedSchemaVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+}
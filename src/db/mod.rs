@@ -0,0 +1,1326 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bpns_rocksdb::{
+    BoundColumnFamily, DBWithThreadMode, Direction, IteratorMode, MultiThreaded, Options, Store,
+};
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use serde::de::DeserializeOwned;
+
+use crate::i18n::Lang;
+use crate::secret::Secret;
+use crate::util::Unit;
+
+mod audit;
+mod backup;
+mod batch;
+mod crypto;
+mod migration;
+
+pub use self::audit::AuditEntry;
+pub use self::backup::{Backup, ImportError};
+pub use self::batch::Batch;
+use self::crypto::TokenCipher;
+pub use self::migration::SCHEMA_VERSION;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub access_token: Secret<String>,
+    pub device_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct User {
+    pub room_id: String,
+    pub token: Secret<String>,
+    /// Subscription timestamp (time of the migration for users created before v1)
+    pub created_at: i64,
+}
+
+/// Last time an alert fired, with the value that triggered it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub fired_at: i64,
+    pub value: i64,
+}
+
+/// Commands enabled (`true`) or disabled (`false`) in a room, overriding `enabled_commands`.
+/// Keys are the command names without `!`.
+pub type RoomCommands = BTreeMap<String, bool>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HashrateSample {
+    pub timestamp: i64,
+    pub hash_rate: f64,
+}
+
+/// Per-user preferences. New fields must have a default, so that settings
+/// saved by older versions can still be deserialized.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserSettings {
+    pub notifications: bool,
+    pub lang: Lang,
+    pub unit: Unit,
+    /// Alert when the last share of a worker is older than this, `0` = disabled
+    pub stale_alert_mins: u32,
+    /// Notify when a worker alerted as stale submits shares again
+    pub recovery_alerts: bool,
+    /// Background alerts are suppressed until this timestamp
+    pub muted_until: Option<i64>,
+    /// Timezone of the displayed dates, `None` = the one of the config
+    pub timezone: Option<Tz>,
+}
+
+impl UserSettings {
+    pub fn is_muted(&self, now: i64) -> bool {
+        matches!(self.muted_until, Some(until) if until > now)
+    }
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self {
+            notifications: true,
+            lang: Lang::default(),
+            unit: Unit::default(),
+            stale_alert_mins: 0,
+            recovery_alerts: true,
+            muted_until: None,
+            timezone: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DBStore {
+    pub db: Store,
+    cipher: Option<TokenCipher>,
+    /// Held while the audit sequence is read and incremented, commands run concurrently
+    audit_lock: Arc<Mutex<()>>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Store(bpns_rocksdb::Error),
+    Crypto(crypto::Error),
+    Serialize(bincode::Error),
+}
+
+#[derive(Debug)]
+pub enum OpenError {
+    Store(Error),
+    /// Database created by a newer version of the bot
+    UnsupportedSchemaVersion(u32),
+    /// Migration to the contained version failed
+    Migration(u32, Error),
+    /// Read-only open of a database with an older schema version, it can't be migrated
+    MigrationRequired(u32),
+    /// Tokens are encrypted but no key was provided
+    EncryptionKeyRequired,
+    /// Tokens can't be decrypted with the provided key
+    InvalidEncryptionKey,
+}
+
+const USER_CF: &str = "user";
+const SESSION_CF: &str = "session";
+const HASHRATE_CF: &str = "hashrate";
+const META_CF: &str = "meta";
+const AUTH_FAILURE_CF: &str = "auth_failure";
+const SETTINGS_CF: &str = "settings";
+const NOTIFICATION_CF: &str = "notification";
+const AUDIT_CF: &str = "audit";
+const ROOM_COMMANDS_CF: &str = "room_commands";
+const REWARDS_HISTORY_CF: &str = "rewards_history";
+const WELCOME_CF: &str = "welcome";
+const DM_ROOM_CF: &str = "dm_room";
+
+const COLUMN_FAMILIES: &[&str] = &[
+    USER_CF,
+    SESSION_CF,
+    HASHRATE_CF,
+    META_CF,
+    AUTH_FAILURE_CF,
+    SETTINGS_CF,
+    NOTIFICATION_CF,
+    AUDIT_CF,
+    ROOM_COMMANDS_CF,
+    REWARDS_HISTORY_CF,
+    WELCOME_CF,
+    DM_ROOM_CF,
+];
+
+const SYNC_TOKEN_KEY: &str = "sync_token";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const TOKEN_ENCRYPTION_KEY: &str = "token_encryption";
+const SUBSCRIPTIONS_KEY: &str = "subscriptions";
+const DEVICE_KEY_KEY: &str = "device_ed25519_key";
+const CRYPTO_STORE_ENCRYPTED_KEY: &str = "crypto_store_encrypted";
+
+/// Max number of hashrate samples stored for each user
+const HASHRATE_SAMPLES_LIMIT: usize = 288;
+
+impl DBStore {
+    /// Open the database, running pending migrations. If `encryption_key` is set,
+    /// plaintext tokens are encrypted.
+    pub fn open(path: &Path, encryption_key: Option<&[u8; 32]>) -> Result<Self, OpenError> {
+        let mut store = Self {
+            db: Store::open(path, COLUMN_FAMILIES)
+                .map_err(|error| OpenError::Store(error.into()))?,
+            cipher: None,
+            audit_lock: Arc::new(Mutex::new(())),
+        };
+
+        migration::run(&store)?;
+
+        let encrypted: bool = store.is_token_encryption_enabled();
+
+        match encryption_key {
+            Some(key) => {
+                store.cipher = Some(TokenCipher::new(key));
+
+                if encrypted {
+                    store.check_encryption_key()?;
+                } else {
+                    store.encrypt_tokens().map_err(OpenError::Store)?;
+                }
+            }
+            None if encrypted => return Err(OpenError::EncryptionKeyRequired),
+            None => (),
+        }
+
+        Ok(store)
+    }
+
+    /// Open the database read-only, also while the bot is running: RocksDB doesn't
+    /// lock it, and the records are the ones written when it's opened. Migrations
+    /// and token encryption are skipped, nothing is ever written.
+    pub fn open_read_only(
+        path: &Path,
+        encryption_key: Option<&[u8; 32]>,
+    ) -> Result<Self, OpenError> {
+        let db = DBWithThreadMode::<MultiThreaded>::open_cf_for_read_only(
+            &Options::default(),
+            path,
+            COLUMN_FAMILIES,
+            false,
+        )
+        .map_err(|error| OpenError::Store(Error::Store(error.into())))?;
+
+        let mut store = Self {
+            db: Store { db: Arc::new(db) },
+            cipher: None,
+            audit_lock: Arc::new(Mutex::new(())),
+        };
+
+        let version: u32 = store.get_schema_version();
+        if version > SCHEMA_VERSION {
+            return Err(OpenError::UnsupportedSchemaVersion(version));
+        }
+        if version < SCHEMA_VERSION {
+            return Err(OpenError::MigrationRequired(version));
+        }
+
+        if store.is_token_encryption_enabled() {
+            match encryption_key {
+                Some(key) => {
+                    store.cipher = Some(TokenCipher::new(key));
+                    store.check_encryption_key()?;
+                }
+                None => return Err(OpenError::EncryptionKeyRequired),
+            }
+        }
+
+        Ok(store)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.db
+            .db
+            .property_value("rocksdb.estimate-num-keys")
+            .is_ok()
+    }
+
+    fn user_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(USER_CF)
+    }
+
+    fn session_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(SESSION_CF)
+    }
+
+    fn hashrate_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(HASHRATE_CF)
+    }
+
+    fn meta_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(META_CF)
+    }
+
+    fn auth_failure_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(AUTH_FAILURE_CF)
+    }
+
+    fn settings_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(SETTINGS_CF)
+    }
+
+    fn notification_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(NOTIFICATION_CF)
+    }
+
+    fn audit_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(AUDIT_CF)
+    }
+
+    fn room_commands_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(ROOM_COMMANDS_CF)
+    }
+
+    fn rewards_history_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(REWARDS_HISTORY_CF)
+    }
+
+    fn welcome_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(WELCOME_CF)
+    }
+
+    fn dm_room_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(DM_ROOM_CF)
+    }
+
+    fn is_token_encryption_enabled(&self) -> bool {
+        self.db
+            .get_deserialized(self.meta_cf(), TOKEN_ENCRYPTION_KEY)
+            .unwrap_or(false)
+    }
+
+    /// Try to decrypt the first token to verify the key
+    fn check_encryption_key(&self) -> Result<(), OpenError> {
+        let mut iter = self.db.db.iterator_cf(&self.user_cf(), IteratorMode::Start);
+
+        if let Some((key, _)) = iter.next() {
+            let user_id: String = String::from_utf8_lossy(&key).to_string();
+            match self.get_user(&user_id) {
+                Ok(_) => (),
+                Err(Error::Crypto(_)) => return Err(OpenError::InvalidEncryptionKey),
+                Err(error) => return Err(OpenError::Store(error)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt all plaintext tokens and mark the database as encrypted
+    fn encrypt_tokens(&self) -> Result<(), Error> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(()),
+        };
+
+        log::info!("Encrypting tokens");
+
+        for (key, _) in self.db.db.iterator_cf(&self.user_cf(), IteratorMode::Start) {
+            let user_id: String = String::from_utf8_lossy(&key).to_string();
+            let mut user: User = self.db.get_deserialized(self.user_cf(), &user_id)?;
+
+            if !crypto::is_encrypted(user.token.expose()) {
+                user.token = Secret::new(cipher.encrypt(user.token.expose())?);
+                self.db.put_serialized(self.user_cf(), &user_id, &user)?;
+            }
+        }
+
+        self.db
+            .put_serialized(self.meta_cf(), TOKEN_ENCRYPTION_KEY, &true)?;
+
+        Ok(())
+    }
+
+    /// Check if a key exists: `Ok(false)` if absent, `Err` if the value can't be
+    /// read or deserialized
+    fn exist<T: DeserializeOwned>(
+        &self,
+        cf: Arc<BoundColumnFamily>,
+        key: &str,
+    ) -> Result<bool, Error> {
+        let value = self
+            .db
+            .db
+            .get_pinned_cf(&cf, key)
+            .map_err(bpns_rocksdb::Error::from)?;
+
+        match value {
+            Some(_) => {
+                self.db.get_deserialized::<&str, T>(cf, key)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Save the session with the ed25519 key of its device, to detect a crypto
+    /// store that doesn't match the session anymore
+    pub fn create_session(
+        &self,
+        user_id: &str,
+        access_token: &str,
+        device_id: &str,
+        device_key: Option<&str>,
+    ) -> Result<(), Error> {
+        let value = Session {
+            access_token: Secret::masked(access_token),
+            device_id: device_id.into(),
+        };
+
+        let mut batch = self.batch();
+        batch.put(self.session_cf(), user_id, &value)?;
+        match device_key {
+            Some(device_key) => batch.put(self.meta_cf(), DEVICE_KEY_KEY, &device_key)?,
+            None => batch.delete(self.meta_cf(), DEVICE_KEY_KEY),
+        }
+        batch.commit()
+    }
+
+    pub fn session_exist(&self, user_id: &str) -> Result<bool, Error> {
+        self.exist::<Session>(self.session_cf(), user_id)
+    }
+
+    pub fn get_session(&self, user_id: &str) -> Result<Session, Error> {
+        Ok(self.db.get_deserialized(self.session_cf(), user_id)?)
+    }
+
+    pub fn set_sync_token(&self, token: &str) -> Result<(), Error> {
+        Ok(self
+            .db
+            .put_serialized(self.meta_cf(), SYNC_TOKEN_KEY, &token)?)
+    }
+
+    pub fn get_sync_token(&self) -> Option<String> {
+        self.db
+            .get_deserialized(self.meta_cf(), SYNC_TOKEN_KEY)
+            .ok()
+    }
+
+    /// Get schema version (`0` for databases created before versioning)
+    pub fn get_schema_version(&self) -> u32 {
+        self.db
+            .get_deserialized(self.meta_cf(), SCHEMA_VERSION_KEY)
+            .unwrap_or(0)
+    }
+
+    /// Trivial read through a column family, to check that the database is readable
+    pub fn check_read(&self) -> Result<(), Error> {
+        self.db
+            .db
+            .get_pinned_cf(&self.meta_cf(), SCHEMA_VERSION_KEY)
+            .map_err(bpns_rocksdb::Error::from)?;
+        Ok(())
+    }
+
+    fn set_schema_version(&self, version: u32) -> Result<(), Error> {
+        Ok(self
+            .db
+            .put_serialized(self.meta_cf(), SCHEMA_VERSION_KEY, &version)?)
+    }
+
+    pub fn delete_session(&self, user_id: &str) -> Result<(), Error> {
+        let mut batch = self.batch();
+        batch.delete(self.session_cf(), user_id);
+        batch.delete(self.meta_cf(), DEVICE_KEY_KEY);
+        batch.commit()
+    }
+
+    /// Ed25519 key of the device of the saved session
+    pub fn get_device_key(&self) -> Option<String> {
+        self.db
+            .get_deserialized(self.meta_cf(), DEVICE_KEY_KEY)
+            .ok()
+    }
+
+    /// If the Matrix crypto store was created with a passphrase, `None` if not tracked yet
+    pub fn get_crypto_store_encrypted(&self) -> Option<bool> {
+        self.db
+            .get_deserialized(self.meta_cf(), CRYPTO_STORE_ENCRYPTED_KEY)
+            .ok()
+    }
+
+    pub fn set_crypto_store_encrypted(&self, encrypted: bool) -> Result<(), Error> {
+        Ok(self
+            .db
+            .put_serialized(self.meta_cf(), CRYPTO_STORE_ENCRYPTED_KEY, &encrypted)?)
+    }
+
+    pub fn create_user(&self, user_id: &str, room_id: &str, token: &str) -> Result<(), Error> {
+        self.subscribe_batch(user_id, room_id, token)?.commit()
+    }
+
+    /// Writes of a subscription: user record, default settings, subscriptions
+    /// counter and auth failures reset
+    fn subscribe_batch(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        token: &str,
+    ) -> Result<Batch<'_>, Error> {
+        let token: Secret<String> = Secret::new(match &self.cipher {
+            Some(cipher) => cipher.encrypt(token)?,
+            None => token.into(),
+        });
+
+        let value: User = User {
+            room_id: room_id.into(),
+            token,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+
+        let mut batch = self.batch();
+
+        if !self.user_exist(user_id)? {
+            batch.put(
+                self.meta_cf(),
+                SUBSCRIPTIONS_KEY,
+                &(self.get_subscriptions_count() + 1),
+            )?;
+        }
+
+        if !self.exist::<UserSettings>(self.settings_cf(), user_id)? {
+            batch.put(self.settings_cf(), user_id, &UserSettings::default())?;
+        }
+
+        batch.put(self.user_cf(), user_id, &value)?;
+
+        // New token, start counting again
+        batch.delete(self.auth_failure_cf(), user_id);
+
+        Ok(batch)
+    }
+
+    /// Number of subscriptions created since the counter was introduced
+    pub fn get_subscriptions_count(&self) -> u64 {
+        self.db
+            .get_deserialized(self.meta_cf(), SUBSCRIPTIONS_KEY)
+            .unwrap_or(0)
+    }
+
+    pub fn user_exist(&self, user_id: &str) -> Result<bool, Error> {
+        self.exist::<User>(self.user_cf(), user_id)
+    }
+
+    pub fn user_with_room_exist(&self, user_id: &str, room_id: &str) -> Result<bool, Error> {
+        if self.user_exist(user_id)? {
+            let user: User = self.db.get_deserialized(self.user_cf(), user_id)?;
+            return Ok(user.room_id.as_str() == room_id);
+        }
+
+        Ok(false)
+    }
+
+    /// Delete the user and all its records at once
+    pub fn delete_user(&self, user_id: &str) -> Result<(), Error> {
+        let mut batch = self.batch();
+        batch.delete(self.user_cf(), user_id);
+        batch.delete(self.hashrate_cf(), user_id);
+        batch.delete(self.auth_failure_cf(), user_id);
+        self.delete_user_records(&mut batch, self.notification_cf(), user_id);
+        self.delete_user_records(&mut batch, self.rewards_history_cf(), user_id);
+        batch.commit()
+    }
+
+    /// Delete the users subscribed in a room, returning their ids
+    pub fn delete_room_users(&self, room_id: &str) -> Result<Vec<String>, Error> {
+        let user_ids: Vec<String> = self
+            .iter_users()
+            .filter(|(_, user)| user.room_id == room_id)
+            .map(|(user_id, _)| user_id)
+            .collect();
+
+        for user_id in user_ids.iter() {
+            self.delete_user(user_id)?;
+        }
+
+        Ok(user_ids)
+    }
+
+    /// Replace the token of the user, keeping room and settings
+    pub fn update_user_token(&self, user_id: &str, token: &str) -> Result<(), Error> {
+        let mut user: User = self.db.get_deserialized(self.user_cf(), user_id)?;
+        user.token = Secret::new(match &self.cipher {
+            Some(cipher) => cipher.encrypt(token)?,
+            None => token.into(),
+        });
+
+        let mut batch = self.batch();
+        batch.put(self.user_cf(), user_id, &user)?;
+        // New token, start counting again
+        batch.delete(self.auth_failure_cf(), user_id);
+        batch.commit()
+    }
+
+    /// Move the subscription of the user to another room
+    pub fn update_user_room(&self, user_id: &str, new_room_id: &str) -> Result<(), Error> {
+        // Raw record, the token is left as stored
+        let mut user: User = self.db.get_deserialized(self.user_cf(), user_id)?;
+        user.room_id = new_room_id.into();
+        Ok(self.db.put_serialized(self.user_cf(), user_id, &user)?)
+    }
+
+    /// Get user, with the token decrypted
+    pub fn get_user(&self, user_id: &str) -> Result<User, Error> {
+        let mut user: User = self.db.get_deserialized(self.user_cf(), user_id)?;
+
+        if let Some(cipher) = &self.cipher {
+            user.token = Secret::new(cipher.decrypt(user.token.expose())?);
+        }
+
+        Ok(user)
+    }
+
+    /// Iterate over all the users. Records that can't be read are logged and skipped.
+    pub fn iter_users(&self) -> impl Iterator<Item = (String, User)> + '_ {
+        self.db
+            .db
+            .iterator_cf(&self.user_cf(), IteratorMode::Start)
+            .filter_map(move |(key, _)| {
+                let user_id: String = String::from_utf8_lossy(&key).to_string();
+                match self.get_user(&user_id) {
+                    Ok(user) => Some((user_id, user)),
+                    Err(error) => {
+                        log::error!("Impossible to get user {}: {:?}", user_id, error);
+                        None
+                    }
+                }
+            })
+    }
+
+    /// Count the user records, including the ones that can't be read
+    pub fn count_users(&self) -> usize {
+        self.db
+            .db
+            .iterator_cf(&self.user_cf(), IteratorMode::Start)
+            .count()
+    }
+
+    pub fn add_hashrate_sample(&self, user_id: &str, hash_rate: f64) -> Result<(), Error> {
+        let mut samples: Vec<HashrateSample> = self.get_hashrate_samples(user_id);
+
+        samples.push(HashrateSample {
+            timestamp: chrono::Utc::now().timestamp(),
+            hash_rate,
+        });
+
+        if samples.len() > HASHRATE_SAMPLES_LIMIT {
+            samples.drain(0..(samples.len() - HASHRATE_SAMPLES_LIMIT));
+        }
+
+        Ok(self
+            .db
+            .put_serialized(self.hashrate_cf(), user_id, &samples)?)
+    }
+
+    pub fn get_hashrate_samples(&self, user_id: &str) -> Vec<HashrateSample> {
+        self.db
+            .get_deserialized(self.hashrate_cf(), user_id)
+            .unwrap_or_default()
+    }
+
+    /// Increment the consecutive auth failures of the user token and return the new count
+    pub fn increment_auth_failures(&self, user_id: &str) -> Result<u32, Error> {
+        let count: u32 = self.get_auth_failures(user_id) + 1;
+        self.db
+            .put_serialized(self.auth_failure_cf(), user_id, &count)?;
+        Ok(count)
+    }
+
+    pub fn get_auth_failures(&self, user_id: &str) -> u32 {
+        self.db
+            .get_deserialized(self.auth_failure_cf(), user_id)
+            .unwrap_or(0)
+    }
+
+    pub fn reset_auth_failures(&self, user_id: &str) -> Result<(), Error> {
+        Ok(self.db.delete(self.auth_failure_cf(), user_id)?)
+    }
+
+    /// Get the last firing of the alert `key` of the user
+    pub fn get_notification(&self, user_id: &str, key: &str) -> Option<Notification> {
+        self.db
+            .get_deserialized(self.notification_cf(), notification_key(user_id, key))
+            .ok()
+    }
+
+    /// Record that the alert `key` fired for `value`. Call it only once the alert is delivered.
+    pub fn set_notification(&self, user_id: &str, key: &str, value: i64) -> Result<(), Error> {
+        let notification = Notification {
+            fired_at: chrono::Utc::now().timestamp(),
+            value,
+        };
+
+        Ok(self.db.put_serialized(
+            self.notification_cf(),
+            notification_key(user_id, key),
+            &notification,
+        )?)
+    }
+
+    /// Forget the alert `key`, so it fires again on the next occurrence
+    pub fn delete_notification(&self, user_id: &str, key: &str) -> Result<(), Error> {
+        Ok(self
+            .db
+            .delete(self.notification_cf(), notification_key(user_id, key))?)
+    }
+
+    /// Alerts of the user that fired and didn't recover yet, sorted by key
+    pub fn get_notifications(&self, user_id: &str) -> Vec<(String, Notification)> {
+        let prefix: String = user_prefix(user_id);
+        self.iter_prefix(self.notification_cf(), prefix.clone())
+            .filter_map(|(key, value)| {
+                let key: &str = std::str::from_utf8(&key).ok()?;
+                let key: &str = key.strip_prefix(prefix.as_str())?;
+                match bincode::deserialize::<Notification>(&value) {
+                    Ok(notification) => Some((key.to_string(), notification)),
+                    Err(error) => {
+                        log::error!("Impossible to read notification {}: {:?}", key, error);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Add to the batch the deletion of the `user_id|...` records of a column family
+    fn delete_user_records(&self, batch: &mut Batch, cf: Arc<BoundColumnFamily>, user_id: &str) {
+        for (key, _) in self.iter_prefix(cf.clone(), user_prefix(user_id)) {
+            batch.delete(cf.clone(), &String::from_utf8_lossy(&key));
+        }
+    }
+
+    /// Records of a column family whose key starts with `prefix`, in key order.
+    /// Seeks to the first one instead of scanning the column family.
+    fn iter_prefix(
+        &self,
+        cf: Arc<BoundColumnFamily>,
+        prefix: String,
+    ) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
+        self.db
+            .db
+            .iterator_cf(
+                &cf,
+                IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+            )
+            .take_while(move |(key, _)| key.starts_with(prefix.as_bytes()))
+    }
+
+    /// Save the total reward (BTC) of the day, replacing the one already stored
+    pub fn upsert_reward(
+        &self,
+        user_id: &str,
+        day: NaiveDate,
+        total_reward: f64,
+    ) -> Result<(), Error> {
+        Ok(self.db.put_serialized(
+            self.rewards_history_cf(),
+            reward_key(user_id, day),
+            &total_reward,
+        )?)
+    }
+
+    /// Stored rewards of the days in the inclusive range, as `(timestamp, total_reward_btc)`
+    /// sorted by date. The timestamp is the start of the day (UTC).
+    pub fn get_rewards(&self, user_id: &str, from: NaiveDate, to: NaiveDate) -> Vec<(i64, f64)> {
+        self.iter_rewards(user_id, from)
+            .take_while(|(day, _)| *day <= to)
+            .map(|(day, reward)| (day.and_hms(0, 0, 0).timestamp(), reward))
+            .collect()
+    }
+
+    /// Date of the most recent stored reward
+    pub fn last_reward_date(&self, user_id: &str) -> Option<NaiveDate> {
+        // Seek backward from the end of the keys of the user
+        let end: String = format!("{}~", user_prefix(user_id));
+        let (key, _) = self
+            .db
+            .db
+            .iterator_cf(
+                &self.rewards_history_cf(),
+                IteratorMode::From(end.as_bytes(), Direction::Reverse),
+            )
+            .next()?;
+        parse_reward_key(user_id, &key)
+    }
+
+    /// Rewards of the user from the day `from`, sorted by date
+    fn iter_rewards(
+        &self,
+        user_id: &str,
+        from: NaiveDate,
+    ) -> impl Iterator<Item = (NaiveDate, f64)> + '_ {
+        let user_id: String = user_id.to_string();
+        let start: String = reward_key(&user_id, from);
+        self.db
+            .db
+            .iterator_cf(
+                &self.rewards_history_cf(),
+                IteratorMode::From(start.as_bytes(), Direction::Forward),
+            )
+            .map_while(move |(key, value)| {
+                let day: NaiveDate = parse_reward_key(&user_id, &key)?;
+                Some((day, value))
+            })
+            .filter_map(|(day, value)| match bincode::deserialize::<f64>(&value) {
+                Ok(reward) => Some((day, reward)),
+                Err(error) => {
+                    log::error!("Impossible to read reward of {}: {:?}", day, error);
+                    None
+                }
+            })
+    }
+
+    /// Get user settings, or the defaults if never set
+    pub fn get_settings(&self, user_id: &str) -> UserSettings {
+        self.db
+            .get_deserialized(self.settings_cf(), user_id)
+            .unwrap_or_default()
+    }
+
+    pub fn set_settings(&self, user_id: &str, settings: &UserSettings) -> Result<(), Error> {
+        Ok(self
+            .db
+            .put_serialized(self.settings_cf(), user_id, settings)?)
+    }
+
+    /// Command overrides of a room, empty if never set
+    pub fn get_room_commands(&self, room_id: &str) -> RoomCommands {
+        self.db
+            .get_deserialized(self.room_commands_cf(), room_id)
+            .unwrap_or_default()
+    }
+
+    pub fn set_room_command(
+        &self,
+        room_id: &str,
+        command: &str,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        let mut commands: RoomCommands = self.get_room_commands(room_id);
+        commands.insert(command.to_string(), enabled);
+        Ok(self
+            .db
+            .put_serialized(self.room_commands_cf(), room_id, &commands)?)
+    }
+
+    /// Check if the welcome message was already sent in the room
+    pub fn is_room_welcomed(&self, room_id: &str) -> Result<bool, Error> {
+        self.exist::<i64>(self.welcome_cf(), room_id)
+    }
+
+    /// Record that the welcome message was sent in the room
+    pub fn set_room_welcomed(&self, room_id: &str) -> Result<(), Error> {
+        Ok(self
+            .db
+            .put_serialized(self.welcome_cf(), room_id, &chrono::Utc::now().timestamp())?)
+    }
+
+    /// Direct room opened by the bot with the user, if any
+    pub fn get_dm_room(&self, user_id: &str) -> Option<String> {
+        self.db.get_deserialized(self.dm_room_cf(), user_id).ok()
+    }
+
+    pub fn set_dm_room(&self, user_id: &str, room_id: &str) -> Result<(), Error> {
+        Ok(self
+            .db
+            .put_serialized(self.dm_room_cf(), user_id, &room_id)?)
+    }
+}
+
+/// `|` can't appear in Matrix user ids
+fn notification_key(user_id: &str, key: &str) -> String {
+    format!("{}{}", user_prefix(user_id), key)
+}
+
+/// Start of the keys of the records of a user, in the column families keyed by user and record
+fn user_prefix(user_id: &str) -> String {
+    format!("{}|", user_id)
+}
+
+/// `user_id|YYYY-MM-DD`: zero-padded dates, so the keys of a user are sorted by date
+fn reward_key(user_id: &str, day: NaiveDate) -> String {
+    format!("{}{}", user_prefix(user_id), day.format("%Y-%m-%d"))
+}
+
+/// Day of a reward key, `None` if the key is of another user
+fn parse_reward_key(user_id: &str, key: &[u8]) -> Option<NaiveDate> {
+    let day: &str = std::str::from_utf8(key)
+        .ok()?
+        .strip_prefix(user_prefix(user_id).as_str())?;
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+}
+
+impl From<bpns_rocksdb::Error> for Error {
+    fn from(err: bpns_rocksdb::Error) -> Self {
+        Error::Store(err)
+    }
+}
+
+impl From<crypto::Error> for Error {
+    fn from(err: crypto::Error) -> Self {
+        Error::Crypto(err)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Serialize(err)
+    }
+}
+
+impl Drop for DBStore {
+    fn drop(&mut self) {
+        log::trace!("Closing Database");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const USER_ID: &str = "@alice:example.com";
+    const KEY: [u8; 32] = [7; 32];
+
+    fn raw_token(store: &DBStore) -> String {
+        let user: User = store.db.get_deserialized(store.user_cf(), USER_ID).unwrap();
+        user.token.expose().clone()
+    }
+
+    #[test]
+    fn test_encrypt_existing_tokens() {
+        let dir = TempDir::new().unwrap();
+
+        let store = DBStore::open(dir.path(), None).unwrap();
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        assert_eq!(raw_token(&store), "token");
+        drop(store);
+
+        let store = DBStore::open(dir.path(), Some(&KEY)).unwrap();
+        assert!(crypto::is_encrypted(&raw_token(&store)));
+        assert_eq!(store.get_user(USER_ID).unwrap().token.expose(), "token");
+
+        store
+            .create_user("@bob:example.com", "!room:example.com", "token2")
+            .unwrap();
+        assert_eq!(
+            store.get_user("@bob:example.com").unwrap().token.expose(),
+            "token2"
+        );
+    }
+
+    #[test]
+    fn test_encryption_key_errors() {
+        let dir = TempDir::new().unwrap();
+
+        let store = DBStore::open(dir.path(), Some(&KEY)).unwrap();
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        drop(store);
+
+        assert!(matches!(
+            DBStore::open(dir.path(), None),
+            Err(OpenError::EncryptionKeyRequired)
+        ));
+        assert!(matches!(
+            DBStore::open(dir.path(), Some(&[8; 32])),
+            Err(OpenError::InvalidEncryptionKey)
+        ));
+        assert!(DBStore::open(dir.path(), Some(&KEY)).is_ok());
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = TempDir::new().unwrap();
+
+        // Opened while the read-write instance is still open
+        let store = DBStore::open(dir.path(), Some(&KEY)).unwrap();
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+
+        let read_only = DBStore::open_read_only(dir.path(), Some(&KEY)).unwrap();
+        assert_eq!(read_only.get_user(USER_ID).unwrap().token.expose(), "token");
+        assert!(read_only.check_read().is_ok());
+        assert!(matches!(
+            DBStore::open_read_only(dir.path(), None),
+            Err(OpenError::EncryptionKeyRequired)
+        ));
+
+        store.set_schema_version(SCHEMA_VERSION - 1).unwrap();
+        assert!(matches!(
+            DBStore::open_read_only(dir.path(), Some(&KEY)),
+            Err(OpenError::MigrationRequired(_))
+        ));
+
+        let missing = TempDir::new().unwrap();
+        assert!(matches!(
+            DBStore::open_read_only(&missing.path().join("db"), None),
+            Err(OpenError::Store(_))
+        ));
+    }
+
+    #[test]
+    fn test_auth_failures() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert_eq!(store.increment_auth_failures(USER_ID).unwrap(), 1);
+        assert_eq!(store.increment_auth_failures(USER_ID).unwrap(), 2);
+
+        // A new token resets the counter
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        assert_eq!(store.get_auth_failures(USER_ID), 0);
+    }
+
+    #[test]
+    fn test_iter_users() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert_eq!(store.iter_users().count(), 0);
+        assert_eq!(store.count_users(), 0);
+
+        let mut expected: Vec<String> = Vec::new();
+        for i in 0..300 {
+            let user_id: String = format!("@user{}:example.com", i);
+            store
+                .create_user(&user_id, "!room:example.com", &format!("token{}", i))
+                .unwrap();
+            expected.push(user_id);
+        }
+
+        // Corrupt record
+        store
+            .db
+            .db
+            .put_cf(&store.user_cf(), "@corrupt:example.com", [0xff])
+            .unwrap();
+
+        let mut users: Vec<(String, User)> = store.iter_users().collect();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        expected.sort();
+
+        assert_eq!(store.count_users(), 301);
+        assert_eq!(
+            users
+                .iter()
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<String>>(),
+            expected
+        );
+        for (user_id, user) in users.iter() {
+            let i: &str = user_id
+                .trim_start_matches("@user")
+                .trim_end_matches(":example.com");
+            assert_eq!(user.token.expose(), format!("token{}", i));
+        }
+    }
+
+    #[test]
+    fn test_update_user_room() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), Some(&KEY)).unwrap();
+        store
+            .create_user(USER_ID, "!old:example.com", "token")
+            .unwrap();
+
+        store.update_user_room(USER_ID, "!new:example.com").unwrap();
+
+        let user: User = store.get_user(USER_ID).unwrap();
+        assert_eq!(user.room_id, "!new:example.com");
+        assert_eq!(user.token.expose(), "token");
+        assert!(store
+            .update_user_room("@bob:example.com", "!new:example.com")
+            .is_err());
+    }
+
+    #[test]
+    fn test_update_user_token() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), Some(&KEY)).unwrap();
+        store
+            .create_user(USER_ID, "!room:example.com", "old")
+            .unwrap();
+        store.increment_auth_failures(USER_ID).unwrap();
+
+        store.update_user_token(USER_ID, "new").unwrap();
+
+        let user: User = store.get_user(USER_ID).unwrap();
+        assert_eq!(user.token.expose(), "new");
+        assert_eq!(user.room_id, "!room:example.com");
+        assert_eq!(store.get_auth_failures(USER_ID), 0);
+        assert!(store.update_user_token("@bob:example.com", "new").is_err());
+    }
+
+    #[test]
+    fn test_delete_room_users() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+        store
+            .create_user(USER_ID, "!gone:example.com", "token")
+            .unwrap();
+        store
+            .create_user("@bob:example.com", "!gone:example.com", "token")
+            .unwrap();
+        store
+            .create_user("@carol:example.com", "!other:example.com", "token")
+            .unwrap();
+        store.add_hashrate_sample(USER_ID, 100.0).unwrap();
+
+        let mut removed = store.delete_room_users("!gone:example.com").unwrap();
+        removed.sort();
+        assert_eq!(removed, vec!["@alice:example.com", "@bob:example.com"]);
+        assert!(!store.user_exist(USER_ID).unwrap());
+        assert!(store.get_hashrate_samples(USER_ID).is_empty());
+        assert!(store.user_exist("@carol:example.com").unwrap());
+
+        assert!(store
+            .delete_room_users("!gone:example.com")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_room_commands() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert!(store.get_room_commands("!room:example.com").is_empty());
+
+        store
+            .set_room_command("!room:example.com", "subscribe", false)
+            .unwrap();
+        store
+            .set_room_command("!room:example.com", "stats", true)
+            .unwrap();
+        store
+            .set_room_command("!room:example.com", "subscribe", true)
+            .unwrap();
+
+        let commands: RoomCommands = store.get_room_commands("!room:example.com");
+        assert_eq!(commands.get("subscribe"), Some(&true));
+        assert_eq!(commands.get("stats"), Some(&true));
+        assert!(store.get_room_commands("!other:example.com").is_empty());
+    }
+
+    #[test]
+    fn test_room_welcomed() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert!(!store.is_room_welcomed("!room:example.com").unwrap());
+        store.set_room_welcomed("!room:example.com").unwrap();
+        assert!(store.is_room_welcomed("!room:example.com").unwrap());
+        assert!(!store.is_room_welcomed("!other:example.com").unwrap());
+    }
+
+    #[test]
+    fn test_dm_room() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert_eq!(store.get_dm_room(USER_ID), None);
+        store.set_dm_room(USER_ID, "!dm:example.com").unwrap();
+        store.set_dm_room(USER_ID, "!dm2:example.com").unwrap();
+        assert_eq!(
+            store.get_dm_room(USER_ID),
+            Some(String::from("!dm2:example.com"))
+        );
+        assert_eq!(store.get_dm_room("@bob:example.com"), None);
+    }
+
+    #[test]
+    fn test_sync_token() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert_eq!(store.get_sync_token(), None);
+
+        store.set_sync_token("s72594_4483_1934").unwrap();
+        store.set_sync_token("s72595_4483_1934").unwrap();
+        drop(store);
+
+        let store = DBStore::open(dir.path(), None).unwrap();
+        assert_eq!(
+            store.get_sync_token(),
+            Some(String::from("s72595_4483_1934"))
+        );
+    }
+
+    #[test]
+    fn test_crypto_store_encrypted() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert_eq!(store.get_crypto_store_encrypted(), None);
+
+        store.set_crypto_store_encrypted(true).unwrap();
+        assert_eq!(store.get_crypto_store_encrypted(), Some(true));
+
+        store.set_crypto_store_encrypted(false).unwrap();
+        assert_eq!(store.get_crypto_store_encrypted(), Some(false));
+    }
+
+    #[test]
+    fn test_notifications() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert_eq!(store.get_notification(USER_ID, "stale:s19"), None);
+
+        store.set_notification(USER_ID, "stale:s19", 42).unwrap();
+        store.set_notification(USER_ID, "block", 7).unwrap();
+        store
+            .set_notification("@bob:example.com", "block", 7)
+            .unwrap();
+        // Its keys sort right after the ones of USER_ID
+        store
+            .set_notification("@alice:example.co", "block", 7)
+            .unwrap();
+        assert_eq!(
+            store.get_notification(USER_ID, "stale:s19").unwrap().value,
+            42
+        );
+
+        let keys: Vec<String> = store
+            .get_notifications(USER_ID)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["block", "stale:s19"]);
+
+        store.delete_notification(USER_ID, "stale:s19").unwrap();
+        assert_eq!(store.get_notification(USER_ID, "stale:s19"), None);
+        assert_eq!(store.get_notifications(USER_ID).len(), 1);
+
+        // Removed with the user
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        store.delete_user(USER_ID).unwrap();
+        assert_eq!(store.get_notification(USER_ID, "block"), None);
+        assert!(store
+            .get_notification("@bob:example.com", "block")
+            .is_some());
+        assert_eq!(store.get_notifications("@alice:example.co").len(), 1);
+    }
+
+    #[test]
+    fn test_rewards_history() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+        let day = |d: u32| NaiveDate::from_ymd(2022, 4, d);
+
+        assert_eq!(store.last_reward_date(USER_ID), None);
+
+        // The second replaces the first
+        store.upsert_reward(USER_ID, day(15), 0.1).unwrap();
+        store.upsert_reward(USER_ID, day(15), 0.2).unwrap();
+        store.upsert_reward(USER_ID, day(9), 0.3).unwrap();
+        store.upsert_reward(USER_ID, day(10), 0.4).unwrap();
+        store
+            .upsert_reward("@bob:example.com", day(15), 0.5)
+            .unwrap();
+        // Keys sorted right after the ones of USER_ID
+        store
+            .upsert_reward("@alice:example.co", day(20), 0.6)
+            .unwrap();
+
+        assert_eq!(
+            store.get_rewards(USER_ID, day(1), day(30)),
+            vec![(1649462400, 0.3), (1649548800, 0.4), (1649980800, 0.2)]
+        );
+        assert_eq!(
+            store.get_rewards(USER_ID, day(10), day(15)),
+            vec![(1649548800, 0.4), (1649980800, 0.2)]
+        );
+        assert!(store.get_rewards(USER_ID, day(11), day(14)).is_empty());
+        assert_eq!(store.last_reward_date(USER_ID), Some(day(15)));
+
+        // Removed with the user
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        store.delete_user(USER_ID).unwrap();
+        assert_eq!(store.last_reward_date(USER_ID), None);
+        assert_eq!(store.last_reward_date("@bob:example.com"), Some(day(15)));
+        assert_eq!(store.last_reward_date("@alice:example.co"), Some(day(20)));
+    }
+
+    #[test]
+    fn test_user_exist() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        // Absent
+        assert!(!store.user_exist(USER_ID).unwrap());
+        assert!(!store
+            .user_with_room_exist(USER_ID, "!room:example.com")
+            .unwrap());
+
+        // Present
+        store
+            .create_user(USER_ID, "!room:example.com", "token")
+            .unwrap();
+        assert!(store.user_exist(USER_ID).unwrap());
+        assert!(store
+            .user_with_room_exist(USER_ID, "!room:example.com")
+            .unwrap());
+        assert!(!store
+            .user_with_room_exist(USER_ID, "!other:example.com")
+            .unwrap());
+
+        // Corrupt
+        store
+            .db
+            .db
+            .put_cf(&store.user_cf(), USER_ID, [0xff])
+            .unwrap();
+        assert!(store.user_exist(USER_ID).is_err());
+        assert!(store
+            .user_with_room_exist(USER_ID, "!room:example.com")
+            .is_err());
+    }
+
+    #[test]
+    fn test_session_exist() {
+        let dir = TempDir::new().unwrap();
+        let store = DBStore::open(dir.path(), None).unwrap();
+
+        assert!(!store.session_exist(USER_ID).unwrap());
+
+        store
+            .create_session(USER_ID, "access", "DEVICE", Some("ed25519"))
+            .unwrap();
+        assert!(store.session_exist(USER_ID).unwrap());
+        assert_eq!(store.get_device_key().as_deref(), Some("ed25519"));
+
+        store
+            .db
+            .db
+            .put_cf(&store.session_cf(), USER_ID, [0xff])
+            .unwrap();
+        assert!(store.session_exist(USER_ID).is_err());
+
+        store.delete_session(USER_ID).unwrap();
+        assert!(!store.session_exist(USER_ID).unwrap());
+        assert_eq!(store.get_device_key(), None);
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use chrono::Utc;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+
+use crate::config::{Config, ConfigHandle};
+use crate::db::DBStore;
+
+static LOGGED_IN: AtomicBool = AtomicBool::new(false);
+static LAST_SYNC: AtomicI64 = AtomicI64::new(0);
+
+pub fn set_logged_in() {
+    LOGGED_IN.store(true, Ordering::SeqCst);
+}
+
+pub fn update_last_sync() {
+    LAST_SYNC.store(Utc::now().timestamp(), Ordering::SeqCst);
+}
+
+fn is_alive(config: &Config, store: &DBStore) -> bool {
+    let last_sync: i64 = LAST_SYNC.load(Ordering::SeqCst);
+    let max_sync_age: i64 = config.health.max_sync_age_mins as i64 * 60;
+
+    last_sync > 0 && Utc::now().timestamp() - last_sync <= max_sync_age && store.is_open()
+}
+
+fn is_ready() -> bool {
+    LOGGED_IN.load(Ordering::SeqCst)
+}
+
+pub fn spawn(config: ConfigHandle, store: DBStore) {
+    let addr: SocketAddr = match config.get().health.bind_addr {
+        Some(addr) => addr,
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        let make_svc = make_service_fn(move |_conn| {
+            let config = config.clone();
+            let store = store.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle_request(req, config.clone(), store.clone())
+                }))
+            }
+        });
+
+        match Server::try_bind(&addr) {
+            Ok(builder) => {
+                log::info!("Health server listening on {}", addr);
+
+                if let Err(error) = builder.serve(make_svc).await {
+                    log::error!("Health server error: {:?}", error);
+                }
+            }
+            Err(error) => log::error!("Impossible to bind health server to {}: {:?}", addr, error),
+        }
+    });
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    config: ConfigHandle,
+    store: DBStore,
+) -> Result<Response<Body>, Infallible> {
+    let (status, body) = match req.uri().path() {
+        "/healthz" if is_alive(&config.get(), &store) => (StatusCode::OK, "ok"),
+        "/healthz" => (StatusCode::SERVICE_UNAVAILABLE, "unhealthy"),
+        "/readyz" if is_ready() => (StatusCode::OK, "ready"),
+        "/readyz" => (StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+        _ => (StatusCode::NOT_FOUND, "not found"),
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    Ok(response)
+}
@@ -0,0 +1,619 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Message catalog
+//!
+//! Every user-facing string has a [`Msg`] key. Templates use `{}` placeholders,
+//! replaced in order by the args passed to [`t`]. Missing translations fall
+//! back to English.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    En,
+    It,
+}
+
+impl Lang {
+    pub const CODES: &'static str = "en, it";
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::It => "it",
+        }
+    }
+}
+
+impl FromStr for Lang {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "en" => Ok(Self::En),
+            "it" => Ok(Self::It),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Msg {
+    InvalidCommand,
+    CommandDisabled,
+    CommandEnabledInRoom,
+    CommandDisabledInRoom,
+    CommandDisabledInConfig,
+    ApiTimeout,
+    PoolUnavailable,
+    PoolRateLimited,
+    EnableUsage,
+    RoomAdminOnly,
+    NotSubscribed,
+    AdminOnly,
+    UndecryptableHint,
+    Welcome,
+    UserStatusTitle,
+    SummaryTitle,
+    ProfileUnavailable,
+    PoolStatsUnavailable,
+    PayoutTitle,
+    PayoutNextBlock,
+    PayoutPerBlock,
+    PayoutBalance,
+    PayoutThreshold,
+    PayoutThresholdReached,
+    PayoutAssumptions,
+    PayoutDisclaimer,
+    PayoutUnavailable,
+    PayoutUsage,
+    Reward,
+    UnconfirmedReward,
+    EstimatedReward,
+    Hashrate5m,
+    TotalWorkerHashrate,
+    Hashrate60m,
+    Hashrate24h,
+    HashrateScoring,
+    HashrateYesterday,
+    OkWorkers,
+    LowWorkers,
+    OffWorkers,
+    DisabledWorkers,
+    WorkersTitle,
+    CompareTitle,
+    CompareUsage,
+    WorkerNotFound,
+    AmbiguousWorker,
+    WorkerColumn,
+    StatusColumn,
+    LastShareColumn,
+    ScoringColumn,
+    DateColumn,
+    RewardColumn,
+    DailyRewardsTitle,
+    DayReward,
+    NoRewardForDate,
+    RewardUsage,
+    RewardsRange,
+    NoRewardsInRange,
+    InvalidRewardsRange,
+    RewardsUsage,
+    PoolStatusTitle,
+    Luck10,
+    Luck50,
+    Luck250,
+    ActiveWorkers,
+    RoundProbability,
+    HashrateTitle,
+    HashrateRange,
+    Min,
+    Avg,
+    Max,
+    NoHashrateSamples,
+    ChartsDisabled,
+    Subscribed,
+    SubscribeInDm,
+    DmSent,
+    DmFailed,
+    TokenExposed,
+    TokenScopeAdvice,
+    RedactionFailed,
+    TokenRequired,
+    TokenUpdated,
+    InvalidToken,
+    SetTokenUsage,
+    AlreadySubscribed,
+    MySubsTitle,
+    MySub,
+    WhoamiTitle,
+    WhoamiUser,
+    WhoamiRoom,
+    WhoamiSubscribed,
+    WhoamiNotSubscribed,
+    WhoamiTimezone,
+    WhoamiAlerts,
+    WhoamiNoAlerts,
+    WhoamiStaleAlert,
+    WhoamiZeroAlert,
+    Unlinked,
+    UnlinkConfirm,
+    NoPendingConfirmation,
+    ConfirmationExpired,
+    NoTokenLinked,
+    SettingsTitle,
+    SettingUpdated,
+    UnknownSetting,
+    InvalidSettingValue,
+    SettingsUsage,
+    LangUpdated,
+    UnknownLang,
+    UnitUpdated,
+    UnknownUnit,
+    StaleAlertSet,
+    StaleAlertDisabled,
+    SetAlertUsage,
+    StaleShareAlert,
+    WorkerRecovered,
+    ZeroHashrateAlert,
+    RecoveryAlertsEnabled,
+    RecoveryAlertsDisabled,
+    NotifyUsage,
+    Muted,
+    Unmuted,
+    MuteUsage,
+    TorConnected,
+    TorNotConnected,
+    TestedProxy,
+    TestedNoProxy,
+    PingLatency,
+    Version,
+    Commit,
+    Uptime,
+    HealthTitle,
+    StatsTitle,
+    Stats,
+    AuditTitle,
+    NoAuditEntries,
+    HelpUserStatus,
+    HelpSummary,
+    HelpWorkers,
+    HelpCompare,
+    HelpDailyRewards,
+    HelpExportRewards,
+    HelpReward,
+    HelpRewards,
+    HelpPayout,
+    HelpPoolStatus,
+    HelpHashrate,
+    HelpChart,
+    HelpSubscribe,
+    HelpSetToken,
+    HelpUnlink,
+    HelpMySubs,
+    HelpWhoami,
+    HelpEnable,
+    HelpCheckTor,
+    HelpPing,
+    HelpSettings,
+    HelpLang,
+    HelpUnit,
+    HelpSetAlert,
+    HelpNotify,
+    HelpMute,
+    HelpUnmute,
+    HelpVersion,
+    HelpHealth,
+    HelpStats,
+    HelpAudit,
+    HelpHelp,
+}
+
+/// Translate a message, replacing the `{}` placeholders with `args`
+pub fn t(key: Msg, lang: Lang, args: &[&dyn fmt::Display]) -> String {
+    let template: &str = match lang {
+        Lang::En => None,
+        Lang::It => it(key),
+    }
+    .unwrap_or_else(|| en(key));
+
+    let mut args = args.iter();
+    let mut parts = template.split("{}");
+    let mut msg = String::from(parts.next().unwrap_or_default());
+
+    for part in parts {
+        if let Some(arg) = args.next() {
+            msg.push_str(&arg.to_string());
+        }
+        msg.push_str(part);
+    }
+
+    msg
+}
+
+fn en(key: Msg) -> &'static str {
+    match key {
+        Msg::InvalidCommand => "Invalid command",
+        Msg::CommandDisabled => "This command is disabled here",
+        Msg::CommandEnabledInRoom => "{}{} enabled in this room",
+        Msg::CommandDisabledInRoom => "{}{} disabled in this room",
+        Msg::CommandDisabledInConfig => "{}{} is disabled in the bot config",
+        Msg::ApiTimeout => "BraiinsPool request timed out",
+        Msg::PoolUnavailable => "BraiinsPool is temporarily unavailable (maintenance); please try again later.",
+        Msg::PoolRateLimited => "Too many requests to BraiinsPool with your token: try again in {} seconds.",
+        Msg::EnableUsage => "Usage: {}enable <command> or {}disable <command>",
+        Msg::RoomAdminOnly => "This command is reserved to room admins",
+        Msg::NotSubscribed => "This account in not subscribed.",
+        Msg::AdminOnly => "This command is reserved to admins",
+        Msg::UndecryptableHint => "I couldn't read a message in this room because I can't decrypt it. Try to invite me again or to verify my device.",
+        Msg::Welcome => "Hi! I'm the BraiinsPool bot: I show the stats of your BraiinsPool account and alert you about your workers and the pool.\nSend {}help to see the commands.\nPrivacy: send {}subscribe <token> in a direct message with me, not in a shared room, or the other members could read your token before I delete it.",
+        Msg::UserStatusTitle => "User Status",
+        Msg::SummaryTitle => "Summary",
+        Msg::ProfileUnavailable => "(account data unavailable)",
+        Msg::PoolStatsUnavailable => "(pool stats unavailable)",
+        Msg::PayoutTitle => "Payout estimate",
+        Msg::PayoutNextBlock => "Next pool block: ~{}",
+        Msg::PayoutPerBlock => "Your reward per block: ~{}",
+        Msg::PayoutBalance => "Balance (confirmed + unconfirmed): {}",
+        Msg::PayoutThreshold => "Payout threshold of {}: ~{} blocks, ~{}",
+        Msg::PayoutThresholdReached => "Payout threshold of {} already reached",
+        Msg::PayoutAssumptions => {
+            "Assumptions: the pool finds blocks at the rate implied by the current round probability, your 5m hashrate doesn't change and the unconfirmed reward gets confirmed."
+        }
+        Msg::PayoutDisclaimer => "⚠️ This is an estimate, not a promise: blocks are found at random.",
+        Msg::PayoutUnavailable => "Not enough data for an estimate (no hashrate?)",
+        Msg::PayoutUsage => "Usage: {}payout [<threshold in BTC>]",
+        Msg::Reward => "Reward: {}",
+        Msg::UnconfirmedReward => "Unconfirmed reward: {}",
+        Msg::EstimatedReward => "Estimate reward (block): {}",
+        Msg::Hashrate5m => "Hashrate 5m: {}",
+        Msg::TotalWorkerHashrate => "Total worker hashrate (5m): {}",
+        Msg::Hashrate60m => "Hashrate 60m: {}",
+        Msg::Hashrate24h => "Hashrate 24h: {}",
+        Msg::HashrateScoring => "Hashrate scoring: {}",
+        Msg::HashrateYesterday => "Hashrate yesterday: {}",
+        Msg::OkWorkers => "Ok workers: {}",
+        Msg::LowWorkers => "Low workers: {}",
+        Msg::OffWorkers => "Off workers: {}",
+        Msg::DisabledWorkers => "Disabled workers: {}",
+        Msg::WorkersTitle => "Workers",
+        Msg::CompareTitle => "Workers comparison",
+        Msg::CompareUsage => "Usage: {}compare <worker1> <worker2>",
+        Msg::WorkerNotFound => "No worker matches: {}",
+        Msg::AmbiguousWorker => "`{}` matches more workers: {}",
+        Msg::WorkerColumn => "Worker",
+        Msg::StatusColumn => "Status",
+        Msg::LastShareColumn => "Last share",
+        Msg::ScoringColumn => "Scoring",
+        Msg::DateColumn => "Date",
+        Msg::RewardColumn => "Reward",
+        Msg::DailyRewardsTitle => "Daily Rewards",
+        Msg::DayReward => "Reward of {}: {}",
+        Msg::NoRewardForDate => "No reward recorded for that date",
+        Msg::RewardUsage => "Usage: {}reward <YYYY-MM-DD|today|yesterday>",
+        Msg::RewardsRange => "Rewards from {} to {}: {}\nDays counted: {}\nDaily average: {}",
+        Msg::NoRewardsInRange => "No reward recorded from {} to {}",
+        Msg::InvalidRewardsRange => "Invalid range: the start must not be after the end, and the range can't exceed {} days",
+        Msg::RewardsUsage => "Usage: {}rewards <from> <to> (YYYY-MM-DD, today or yesterday), at most {} days",
+        Msg::PoolStatusTitle => "Pool Status",
+        Msg::Luck10 => "Luck 10 blocks: {}",
+        Msg::Luck50 => "Luck 50 blocks: {}",
+        Msg::Luck250 => "Luck 250 blocks: {}",
+        Msg::ActiveWorkers => "Active workers: {}",
+        Msg::RoundProbability => "Round probability: {}",
+        Msg::HashrateTitle => "Hashrate 5m",
+        Msg::HashrateRange => "From {} to {}",
+        Msg::Min => "Min: {}",
+        Msg::Avg => "Avg: {}",
+        Msg::Max => "Max: {}",
+        Msg::NoHashrateSamples => "No hashrate samples yet, please try again later.",
+        Msg::ChartsDisabled => "Charts are not enabled",
+        Msg::Subscribed => "Subscribed",
+        Msg::SubscribeInDm => "Send here {}subscribe <token> to subscribe: only you and I can read this room.",
+        Msg::DmSent => "I've sent you a DM",
+        Msg::DmFailed => "I couldn't open a direct room with you, maybe your server refuses the invites: start one with me and send {}subscribe <token> there.",
+        Msg::TokenExposed => "Your token was visible to the members of this room, so it was not saved: consider rotating it.",
+        Msg::TokenScopeAdvice => "Tip: the pool API doesn't tell the permissions of a token, so I can't check them. Use a token created only for this bot, with read-only access: it limits the damage if it ever leaks.",
+        Msg::RedactionFailed => "I could not delete your message containing the token: please delete it manually and consider rotating the token.",
+        Msg::TokenRequired => "Please provide a token.\nTo subscribe send: {}subscribe <token>",
+        Msg::TokenUpdated => "Token updated",
+        Msg::InvalidToken => "Invalid token: BraiinsPool refused it",
+        Msg::SetTokenUsage => "Usage: {}settoken <token>",
+        Msg::AlreadySubscribed => "This account is already subscribed",
+        Msg::MySubsTitle => "Your subscriptions",
+        Msg::MySub => "{} - account: {}",
+        Msg::WhoamiTitle => "Who am I",
+        Msg::WhoamiUser => "Matrix id: {}",
+        Msg::WhoamiRoom => "This room: {}",
+        Msg::WhoamiSubscribed => "Subscribed: yes, in {} - account: {}",
+        Msg::WhoamiNotSubscribed => "Subscribed: no",
+        Msg::WhoamiTimezone => "Timezone: {}",
+        Msg::WhoamiAlerts => "Active alerts: {}",
+        Msg::WhoamiNoAlerts => "Active alerts: none",
+        Msg::WhoamiStaleAlert => "{} not submitting shares",
+        Msg::WhoamiZeroAlert => "{} not hashing",
+        Msg::Unlinked => "Unlinked",
+        Msg::UnlinkConfirm => "This will remove the token linked to your account. Reply {}unlink confirm within {} seconds to proceed",
+        Msg::NoPendingConfirmation => "Nothing to confirm: send {}unlink first",
+        Msg::ConfirmationExpired => "The confirmation expired after {} seconds, nothing was removed: send {}unlink again",
+        Msg::NoTokenLinked => "No token linked to this account",
+        Msg::SettingsTitle => "Settings",
+        Msg::SettingUpdated => "Setting `{}` updated",
+        Msg::UnknownSetting => "Unknown setting `{}`. Available settings: {}",
+        Msg::InvalidSettingValue => "Invalid value for `{}`. Accepted values: {}",
+        Msg::SettingsUsage => "Usage: {}settings <key> <value>",
+        Msg::LangUpdated => "Language set to English",
+        Msg::UnknownLang => "Unknown language. Available languages: {}",
+        Msg::UnitUpdated => "Amounts will be shown in {}",
+        Msg::UnknownUnit => "Unknown unit. Available units: {}",
+        Msg::StaleAlertSet => {
+            "You will be alerted when a worker has no shares for more than {} minutes"
+        }
+        Msg::StaleAlertDisabled => "Stale share alert disabled",
+        Msg::SetAlertUsage => "Usage: {}setalert stale <minutes|off>",
+        Msg::StaleShareAlert => "Worker {} has not submitted shares for {}",
+        Msg::WorkerRecovered => "Worker {} is back online",
+        Msg::ZeroHashrateAlert => "⚠️ Worker {} is not hashing: 5m hashrate {}",
+        Msg::RecoveryAlertsEnabled => "You will be notified when a worker is back online",
+        Msg::RecoveryAlertsDisabled => "Recovery notifications disabled",
+        Msg::NotifyUsage => "Usage: {}notify recovery <on|off>",
+        Msg::Muted => "Alerts muted until {} UTC",
+        Msg::Unmuted => "Alerts unmuted",
+        Msg::MuteUsage => "Usage: {}mute <duration> (ex. 30m, 2h, 1h30m)",
+        Msg::TorConnected => "Connected to Tor Network",
+        Msg::TorNotConnected => "NOT connected to Tor Network",
+        Msg::TestedProxy => "Tested via proxy {}",
+        Msg::TestedNoProxy => "Tested without proxy",
+        Msg::PingLatency => "BraiinsPool: {} ms\nMatrix reply: {} ms",
+        Msg::Version => "BraiinsPool Bot v{}",
+        Msg::Commit => "Commit: {}",
+        Msg::Uptime => "Uptime: {}",
+        Msg::HealthTitle => "Health",
+        Msg::StatsTitle => "Stats",
+        Msg::Stats => {
+            "Commands processed: {}\nUptime: {}\nSubscribed users: {}\nBraiinsPool API calls: {}\nAverage API latency: {}\nCache hit rate: n/a (no cache, every request reaches BraiinsPool)"
+        }
+        Msg::AuditTitle => "Audit log",
+        Msg::NoAuditEntries => "No audit entries",
+        Msg::HelpUserStatus => "{}userstatus - Get user status",
+        Msg::HelpSummary => "{}summary - Get account and pool status at a glance",
+        Msg::HelpWorkers => "{}workers - Get workers",
+        Msg::HelpCompare => "{}compare <worker1> <worker2> - Compare two workers side by side",
+        Msg::HelpDailyRewards => "{}dailyrewards - Get daily rewards",
+        Msg::HelpExportRewards => "{}exportrewards - Export daily rewards as CSV",
+        Msg::HelpReward => "{}reward <YYYY-MM-DD|today|yesterday> - Get the reward of a day",
+        Msg::HelpRewards => "{}rewards <from> <to> - Get the total and the daily average of the rewards of a date range",
+        Msg::HelpPayout => "{}payout [<threshold in BTC>] - Estimate the time to the next reward and to the payout threshold",
+        Msg::HelpPoolStatus => "{}poolstatus - Get pool status",
+        Msg::HelpHashrate => "{}hashrate - Get hashrate trend",
+        Msg::HelpChart => "{}chart - Get hashrate chart",
+        Msg::HelpSubscribe => "{}subscribe <token> - Subscribe with token",
+        Msg::HelpSetToken => "{}settoken <token> - Replace your token, keeping your settings",
+        Msg::HelpUnlink => "{}unlink [confirm] - Unlink account from token",
+        Msg::HelpMySubs => "{}mysubs - List the rooms where you are subscribed",
+        Msg::HelpWhoami => "{}whoami - Show what the bot knows about you",
+        Msg::HelpEnable => "{}enable|disable <command> - Enable or disable a command in this room (room admins)",
+        Msg::HelpCheckTor => "{}checktor - Check Tor connection",
+        Msg::HelpPing => "{}ping - Check BraiinsPool and Matrix latency",
+        Msg::HelpSettings => "{}settings [<key> <value>] - Show or change your settings",
+        Msg::HelpLang => "{}lang <code> - Set your language ({})",
+        Msg::HelpUnit => "{}unit <btc|sat> - Set the unit of rewards",
+        Msg::HelpSetAlert => {
+            "{}setalert stale <minutes|off> - Alert when a worker stops submitting shares"
+        }
+        Msg::HelpNotify => "{}notify recovery <on|off> - Notify when a stale worker is back online",
+        Msg::HelpMute => "{}mute <duration> - Mute alerts for a while (ex. 30m, 2h)",
+        Msg::HelpUnmute => "{}unmute - Unmute alerts",
+        Msg::HelpVersion => "{}version - Get bot version and uptime",
+        Msg::HelpHealth => "{}health - Check bot dependencies",
+        Msg::HelpStats => "{}stats - Show bot metrics",
+        Msg::HelpAudit => "{}audit [user] - Show the last processed commands",
+        Msg::HelpHelp => "{}help - Help",
+    }
+}
+
+fn it(key: Msg) -> Option<&'static str> {
+    let msg: &str = match key {
+        Msg::InvalidCommand => "Comando non valido",
+        Msg::CommandDisabled => "Questo comando è disabilitato qui",
+        Msg::CommandEnabledInRoom => "{}{} abilitato in questa stanza",
+        Msg::CommandDisabledInRoom => "{}{} disabilitato in questa stanza",
+        Msg::CommandDisabledInConfig => "{}{} è disabilitato nella configurazione del bot",
+        Msg::ApiTimeout => "Timeout della richiesta a BraiinsPool",
+        Msg::PoolUnavailable => "BraiinsPool è temporaneamente non disponibile (manutenzione); riprova più tardi.",
+        Msg::PoolRateLimited => "Troppe richieste a BraiinsPool con il tuo token: riprova tra {} secondi.",
+        Msg::EnableUsage => "Uso: {}enable <comando> oppure {}disable <comando>",
+        Msg::RoomAdminOnly => "Questo comando è riservato agli amministratori della stanza",
+        Msg::NotSubscribed => "Questo account non è iscritto.",
+        Msg::AdminOnly => "Questo comando è riservato agli amministratori",
+        Msg::UndecryptableHint => "Non ho potuto leggere un messaggio in questa stanza perché non riesco a decifrarlo. Prova a invitarmi di nuovo o a verificare il mio dispositivo.",
+        Msg::Welcome => "Ciao! Sono il bot di BraiinsPool: mostro le statistiche del tuo account BraiinsPool e ti avviso su worker e pool.\nInvia {}help per vedere i comandi.\nPrivacy: invia {}subscribe <token> in un messaggio diretto con me, non in una stanza condivisa, altrimenti gli altri membri potrebbero leggere il tuo token prima che lo elimini.",
+        Msg::UserStatusTitle => "Stato utente",
+        Msg::SummaryTitle => "Riepilogo",
+        Msg::ProfileUnavailable => "(dati dell'account non disponibili)",
+        Msg::PoolStatsUnavailable => "(statistiche della pool non disponibili)",
+        Msg::PayoutTitle => "Stima del pagamento",
+        Msg::PayoutNextBlock => "Prossimo blocco della pool: ~{}",
+        Msg::PayoutPerBlock => "Tua ricompensa per blocco: ~{}",
+        Msg::PayoutBalance => "Saldo (confermato + non confermato): {}",
+        Msg::PayoutThreshold => "Soglia di pagamento di {}: ~{} blocchi, ~{}",
+        Msg::PayoutThresholdReached => "Soglia di pagamento di {} già raggiunta",
+        Msg::PayoutAssumptions => {
+            "Ipotesi: la pool trova blocchi al ritmo indicato dalla probabilità del round corrente, il tuo hashrate a 5m non cambia e la ricompensa non confermata viene confermata."
+        }
+        Msg::PayoutDisclaimer => "⚠️ È una stima, non una promessa: i blocchi vengono trovati in modo casuale.",
+        Msg::PayoutUnavailable => "Dati insufficienti per una stima (nessun hashrate?)",
+        Msg::PayoutUsage => "Uso: {}payout [<soglia in BTC>]",
+        Msg::Reward => "Ricompensa: {}",
+        Msg::UnconfirmedReward => "Ricompensa non confermata: {}",
+        Msg::EstimatedReward => "Ricompensa stimata (blocco): {}",
+        Msg::TotalWorkerHashrate => "Hashrate totale dei worker (5m): {}",
+        Msg::HashrateYesterday => "Hashrate ieri: {}",
+        Msg::OkWorkers => "Worker ok: {}",
+        Msg::LowWorkers => "Worker bassi: {}",
+        Msg::OffWorkers => "Worker spenti: {}",
+        Msg::DisabledWorkers => "Worker disabilitati: {}",
+        Msg::CompareTitle => "Confronto worker",
+        Msg::CompareUsage => "Uso: {}compare <worker1> <worker2>",
+        Msg::WorkerNotFound => "Nessun worker corrisponde a: {}",
+        Msg::AmbiguousWorker => "`{}` corrisponde a più worker: {}",
+        Msg::StatusColumn => "Stato",
+        Msg::LastShareColumn => "Ultima share",
+        Msg::DateColumn => "Data",
+        Msg::RewardColumn => "Ricompensa",
+        Msg::DailyRewardsTitle => "Ricompense giornaliere",
+        Msg::DayReward => "Ricompensa del {}: {}",
+        Msg::NoRewardForDate => "Nessuna ricompensa registrata per quella data",
+        Msg::RewardUsage => "Uso: {}reward <AAAA-MM-GG|today|yesterday>",
+        Msg::RewardsRange => "Ricompense dal {} al {}: {}\nGiorni conteggiati: {}\nMedia giornaliera: {}",
+        Msg::NoRewardsInRange => "Nessuna ricompensa registrata dal {} al {}",
+        Msg::InvalidRewardsRange => "Intervallo non valido: l'inizio non può essere dopo la fine, e l'intervallo non può superare {} giorni",
+        Msg::RewardsUsage => "Uso: {}rewards <da> <a> (AAAA-MM-GG, today o yesterday), al massimo {} giorni",
+        Msg::PoolStatusTitle => "Stato della pool",
+        Msg::Luck10 => "Fortuna 10 blocchi: {}",
+        Msg::Luck50 => "Fortuna 50 blocchi: {}",
+        Msg::Luck250 => "Fortuna 250 blocchi: {}",
+        Msg::ActiveWorkers => "Worker attivi: {}",
+        Msg::RoundProbability => "Probabilità del round: {}",
+        Msg::HashrateRange => "Da {} a {}",
+        Msg::Avg => "Media: {}",
+        Msg::NoHashrateSamples => "Ancora nessun campione di hashrate, riprova più tardi.",
+        Msg::ChartsDisabled => "I grafici non sono abilitati",
+        Msg::Subscribed => "Iscritto",
+        Msg::SubscribeInDm => "Invia qui {}subscribe <token> per iscriverti: solo tu e io possiamo leggere questa stanza.",
+        Msg::DmSent => "Ti ho inviato un messaggio diretto",
+        Msg::DmFailed => "Non sono riuscito ad aprire una stanza diretta con te, forse il tuo server rifiuta gli inviti: aprine una con me e invia lì {}subscribe <token>.",
+        Msg::TokenExposed => "Il tuo token era visibile ai membri di questa stanza, quindi non è stato salvato: valuta di rigenerarlo.",
+        Msg::TokenScopeAdvice => "Suggerimento: l'API della pool non indica i permessi di un token, quindi non posso verificarli. Usa un token creato solo per questo bot, con accesso in sola lettura: limita i danni se dovesse essere rubato.",
+        Msg::RedactionFailed => "Non sono riuscito a eliminare il tuo messaggio con il token: eliminalo manualmente e valuta di rigenerare il token.",
+        Msg::TokenRequired => "Fornisci un token.\nPer iscriverti invia: {}subscribe <token>",
+        Msg::TokenUpdated => "Token aggiornato",
+        Msg::InvalidToken => "Token non valido: BraiinsPool lo ha rifiutato",
+        Msg::SetTokenUsage => "Uso: {}settoken <token>",
+        Msg::AlreadySubscribed => "Questo account è già iscritto",
+        Msg::MySubsTitle => "Le tue iscrizioni",
+        Msg::MySub => "{} - account: {}",
+        Msg::WhoamiTitle => "Chi sono",
+        Msg::WhoamiUser => "Id Matrix: {}",
+        Msg::WhoamiRoom => "Questa stanza: {}",
+        Msg::WhoamiSubscribed => "Iscritto: sì, in {} - account: {}",
+        Msg::WhoamiNotSubscribed => "Iscritto: no",
+        Msg::WhoamiTimezone => "Fuso orario: {}",
+        Msg::WhoamiAlerts => "Avvisi attivi: {}",
+        Msg::WhoamiNoAlerts => "Avvisi attivi: nessuno",
+        Msg::WhoamiStaleAlert => "{} non invia share",
+        Msg::WhoamiZeroAlert => "{} non sta minando",
+        Msg::Unlinked => "Scollegato",
+        Msg::UnlinkConfirm => "Questo rimuoverà il token collegato al tuo account. Rispondi {}unlink confirm entro {} secondi per procedere",
+        Msg::NoPendingConfirmation => "Niente da confermare: invia prima {}unlink",
+        Msg::ConfirmationExpired => "La conferma è scaduta dopo {} secondi, non è stato rimosso nulla: invia di nuovo {}unlink",
+        Msg::NoTokenLinked => "Nessun token collegato a questo account",
+        Msg::SettingsTitle => "Impostazioni",
+        Msg::SettingUpdated => "Impostazione `{}` aggiornata",
+        Msg::UnknownSetting => "Impostazione `{}` sconosciuta. Impostazioni disponibili: {}",
+        Msg::InvalidSettingValue => "Valore non valido per `{}`. Valori accettati: {}",
+        Msg::SettingsUsage => "Uso: {}settings <chiave> <valore>",
+        Msg::LangUpdated => "Lingua impostata su italiano",
+        Msg::UnknownLang => "Lingua sconosciuta. Lingue disponibili: {}",
+        Msg::UnitUpdated => "Gli importi saranno mostrati in {}",
+        Msg::UnknownUnit => "Unità sconosciuta. Unità disponibili: {}",
+        Msg::StaleAlertSet => {
+            "Riceverai un avviso quando un worker non invia share per più di {} minuti"
+        }
+        Msg::StaleAlertDisabled => "Avviso share ferme disabilitato",
+        Msg::SetAlertUsage => "Uso: {}setalert stale <minuti|off>",
+        Msg::StaleShareAlert => "Il worker {} non invia share da {}",
+        Msg::WorkerRecovered => "Il worker {} è di nuovo online",
+        Msg::ZeroHashrateAlert => "⚠️ Il worker {} non sta minando: hashrate 5m {}",
+        Msg::RecoveryAlertsEnabled => "Riceverai un avviso quando un worker torna online",
+        Msg::RecoveryAlertsDisabled => "Avvisi di ripristino disabilitati",
+        Msg::NotifyUsage => "Uso: {}notify recovery <on|off>",
+        Msg::Muted => "Avvisi silenziati fino al {} UTC",
+        Msg::Unmuted => "Avvisi riattivati",
+        Msg::MuteUsage => "Uso: {}mute <durata> (es. 30m, 2h, 1h30m)",
+        Msg::TorConnected => "Connesso alla rete Tor",
+        Msg::TorNotConnected => "NON connesso alla rete Tor",
+        Msg::TestedProxy => "Verificato tramite il proxy {}",
+        Msg::TestedNoProxy => "Verificato senza proxy",
+        Msg::PingLatency => "BraiinsPool: {} ms\nRisposta Matrix: {} ms",
+        Msg::Version => "BraiinsPool Bot v{}",
+        Msg::Uptime => "Attivo da: {}",
+        Msg::HealthTitle => "Salute",
+        Msg::StatsTitle => "Statistiche",
+        Msg::Stats => {
+            "Comandi elaborati: {}\nAttivo da: {}\nUtenti iscritti: {}\nChiamate API BraiinsPool: {}\nLatenza media API: {}\nCache hit: n/d (nessuna cache, ogni richiesta arriva a BraiinsPool)"
+        }
+        Msg::AuditTitle => "Registro comandi",
+        Msg::NoAuditEntries => "Nessun comando registrato",
+        Msg::HelpUserStatus => "{}userstatus - Stato utente",
+        Msg::HelpSummary => "{}summary - Riepilogo dell'account e della pool",
+        Msg::HelpWorkers => "{}workers - Lista dei worker",
+        Msg::HelpCompare => "{}compare <worker1> <worker2> - Confronta due worker",
+        Msg::HelpDailyRewards => "{}dailyrewards - Ricompense giornaliere",
+        Msg::HelpExportRewards => "{}exportrewards - Esporta le ricompense giornaliere in CSV",
+        Msg::HelpReward => "{}reward <AAAA-MM-GG|today|yesterday> - Ricompensa di un giorno",
+        Msg::HelpRewards => "{}rewards <da> <a> - Totale e media giornaliera delle ricompense di un intervallo di date",
+        Msg::HelpPayout => "{}payout [<soglia in BTC>] - Stima il tempo alla prossima ricompensa e alla soglia di pagamento",
+        Msg::HelpPoolStatus => "{}poolstatus - Stato della pool",
+        Msg::HelpHashrate => "{}hashrate - Andamento dell'hashrate",
+        Msg::HelpChart => "{}chart - Grafico dell'hashrate",
+        Msg::HelpSubscribe => "{}subscribe <token> - Iscriviti con un token",
+        Msg::HelpSetToken => "{}settoken <token> - Sostituisci il token, mantenendo le impostazioni",
+        Msg::HelpUnlink => "{}unlink [confirm] - Scollega l'account dal token",
+        Msg::HelpMySubs => "{}mysubs - Elenca le stanze in cui sei iscritto",
+        Msg::HelpWhoami => "{}whoami - Mostra cosa sa il bot di te",
+        Msg::HelpEnable => "{}enable|disable <comando> - Abilita o disabilita un comando in questa stanza (amministratori della stanza)",
+        Msg::HelpCheckTor => "{}checktor - Verifica la connessione Tor",
+        Msg::HelpPing => "{}ping - Latenza di BraiinsPool e Matrix",
+        Msg::HelpSettings => "{}settings [<chiave> <valore>] - Mostra o modifica le impostazioni",
+        Msg::HelpLang => "{}lang <codice> - Imposta la lingua ({})",
+        Msg::HelpUnit => "{}unit <btc|sat> - Imposta l'unità delle ricompense",
+        Msg::HelpSetAlert => "{}setalert stale <minuti|off> - Avviso per worker senza share",
+        Msg::HelpNotify => "{}notify recovery <on|off> - Avviso quando un worker fermo torna online",
+        Msg::HelpMute => "{}mute <durata> - Silenzia gli avvisi per un po' (es. 30m, 2h)",
+        Msg::HelpUnmute => "{}unmute - Riattiva gli avvisi",
+        Msg::HelpVersion => "{}version - Versione e uptime del bot",
+        Msg::HelpHealth => "{}health - Verifica le dipendenze del bot",
+        Msg::HelpStats => "{}stats - Mostra le metriche del bot",
+        Msg::HelpAudit => "{}audit [utente] - Mostra gli ultimi comandi elaborati",
+        Msg::HelpHelp => "{}help - Aiuto",
+        _ => return None,
+    };
+
+    Some(msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_t() {
+        assert_eq!(t(Msg::Subscribed, Lang::En, &[]), "Subscribed");
+        assert_eq!(t(Msg::Subscribed, Lang::It, &[]), "Iscritto");
+        assert_eq!(
+            t(Msg::PingLatency, Lang::En, &[&12, &"34"]),
+            "BraiinsPool: 12 ms\nMatrix reply: 34 ms"
+        );
+        assert_eq!(t(Msg::HashrateRange, Lang::It, &[&"a", &"b"]), "Da a a b");
+    }
+
+    #[test]
+    fn test_t_fallback() {
+        // Not translated
+        assert_eq!(t(Msg::Min, Lang::It, &[&1]), "Min: 1");
+        // Missing args
+        assert_eq!(t(Msg::Reward, Lang::En, &[]), "Reward: ");
+    }
+
+    #[test]
+    fn test_lang() {
+        assert_eq!(Lang::from_str("IT"), Ok(Lang::It));
+        assert_eq!(Lang::from_str("xx"), Err(()));
+        assert_eq!(Lang::default().code(), "en");
+    }
+}
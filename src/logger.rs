@@ -1,17 +1,483 @@
 // Copyright (c) 2021-2022 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use env_logger::{Builder, Env};
-use log::Level;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
 
-use crate::CONFIG;
+use arc_swap::ArcSwap;
+use chrono::{DateTime, SecondsFormat, Utc};
+use env_logger::{Builder, Env, Logger, Target, DEFAULT_FILTER_ENV};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
 
-pub fn init() {
-    let log_level: Level = if cfg!(debug_assertions) && CONFIG.log_level != Level::Trace {
+use crate::config::model::LogFormat;
+use crate::config::Config;
+use crate::secret;
+
+lazy_static! {
+    /// Levels applied by [`BotLogger`], changed by [`set_level`]
+    static ref LEVELS: ArcSwap<Levels> = ArcSwap::from_pointee(Levels::new(Level::Info, &[]));
+}
+
+thread_local! {
+    /// Structured fields of the records logged inside [`with_fields`]
+    static FIELDS: RefCell<Vec<(&'static str, Value)>> = RefCell::new(Vec::new());
+}
+
+pub fn init(config: &Config) {
+    let from_env: bool = std::env::var_os(DEFAULT_FILTER_ENV).is_some();
+    let mut builder: Builder = if from_env {
+        Builder::from_env(Env::default())
+    } else {
+        // Let everything through the builder: the levels are applied by `BotLogger`,
+        // so they can be changed on config reload
+        let mut builder = Builder::new();
+        builder.filter_level(LevelFilter::Trace);
+        builder
+    };
+
+    if config.log_format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_record(record, Utc::now())));
+    }
+
+    let mut file_error: Option<(&PathBuf, io::Error)> = None;
+    if let Some(path) = &config.log_file {
+        let max_size: u64 = config.log_file_max_size_mb * 1024 * 1024;
+        match RotatingFile::open(path, max_size, config.log_file_keep) {
+            Ok(file) => {
+                builder.target(Target::Pipe(Box::new(Tee { file })));
+            }
+            Err(error) => file_error = Some((path, error)),
+        }
+    }
+
+    let inner: Logger = builder.build();
+    let max_level: LevelFilter = inner.filter();
+    log::set_boxed_logger(Box::new(BotLogger { inner, from_env }))
+        .expect("logger already initialized");
+
+    if from_env {
+        log::set_max_level(max_level);
+    } else {
+        set_level(config.log_level, &config.log_modules);
+    }
+
+    if let Some((path, error)) = file_error {
+        log::error!("Impossible to open log file {}: {}", path.display(), error);
+    }
+}
+
+/// Change the log levels at runtime: `level` for the modules without their own level in
+/// `modules`. Return `false` if ignored because `RUST_LOG` is set.
+pub fn set_level(level: Level, modules: &[(String, Level)]) -> bool {
+    if std::env::var_os(DEFAULT_FILTER_ENV).is_some() {
+        return false;
+    }
+
+    let level: Level = if cfg!(debug_assertions) && level != Level::Trace {
         Level::Debug
     } else {
-        CONFIG.log_level
+        level
     };
 
-    Builder::from_env(Env::default().default_filter_or(log_level.to_string())).init();
+    let levels = Levels::new(level, modules);
+    log::set_max_level(levels.max());
+    LEVELS.store(Arc::new(levels));
+    true
+}
+
+/// Level of each module: the longest module path matching the target of the record wins
+struct Levels {
+    default: LevelFilter,
+    /// Longest module paths first
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Levels {
+    fn new(default: Level, modules: &[(String, Level)]) -> Self {
+        let mut modules: Vec<(String, LevelFilter)> = modules
+            .iter()
+            .map(|(module, level)| (module.clone(), level.to_level_filter()))
+            .collect();
+        modules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+
+        Self {
+            default: default.to_level_filter(),
+            modules,
+        }
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| match target.strip_prefix(module.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with("::"),
+                None => false,
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// Most verbose level, the records above it are discarded before reaching the logger
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// Apply [`LEVELS`] and mask the secrets before the env_logger logger
+struct BotLogger {
+    inner: Logger,
+    /// Levels from `RUST_LOG`, applied by `inner`, instead of [`LEVELS`]
+    from_env: bool,
+}
+
+impl Log for BotLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if self.from_env {
+            self.inner.enabled(metadata)
+        } else {
+            metadata.level() <= LEVELS.load().level(metadata.target())
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Masked after formatting: the secrets can come from any argument
+        let message: String = record.args().to_string();
+        match secret::mask(&message) {
+            Cow::Borrowed(_) => self.inner.log(record),
+            Cow::Owned(masked) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("{}", masked))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Attach structured fields to the records logged by `f`, only the JSON format shows them.
+/// See the `log_fields!` macro of the bot.
+pub fn with_fields<T>(fields: Vec<(&'static str, Value)>, f: impl FnOnce() -> T) -> T {
+    let len: usize = FIELDS.with(|current| {
+        let mut current = current.borrow_mut();
+        let len: usize = current.len();
+        current.extend(fields);
+        len
+    });
+    let result: T = f();
+    FIELDS.with(|current| current.borrow_mut().truncate(len));
+    result
+}
+
+/// Single line JSON object of a record
+fn json_record(record: &Record, timestamp: DateTime<Utc>) -> String {
+    let mut object = Map::new();
+    object.insert(
+        String::from("timestamp"),
+        Value::from(timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+    );
+    object.insert(
+        String::from("level"),
+        Value::from(record.level().to_string()),
+    );
+    object.insert(String::from("target"), Value::from(record.target()));
+    if let Some(module) = record.module_path() {
+        object.insert(String::from("module"), Value::from(module));
+    }
+    object.insert(
+        String::from("message"),
+        Value::from(record.args().to_string()),
+    );
+
+    FIELDS.with(|fields| {
+        for (key, value) in fields.borrow().iter() {
+            object.insert(key.to_string(), value.clone());
+        }
+    });
+
+    Value::Object(object).to_string()
+}
+
+/// Write the log records both to stderr and to the log file
+struct Tee {
+    file: RotatingFile,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.file.write_record(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Log file rotated by size: when a record would exceed `max_size`, the file is
+/// renamed to `<path>.1`, the older ones are shifted up to `<path>.<keep>` and a
+/// new file is started. Records are never split across two files.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    current: Mutex<CurrentFile>,
+}
+
+struct CurrentFile {
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        let size: u64 = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            current: Mutex::new(CurrentFile { file, size }),
+        })
+    }
+
+    /// Append a whole record, rotating first if needed. Safe to call from many threads.
+    fn write_record(&self, record: &[u8]) -> io::Result<()> {
+        // A panic while holding the lock leaves at most a partial record
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if current.size > 0 && current.size + record.len() as u64 > self.max_size {
+            current.file = self.rotate()?;
+            current.size = 0;
+        }
+
+        current.file.write_all(record)?;
+        current.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Shift the rotated files and return the new, empty, log file
+    fn rotate(&self) -> io::Result<File> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from: PathBuf = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use chrono::TimeZone;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::secret::Secret;
+
+    #[test]
+    fn test_levels() {
+        let levels = Levels::new(
+            Level::Info,
+            &[
+                (String::from("braiinspool_matrix_bot"), Level::Debug),
+                (String::from("matrix_sdk"), Level::Warn),
+                (String::from("matrix_sdk::encryption"), Level::Error),
+            ],
+        );
+
+        assert_eq!(levels.level("braiinspool_matrix_bot"), LevelFilter::Debug);
+        assert_eq!(
+            levels.level("braiinspool_matrix_bot::bot::poller"),
+            LevelFilter::Debug
+        );
+        assert_eq!(levels.level("matrix_sdk::client"), LevelFilter::Warn);
+        assert_eq!(
+            levels.level("matrix_sdk::encryption::olm"),
+            LevelFilter::Error
+        );
+        // Not a submodule
+        assert_eq!(levels.level("matrix_sdk_crypto"), LevelFilter::Info);
+        assert_eq!(levels.level("reqwest::connect"), LevelFilter::Info);
+        assert_eq!(levels.max(), LevelFilter::Debug);
+
+        let levels = Levels::new(Level::Warn, &[]);
+        assert_eq!(levels.level("braiinspool_matrix_bot"), LevelFilter::Warn);
+        assert_eq!(levels.max(), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_json_record() {
+        let timestamp: DateTime<Utc> = Utc.ymd(2022, 4, 15).and_hms_milli(10, 30, 0, 250);
+        let fields = vec![("command", json!("!workers")), ("latency_ms", json!(42))];
+
+        let line: String = with_fields(fields, || {
+            json_record(
+                &Record::builder()
+                    .args(format_args!("Command {}\nprocessed", "!workers"))
+                    .level(Level::Info)
+                    .target("braiinspool_matrix_bot::bot")
+                    .module_path(Some("braiinspool_matrix_bot::bot"))
+                    .build(),
+                timestamp,
+            )
+        });
+
+        assert!(!line.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<Value>(&line).unwrap(),
+            json!({
+                "timestamp": "2022-04-15T10:30:00.250Z",
+                "level": "INFO",
+                "target": "braiinspool_matrix_bot::bot",
+                "module": "braiinspool_matrix_bot::bot",
+                "message": "Command !workers\nprocessed",
+                "command": "!workers",
+                "latency_ms": 42,
+            })
+        );
+
+        // Fields are attached only inside `with_fields`
+        let line: String = json_record(
+            &Record::builder().args(format_args!("Started")).build(),
+            timestamp,
+        );
+        assert!(!line.contains("command"));
+    }
+
+    /// Log records written to memory
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mask_secrets() {
+        let output = Output::default();
+        let logger = BotLogger {
+            inner: Builder::new()
+                .filter_level(LevelFilter::Debug)
+                .target(Target::Pipe(Box::new(output.clone())))
+                .build(),
+            from_env: true,
+        };
+
+        let user = crate::db::User {
+            room_id: String::from("!room:example.com"),
+            token: Secret::masked("fake-braiinspool-token"),
+            created_at: 0,
+        };
+
+        logger.log(
+            &Record::builder()
+                .args(format_args!(
+                    "Polling {:?} with token {}",
+                    user,
+                    user.token.expose()
+                ))
+                .level(Level::Debug)
+                .target("braiinspool_matrix_bot::bot::poller")
+                .build(),
+        );
+
+        let output: String = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("token: ***"), "{}", output);
+        assert!(output.contains("with token ***"), "{}", output);
+        assert!(!output.contains("fake-braiinspool-token"), "{}", output);
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = TempDir::new().unwrap();
+        let path: PathBuf = dir.path().join("logs/bot.log");
+        let file = Arc::new(RotatingFile::open(&path, 100, 2).unwrap());
+
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let file = file.clone();
+                thread::spawn(move || {
+                    for record in 0..10 {
+                        let line: String =
+                            format!("thread {} record {:02} xxxxxxxxxx\n", thread, record);
+                        file.write_record(line.as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(!file.rotated_path(3).exists());
+        for path in [path.clone(), file.rotated_path(1), file.rotated_path(2)] {
+            let content: String = fs::read_to_string(&path).unwrap();
+            assert!(!content.is_empty());
+            assert!(content.len() <= 100);
+            // Only whole records
+            assert!(content.ends_with('\n'));
+            assert!(content.lines().all(|line| line.len() == 29));
+        }
+
+        // Reopened after a restart: appended, then rotated without keeping the old file
+        let size: u64 = fs::metadata(&path).unwrap().len();
+        let rotated: String = fs::read_to_string(file.rotated_path(1)).unwrap();
+        let file = RotatingFile::open(&path, size + 5, 0).unwrap();
+        file.write_record(b"restarted\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "restarted\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(1)).unwrap(), rotated);
+    }
 }
@@ -7,22 +7,451 @@ extern crate lazy_static;
 extern crate serde;
 
 mod bot;
+mod check;
 mod config;
 mod db;
+mod health;
+mod i18n;
 mod logger;
+mod metrics;
+mod pool;
+mod secret;
 mod util;
 
 use bot::Bot;
-use config::Config;
-use db::DBStore;
+use clap::Parser;
+use config::{Args, Command, Config, ConfigHandle};
+use db::{Backup, DBStore, ImportError, OpenError, UserSettings, SCHEMA_VERSION};
+use pool::{BraiinsPool, LimitedPool};
+
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 lazy_static! {
-    pub static ref CONFIG: Config = Config::from_args();
-    pub static ref STORE: DBStore = DBStore::open(&CONFIG.matrix.db_path).unwrap();
+    pub static ref START: Instant = Instant::now();
+}
+
+fn open_store(config: &Config) -> DBStore {
+    for path in [&config.matrix.db_path, &config.matrix.state_path] {
+        if let Err(error) = std::fs::create_dir_all(path) {
+            eprintln!(
+                "Impossible to create directory {:?} ({}): check `main_path` in config file",
+                path, error
+            );
+            process::exit(1);
+        }
+    }
+
+    let encryption_key: Option<&[u8; 32]> = config.db_encryption_key.as_ref().map(|key| &key.0);
+
+    match DBStore::open(&config.matrix.db_path, encryption_key) {
+        Ok(store) => store,
+        Err(OpenError::Store(error)) => {
+            eprintln!(
+                "Impossible to open database at {:?} ({:?}): is another instance of the bot running?",
+                config.matrix.db_path, error
+            );
+            process::exit(1);
+        }
+        Err(error) => exit_open_error(config, error),
+    }
+}
+
+/// Open the database without writing to it, for the subcommands that also work
+/// while the bot is running
+fn open_store_read_only(config: &Config) -> DBStore {
+    let encryption_key: Option<&[u8; 32]> = config.db_encryption_key.as_ref().map(|key| &key.0);
+
+    match DBStore::open_read_only(&config.matrix.db_path, encryption_key) {
+        Ok(store) => store,
+        Err(OpenError::Store(error)) => {
+            eprintln!(
+                "Impossible to open database at {:?} read-only ({:?}): check `main_path` in config file, and start the bot once if it was upgraded",
+                config.matrix.db_path, error
+            );
+            process::exit(1);
+        }
+        Err(error) => exit_open_error(config, error),
+    }
+}
+
+fn exit_open_error(config: &Config, error: OpenError) -> ! {
+    match error {
+        OpenError::Store(error) => {
+            eprintln!(
+                "Impossible to open database at {:?} ({:?})",
+                config.matrix.db_path, error
+            );
+        }
+        OpenError::UnsupportedSchemaVersion(version) => {
+            eprintln!(
+                "Database at {:?} has schema version {} but this version of the bot supports up to {}: please upgrade the bot",
+                config.matrix.db_path, version, SCHEMA_VERSION
+            );
+        }
+        OpenError::Migration(version, error) => {
+            eprintln!(
+                "Impossible to migrate database at {:?} to schema version {} ({:?}): consider restoring a backup",
+                config.matrix.db_path, version, error
+            );
+        }
+        OpenError::MigrationRequired(version) => {
+            eprintln!(
+                "Database at {:?} has schema version {} and must be migrated to {}: start the bot once with this version",
+                config.matrix.db_path, version, SCHEMA_VERSION
+            );
+        }
+        OpenError::EncryptionKeyRequired => {
+            eprintln!(
+                "Tokens in database at {:?} are encrypted: set `db_encryption_key` or `db_encryption_key_file` in config file",
+                config.matrix.db_path
+            );
+        }
+        OpenError::InvalidEncryptionKey => {
+            eprintln!(
+                "Impossible to decrypt tokens in database at {:?}: wrong `db_encryption_key`",
+                config.matrix.db_path
+            );
+        }
+    }
+    process::exit(1);
+}
+
+fn print_summary(backup: &Backup) {
+    for (name, count) in backup.summary() {
+        println!("{}: {}", name, count);
+    }
+}
+
+fn init(path: PathBuf, force: bool) {
+    match config::write_template(&path, force) {
+        Ok(()) => println!("Config template written to {:?}", path),
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+            eprintln!("{:?} already exists, pass --force to overwrite it", path);
+            process::exit(1);
+        }
+        Err(error) => {
+            eprintln!(
+                "Impossible to write config template to {:?}: {}",
+                path, error
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn export(store: &DBStore, output: &Path) {
+    let backup: Backup = store.export();
+
+    let json: String = match serde_json::to_string_pretty(&backup) {
+        Ok(json) => json,
+        Err(error) => {
+            eprintln!("Impossible to serialize backup: {}", error);
+            process::exit(1);
+        }
+    };
+
+    if let Err(error) = std::fs::write(output, json) {
+        eprintln!("Impossible to write backup to {:?}: {}", output, error);
+        process::exit(1);
+    }
+
+    println!("Database exported to {:?}", output);
+    print_summary(&backup);
+}
+
+fn import(store: &DBStore, input: &Path, force: bool) {
+    let backup: Backup = match std::fs::read_to_string(input)
+        .map_err(|error| error.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|error| error.to_string()))
+    {
+        Ok(backup) => backup,
+        Err(error) => {
+            eprintln!("Impossible to read backup at {:?}: {}", input, error);
+            process::exit(1);
+        }
+    };
+
+    match store.import(&backup, force) {
+        Ok(()) => {
+            println!("Backup imported from {:?}", input);
+            print_summary(&backup);
+        }
+        Err(ImportError::UnsupportedVersion(version)) => {
+            eprintln!("Unsupported backup version {}", version);
+            process::exit(1);
+        }
+        Err(ImportError::SchemaMismatch(version)) => {
+            eprintln!(
+                "Backup has schema version {} but the database has {}: use the same version of the bot used for the export",
+                version, SCHEMA_VERSION
+            );
+            process::exit(1);
+        }
+        Err(ImportError::EncryptionMismatch) => {
+            eprintln!("Token encryption of the backup and of the database differ: set the same `db_encryption_key` used for the export");
+            process::exit(1);
+        }
+        Err(ImportError::ExistingKeys(count)) => {
+            eprintln!(
+                "{} records of the backup already exist in the database: use --force to overwrite them",
+                count
+            );
+            process::exit(1);
+        }
+        Err(ImportError::Db(error)) => {
+            eprintln!("Impossible to import backup: {:?}", error);
+            process::exit(1);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserRow {
+    user_id: String,
+    room_id: String,
+    /// Last 4 chars only
+    token_suffix: String,
+    created_at: i64,
+    settings: UserSettings,
+}
+
+fn list_users(store: &DBStore, json: bool) {
+    let rows: Vec<UserRow> = store
+        .iter_users()
+        .map(|(user_id, user)| {
+            let chars: Vec<char> = user.token.expose().chars().collect();
+            UserRow {
+                settings: store.get_settings(&user_id),
+                user_id,
+                room_id: user.room_id,
+                token_suffix: chars[chars.len().saturating_sub(4)..].iter().collect(),
+                created_at: user.created_at,
+            }
+        })
+        .collect();
+
+    if json {
+        match serde_json::to_string_pretty(&rows) {
+            Ok(json) => println!("{}", json),
+            Err(error) => {
+                eprintln!("Impossible to serialize users: {}", error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let table: Vec<[String; 4]> = rows
+        .iter()
+        .map(|row| {
+            [
+                row.user_id.clone(),
+                row.room_id.clone(),
+                format!("...{}", row.token_suffix),
+                changed_settings(&row.settings),
+            ]
+        })
+        .collect();
+
+    let header = [
+        String::from("USER"),
+        String::from("ROOM"),
+        String::from("TOKEN"),
+        String::from("SETTINGS"),
+    ];
+    let mut widths = [0usize; 4];
+    for line in std::iter::once(&header).chain(table.iter()) {
+        for (width, cell) in widths.iter_mut().zip(line.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for line in std::iter::once(&header).chain(table.iter()) {
+        let cells: Vec<String> = line
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+
+    println!("{} users", rows.len());
+}
+
+/// Settings that differ from the defaults, as `key=value`
+fn changed_settings(settings: &UserSettings) -> String {
+    let current = serde_json::to_value(settings).unwrap_or_default();
+    let default = serde_json::to_value(UserSettings::default()).unwrap_or_default();
+
+    match (current.as_object(), default.as_object()) {
+        (Some(current), Some(default)) => current
+            .iter()
+            .filter(|(key, value)| default.get(key.as_str()) != Some(value))
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join(" "),
+        _ => String::new(),
+    }
+}
+
+async fn logout(config: &Config, store: &DBStore, purge_state: bool) {
+    match bot::logout(config, store).await {
+        Ok(Some(device_id)) if purge_state => {
+            println!("Device {} logged out", device_id)
+        }
+        Ok(Some(device_id)) => println!(
+            "Device {} logged out. Delete the state directory {:?} (or run again with --purge-state) before the next start: a new device will be created",
+            device_id, config.matrix.state_path
+        ),
+        Ok(None) => println!("No saved session for {}", config.matrix.user_id),
+        Err(error) => {
+            eprintln!("Impossible to logout: {:?}", error);
+            process::exit(1);
+        }
+    }
+
+    // Only once the session is gone: the keys are useless without it
+    if purge_state {
+        match std::fs::remove_dir_all(&config.matrix.state_path) {
+            Ok(()) => println!("State directory {:?} deleted", config.matrix.state_path),
+            Err(error) => {
+                eprintln!(
+                    "Impossible to delete the state directory {:?}: {}",
+                    config.matrix.state_path, error
+                );
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Reload the config file on SIGHUP, see [`ConfigHandle::reload`]
+#[cfg(unix)]
+fn reload_on_sighup(config: ConfigHandle) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(error) => {
+            log::error!("Impossible to listen for SIGHUP: {}", error);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("Reloading config file at {:?}", config.path());
+
+            let (changes, warnings) = match config.reload() {
+                Ok(reloaded) => reloaded,
+                Err(error) => {
+                    log::error!(
+                        "Impossible to reload config file, keeping the current config: {}",
+                        error
+                    );
+                    continue;
+                }
+            };
+
+            for warning in warnings.iter() {
+                log::warn!("Config file: {}", warning);
+            }
+
+            let current: Arc<Config> = config.get();
+            if changes.applied.contains(&"log_level")
+                && !logger::set_level(current.log_level, &current.log_modules)
+            {
+                log::warn!("`log_level` not applied: RUST_LOG is set");
+            }
+
+            if changes.applied.is_empty() {
+                log::info!("Config reloaded, no changes applied");
+            } else {
+                log::info!("Config reloaded, applied: {}", changes.applied.join(", "));
+            }
+
+            if !changes.ignored.is_empty() {
+                log::warn!(
+                    "Changed but not applied until restart: {}",
+                    changes.ignored.join(", ")
+                );
+            }
+        }
+    });
 }
 
 #[tokio::main]
 async fn main() {
-    logger::init();
-    Bot::run().await.unwrap();
+    lazy_static::initialize(&START);
+
+    let args: Args = Args::parse();
+
+    if let Some(path) = &args.init {
+        return init(
+            path.clone().unwrap_or_else(config::default_config_file),
+            args.force,
+        );
+    }
+
+    let config = ConfigHandle::new(Config::from_args(&args), args.config_file_path());
+    let current: Arc<Config> = config.get();
+    logger::init(&current);
+    log::info!("Config: {}", current.summary());
+
+    // Dispatched before building the Matrix client of the bot
+    match args.command() {
+        Command::Run => (),
+        Command::Check { tokens } => {
+            let ok: bool = check::run(&current, tokens).await;
+            process::exit(if ok { 0 } else { 1 });
+        }
+        Command::Export { output } => return export(&open_store_read_only(&current), &output),
+        Command::Import { input, force } => return import(&open_store(&current), &input, force),
+        Command::Logout { purge_state } => {
+            return logout(&current, &open_store(&current), purge_state).await
+        }
+        Command::ListUsers { json } => return list_users(&open_store_read_only(&current), json),
+    }
+
+    let store: DBStore = open_store(&current);
+
+    health::spawn(config.clone(), store.clone());
+
+    #[cfg(unix)]
+    reload_on_sighup(config.clone());
+
+    let pool = Arc::new(LimitedPool::new(
+        Arc::new(BraiinsPool::new(current.braiinspool.proxy_url())),
+        current.braiinspool.max_concurrent_api_calls,
+        Duration::from_secs(current.braiinspool.api_timeout_secs),
+        Duration::from_secs(current.braiinspool.rate_limit_max_wait_secs),
+    ));
+    let bot = Bot::new(config, store, pool);
+
+    match bot.run().await {
+        Ok(()) => (),
+        Err(bot::Error::PasswordRequired) => {
+            log::error!("No saved session and no password to login: set `matrix.password` or `matrix.password_file`");
+            process::exit(1);
+        }
+        Err(bot::Error::CryptoStoreNotEncrypted) => {
+            log::error!("The crypto store at {:?} was created without passphrase and can't be encrypted afterwards: remove `matrix.crypto_store_passphrase`, or delete the state directory and the saved session to start with a new device (to verify again)", current.matrix.state_path);
+            process::exit(1);
+        }
+        Err(bot::Error::CryptoStorePassphraseRequired) => {
+            log::error!("The crypto store at {:?} is encrypted: set the `matrix.crypto_store_passphrase` used to create it", current.matrix.state_path);
+            process::exit(1);
+        }
+        Err(bot::Error::CryptoStoreWrongPassphrase(error)) => {
+            log::error!("Impossible to open the crypto store at {:?} ({:?}): check `matrix.crypto_store_passphrase`, it can't be changed after the store is created", current.matrix.state_path, error);
+            process::exit(1);
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,48 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Process-wide operational counters, reported by `!stats`
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+static COMMANDS: AtomicU64 = AtomicU64::new(0);
+static API_CALLS: AtomicU64 = AtomicU64::new(0);
+static API_LATENCY_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_command() {
+    COMMANDS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_api_call(latency: Duration) {
+    API_CALLS.fetch_add(1, Ordering::Relaxed);
+    API_LATENCY_MS.fetch_add(latency.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn commands() -> u64 {
+    COMMANDS.load(Ordering::Relaxed)
+}
+
+pub fn api_calls() -> u64 {
+    API_CALLS.load(Ordering::Relaxed)
+}
+
+/// Average latency of the BraiinsPool API calls, `None` before the first call
+pub fn avg_api_latency_ms() -> Option<u64> {
+    average(API_LATENCY_MS.load(Ordering::Relaxed), api_calls())
+}
+
+fn average(total: u64, count: u64) -> Option<u64> {
+    total.checked_div(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_average() {
+        assert_eq!(average(0, 0), None);
+        assert_eq!(average(300, 4), Some(75));
+    }
+}
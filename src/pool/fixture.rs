@@ -0,0 +1,48 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! [`PoolApi`] test double returning canned responses from `fixtures/`
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use braiinspool::model::{DailyReward, PoolStats, UserProfile, Worker};
+
+use super::{Error, PoolApi, PoolClientFactory};
+
+const USER_PROFILE: &str = include_str!("fixtures/user_profile.json");
+const WORKERS: &str = include_str!("fixtures/workers.json");
+const DAILY_REWARDS: &str = include_str!("fixtures/daily_rewards.json");
+const POOL_STATS: &str = include_str!("fixtures/pool_stats.json");
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixturePool;
+
+#[async_trait]
+impl PoolApi for FixturePool {
+    async fn user_profile(&self) -> Result<UserProfile, Error> {
+        Ok(serde_json::from_str(USER_PROFILE).expect("invalid user_profile fixture"))
+    }
+
+    async fn workers(&self) -> Result<HashMap<String, Worker>, Error> {
+        Ok(serde_json::from_str(WORKERS).expect("invalid workers fixture"))
+    }
+
+    async fn daily_rewards(&self) -> Result<Vec<DailyReward>, Error> {
+        Ok(serde_json::from_str(DAILY_REWARDS).expect("invalid daily_rewards fixture"))
+    }
+
+    async fn pool_stats(&self) -> Result<PoolStats, Error> {
+        Ok(serde_json::from_str(POOL_STATS).expect("invalid pool_stats fixture"))
+    }
+
+    async fn check_tor_connection(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+}
+
+impl PoolClientFactory for FixturePool {
+    fn client(&self, _token: &str) -> Result<Box<dyn PoolApi>, Error> {
+        Ok(Box::new(FixturePool))
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Cap the number of in-flight BraiinsPool API calls and their duration
+//!
+//! All the clients built by [`LimitedPool`] share the same semaphore, so the
+//! limit holds across users, the background poller and the commands.
+//!
+//! They also share the cooldowns of the rate limited tokens: once BraiinsPool
+//! answers 429, the calls with that token fail without reaching it until the
+//! `Retry-After` expires.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use braiinspool::model::{DailyReward, PoolStats, UserProfile, Worker};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::{Error, PoolApi, PoolClientFactory};
+use crate::metrics;
+
+/// End of the cooldown of each rate limited token
+type Cooldowns = Arc<Mutex<HashMap<String, Instant>>>;
+
+pub struct LimitedPool {
+    inner: Arc<dyn PoolClientFactory>,
+    semaphore: Arc<Semaphore>,
+    timeout: Duration,
+    max_wait: Duration,
+    cooldowns: Cooldowns,
+}
+
+impl LimitedPool {
+    /// A `Retry-After` up to `max_wait` is waited and the call retried once
+    pub fn new(
+        inner: Arc<dyn PoolClientFactory>,
+        max_concurrent_calls: usize,
+        timeout: Duration,
+        max_wait: Duration,
+    ) -> Self {
+        Self {
+            inner,
+            semaphore: Arc::new(Semaphore::new(max_concurrent_calls)),
+            timeout,
+            max_wait,
+            cooldowns: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl PoolClientFactory for LimitedPool {
+    fn client(&self, token: &str) -> Result<Box<dyn PoolApi>, Error> {
+        Ok(Box::new(LimitedClient {
+            inner: self.inner.client(token)?,
+            token: token.to_string(),
+            semaphore: self.semaphore.clone(),
+            timeout: self.timeout,
+            max_wait: self.max_wait,
+            cooldowns: self.cooldowns.clone(),
+        }))
+    }
+}
+
+struct LimitedClient {
+    inner: Box<dyn PoolApi>,
+    token: String,
+    semaphore: Arc<Semaphore>,
+    timeout: Duration,
+    max_wait: Duration,
+    cooldowns: Cooldowns,
+}
+
+impl LimitedClient {
+    async fn permit(&self) -> SemaphorePermit<'_> {
+        // The semaphore is never closed
+        self.semaphore
+            .acquire()
+            .await
+            .expect("API calls semaphore closed")
+    }
+
+    /// Remaining cooldown of the token, if rate limited
+    fn cooldown(&self) -> Option<Duration> {
+        let cooldowns = self.cooldowns.lock().expect("cooldowns lock poisoned");
+        let until: Instant = *cooldowns.get(&self.token)?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn set_cooldown(&self, retry_after: Duration) {
+        let now = Instant::now();
+        let mut cooldowns = self.cooldowns.lock().expect("cooldowns lock poisoned");
+        cooldowns.retain(|_, until| *until > now);
+        cooldowns.insert(self.token.clone(), now + retry_after);
+    }
+
+    /// Run an API call within the limits. When rate limited, a short `Retry-After`
+    /// is waited (without holding the permit) and the call retried once; a longer
+    /// one starts the cooldown of the token.
+    async fn call<T, F, Fut>(&self, call: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        if let Some(remaining) = self.cooldown() {
+            return Err(Error::RateLimited(remaining));
+        }
+
+        let result = {
+            let _permit = self.permit().await;
+            timed(self.timeout, call()).await
+        };
+
+        let result = match result {
+            Err(Error::RateLimited(retry_after)) if retry_after <= self.max_wait => {
+                log::warn!(
+                    "BraiinsPool rate limit reached, retrying in {}s",
+                    retry_after.as_secs()
+                );
+                tokio::time::sleep(retry_after).await;
+                let _permit = self.permit().await;
+                timed(self.timeout, call()).await
+            }
+            result => result,
+        };
+
+        if let Err(Error::RateLimited(retry_after)) = &result {
+            log::warn!(
+                "BraiinsPool rate limit reached, token not used for {}s",
+                retry_after.as_secs()
+            );
+            self.set_cooldown(*retry_after);
+        }
+
+        result
+    }
+}
+
+/// Run an API call, recording its latency (waiting time for the permit excluded).
+/// The call is dropped if it doesn't complete within `timeout`.
+async fn timed<T>(
+    timeout: Duration,
+    call: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let start = Instant::now();
+    let result = tokio::time::timeout(timeout, call).await;
+    metrics::record_api_call(start.elapsed());
+    result.unwrap_or(Err(Error::Timeout))
+}
+
+#[async_trait]
+impl PoolApi for LimitedClient {
+    async fn user_profile(&self) -> Result<UserProfile, Error> {
+        self.call(|| self.inner.user_profile()).await
+    }
+
+    async fn workers(&self) -> Result<HashMap<String, Worker>, Error> {
+        self.call(|| self.inner.workers()).await
+    }
+
+    async fn daily_rewards(&self) -> Result<Vec<DailyReward>, Error> {
+        self.call(|| self.inner.daily_rewards()).await
+    }
+
+    async fn pool_stats(&self) -> Result<PoolStats, Error> {
+        self.call(|| self.inner.pool_stats()).await
+    }
+
+    async fn check_tor_connection(&self) -> Result<bool, Error> {
+        self.call(|| self.inner.check_tor_connection()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::pool::fixture::FixturePool;
+
+    /// Track the max number of concurrent calls
+    #[derive(Default)]
+    struct SlowPool {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl PoolApi for Arc<SlowPool> {
+        async fn user_profile(&self) -> Result<UserProfile, Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            FixturePool.user_profile().await
+        }
+
+        async fn workers(&self) -> Result<HashMap<String, Worker>, Error> {
+            FixturePool.workers().await
+        }
+
+        async fn daily_rewards(&self) -> Result<Vec<DailyReward>, Error> {
+            FixturePool.daily_rewards().await
+        }
+
+        async fn pool_stats(&self) -> Result<PoolStats, Error> {
+            FixturePool.pool_stats().await
+        }
+
+        async fn check_tor_connection(&self) -> Result<bool, Error> {
+            Ok(true)
+        }
+    }
+
+    impl PoolClientFactory for Arc<SlowPool> {
+        fn client(&self, _token: &str) -> Result<Box<dyn PoolApi>, Error> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        let slow = Arc::new(SlowPool::default());
+        let pool = Arc::new(LimitedPool::new(
+            Arc::new(slow.clone()),
+            3,
+            Duration::from_secs(30),
+            Duration::ZERO,
+        ));
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let client = pool.client("token").unwrap();
+                    client.user_profile().await.unwrap();
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(slow.max_in_flight.load(Ordering::SeqCst), 3);
+        assert_eq!(slow.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let slow = Arc::new(SlowPool::default());
+        let pool = LimitedPool::new(
+            Arc::new(slow.clone()),
+            3,
+            Duration::from_millis(1),
+            Duration::ZERO,
+        );
+        let client = pool.client("token").unwrap();
+
+        assert!(matches!(client.user_profile().await, Err(Error::Timeout)));
+        assert!(client.workers().await.is_ok());
+    }
+
+    /// Answer 429 to the first call
+    struct RateLimitedPool {
+        calls: AtomicUsize,
+        retry_after: Duration,
+    }
+
+    #[async_trait]
+    impl PoolApi for Arc<RateLimitedPool> {
+        async fn user_profile(&self) -> Result<UserProfile, Error> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(Error::RateLimited(self.retry_after));
+            }
+            FixturePool.user_profile().await
+        }
+
+        async fn workers(&self) -> Result<HashMap<String, Worker>, Error> {
+            FixturePool.workers().await
+        }
+
+        async fn daily_rewards(&self) -> Result<Vec<DailyReward>, Error> {
+            FixturePool.daily_rewards().await
+        }
+
+        async fn pool_stats(&self) -> Result<PoolStats, Error> {
+            FixturePool.pool_stats().await
+        }
+
+        async fn check_tor_connection(&self) -> Result<bool, Error> {
+            Ok(true)
+        }
+    }
+
+    impl PoolClientFactory for Arc<RateLimitedPool> {
+        fn client(&self, _token: &str) -> Result<Box<dyn PoolApi>, Error> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        // Short Retry-After: waited and retried
+        let limited = Arc::new(RateLimitedPool {
+            calls: AtomicUsize::new(0),
+            retry_after: Duration::from_millis(10),
+        });
+        let pool = LimitedPool::new(
+            Arc::new(limited.clone()),
+            3,
+            Duration::from_secs(30),
+            Duration::from_secs(5),
+        );
+        assert!(pool.client("token").unwrap().user_profile().await.is_ok());
+        assert_eq!(limited.calls.load(Ordering::SeqCst), 2);
+
+        // Long Retry-After: the token is in cooldown, also for the other clients
+        let limited = Arc::new(RateLimitedPool {
+            calls: AtomicUsize::new(0),
+            retry_after: Duration::from_secs(60),
+        });
+        let pool = LimitedPool::new(
+            Arc::new(limited.clone()),
+            3,
+            Duration::from_secs(30),
+            Duration::from_secs(5),
+        );
+        assert!(matches!(
+            pool.client("token").unwrap().user_profile().await,
+            Err(Error::RateLimited(retry_after)) if retry_after == Duration::from_secs(60)
+        ));
+        assert!(matches!(
+            pool.client("token").unwrap().user_profile().await,
+            Err(Error::RateLimited(retry_after)) if retry_after <= Duration::from_secs(60)
+        ));
+        assert_eq!(limited.calls.load(Ordering::SeqCst), 1);
+
+        // The other tokens are not affected
+        assert!(pool.client("other").unwrap().user_profile().await.is_ok());
+        assert_eq!(limited.calls.load(Ordering::SeqCst), 2);
+    }
+}
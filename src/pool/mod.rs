@@ -0,0 +1,249 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use braiinspool::model::{DailyReward, PoolStats, UserProfile, Worker};
+use braiinspool::Client as BraiinsPoolClient;
+use chrono::{DateTime, Utc};
+
+#[cfg(test)]
+pub mod fixture;
+mod limit;
+
+pub use self::limit::LimitedPool;
+
+/// Delay after a 429 without (readable) `Retry-After`
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// Cap of the `Retry-After`, a wrong one must not disable a token for days
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+pub enum Error {
+    Api(braiinspool::client::Error),
+    /// HTTP 5xx or maintenance page: the pool is down, not the request.
+    /// Keeps the description of the client error, for the logs.
+    Unavailable(String),
+    /// HTTP 429: the token can be used again after the delay
+    RateLimited(Duration),
+    /// No response within `braiinspool.api_timeout_secs`
+    Timeout,
+}
+
+#[async_trait]
+pub trait PoolApi: Send + Sync {
+    async fn user_profile(&self) -> Result<UserProfile, Error>;
+    async fn workers(&self) -> Result<HashMap<String, Worker>, Error>;
+    async fn daily_rewards(&self) -> Result<Vec<DailyReward>, Error>;
+    async fn pool_stats(&self) -> Result<PoolStats, Error>;
+    async fn check_tor_connection(&self) -> Result<bool, Error>;
+}
+
+/// Check if the request was rejected because of an invalid or expired token.
+/// The client doesn't expose the HTTP status code, so look for it in the error.
+pub fn is_auth_error(error: &Error) -> bool {
+    let error: String = match error {
+        Error::Api(error) => format!("{:?}", error),
+        Error::Unavailable(_) | Error::RateLimited(_) | Error::Timeout => return false,
+    };
+    ["401", "403", "Unauthorized", "Forbidden"]
+        .iter()
+        .any(|pattern| error.contains(pattern))
+}
+
+/// Build a [`PoolApi`] client for a token
+pub trait PoolClientFactory: Send + Sync {
+    fn client(&self, token: &str) -> Result<Box<dyn PoolApi>, Error>;
+}
+
+pub struct BraiinsPool {
+    proxy: Option<String>,
+}
+
+impl BraiinsPool {
+    pub fn new(proxy: Option<String>) -> Self {
+        Self { proxy }
+    }
+}
+
+impl PoolClientFactory for BraiinsPool {
+    fn client(&self, token: &str) -> Result<Box<dyn PoolApi>, Error> {
+        Ok(Box::new(BraiinsPoolClient::new(
+            token,
+            self.proxy.as_deref(),
+        )?))
+    }
+}
+
+#[async_trait]
+impl PoolApi for BraiinsPoolClient {
+    async fn user_profile(&self) -> Result<UserProfile, Error> {
+        Ok(BraiinsPoolClient::user_profile(self).await?)
+    }
+
+    async fn workers(&self) -> Result<HashMap<String, Worker>, Error> {
+        Ok(BraiinsPoolClient::workers(self).await?)
+    }
+
+    async fn daily_rewards(&self) -> Result<Vec<DailyReward>, Error> {
+        Ok(BraiinsPoolClient::daily_rewards(self).await?)
+    }
+
+    async fn pool_stats(&self) -> Result<PoolStats, Error> {
+        Ok(BraiinsPoolClient::pool_stats(self).await?)
+    }
+
+    async fn check_tor_connection(&self) -> Result<bool, Error> {
+        Ok(BraiinsPoolClient::check_tor_connection(self).await?)
+    }
+}
+
+/// Check if the client error is a server error (HTTP 5xx) or a maintenance page.
+/// Like for [`is_auth_error`], the status code is only in the description.
+fn is_unavailable(error: &str) -> bool {
+    let error: String = error.to_lowercase();
+    [
+        "status(5",
+        "internal server error",
+        "bad gateway",
+        "service unavailable",
+        "gateway timeout",
+        "maintenance",
+    ]
+    .iter()
+    .any(|pattern| error.contains(pattern))
+}
+
+/// Check if the client error is a HTTP 429 (too many requests)
+fn is_rate_limited(error: &str) -> bool {
+    let error: String = error.to_lowercase();
+    ["status(429", "status: 429", "too many requests"]
+        .iter()
+        .any(|pattern| error.contains(pattern))
+}
+
+/// Find the `Retry-After` header in the description of the error, in seconds or as HTTP date.
+/// The client doesn't always include the headers: the caller falls back to a default delay.
+fn parse_retry_after(error: &str, now: DateTime<Utc>) -> Option<Duration> {
+    const HEADER: &str = "retry-after";
+
+    // ASCII lowercase keeps the byte offsets of `error`
+    let start: usize = error.to_ascii_lowercase().find(HEADER)? + HEADER.len();
+    let value: &str = error[start..]
+        .trim_start_matches(|c: char| c == '"' || c == '\\' || c == ':' || c == '=' || c == ' ');
+
+    let digits: usize = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let retry_after: Duration = if digits > 0 {
+        Duration::from_secs(value[..digits].parse().ok()?)
+    } else {
+        // ex. `Wed, 21 Oct 2015 07:28:00 GMT`
+        let end: usize = value.find("GMT")? + "GMT".len();
+        let date = DateTime::parse_from_rfc2822(&value[..end]).ok()?;
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default()
+    };
+
+    Some(retry_after.min(MAX_RETRY_AFTER))
+}
+
+impl From<braiinspool::client::Error> for Error {
+    fn from(err: braiinspool::client::Error) -> Self {
+        let description: String = format!("{:?}", err);
+        if is_rate_limited(&description) {
+            Error::RateLimited(
+                parse_retry_after(&description, Utc::now()).unwrap_or(DEFAULT_RETRY_AFTER),
+            )
+        } else if is_unavailable(&description) {
+            Error::Unavailable(description)
+        } else {
+            Error::Api(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_unavailable() {
+        // Description of a 503 response, as built by reqwest
+        assert!(is_unavailable(
+            "Reqwest(reqwest::Error { kind: Status(503), url: Url { scheme: \"https\", host: Some(Domain(\"pool.braiins.com\")), path: \"/stats/json/btc/\" } })"
+        ));
+        assert!(is_unavailable(
+            "Reqwest(reqwest::Error { kind: Status(502) })"
+        ));
+        assert!(is_unavailable(
+            "Json(\"<html><title>Braiins Pool - Maintenance</title></html>\")"
+        ));
+        assert!(is_unavailable(
+            "UnexpectedResponse(\"503 Service Unavailable\")"
+        ));
+
+        assert!(!is_unavailable(
+            "Reqwest(reqwest::Error { kind: Status(401) })"
+        ));
+        assert!(!is_unavailable(
+            "Reqwest(reqwest::Error { kind: Status(404) })"
+        ));
+        assert!(!is_unavailable(
+            "Reqwest(reqwest::Error { kind: Request, source: ConnectError })"
+        ));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2022-03-01T10:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let response = "HTTP/1.1 429 Too Many Requests\r\nContent-Type: application/json\r\nRetry-After: 120\r\n\r\n{\"error\": \"rate limit exceeded\"}";
+        assert!(is_rate_limited(response));
+        assert!(!is_unavailable(response));
+        assert_eq!(
+            parse_retry_after(response, now),
+            Some(Duration::from_secs(120))
+        );
+
+        // Header map in the description of the error
+        assert_eq!(
+            parse_retry_after(
+                "UnexpectedResponse { status: 429, headers: {\"retry-after\": \"30\"} }",
+                now
+            ),
+            Some(Duration::from_secs(30))
+        );
+
+        // HTTP date
+        assert_eq!(
+            parse_retry_after("Retry-After: Tue, 01 Mar 2022 10:05:00 GMT", now),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            parse_retry_after("Retry-After: Tue, 01 Mar 2022 09:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+
+        assert_eq!(
+            parse_retry_after("Retry-After: 864000", now),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(
+            parse_retry_after("Reqwest(reqwest::Error { kind: Status(429) })", now),
+            None
+        );
+        assert_eq!(parse_retry_after("Retry-After: soon", now), None);
+        assert!(is_rate_limited(
+            "Reqwest(reqwest::Error { kind: Status(429) })"
+        ));
+        assert!(!is_rate_limited(
+            "Reqwest(reqwest::Error { kind: Status(503) })"
+        ));
+    }
+}
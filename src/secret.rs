@@ -0,0 +1,140 @@
+// Copyright (c) 2021-2022 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Values that must never reach the log
+//!
+//! A [`Secret`] prints `***` with `Debug` and `Display`. The plaintext secrets in use
+//! are also registered, so that the logger masks them when they end up in a message by
+//! another path, like the error of a request or the body of a command. Ciphertexts and
+//! the records read from the database are not: the registry only grows.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub const MASK: &str = "***";
+/// Shorter values would mask unrelated text
+const MIN_MASKED_LEN: usize = 8;
+
+lazy_static! {
+    static ref REGISTRY: ArcSwap<Registry> = ArcSwap::from_pointee(Registry::default());
+}
+
+#[derive(Clone, Default)]
+struct Registry {
+    known: HashSet<String>,
+    /// Longest first: a secret containing another one is replaced whole
+    values: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret<T>(T);
+
+impl Secret<String> {
+    pub fn new<S>(value: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self(value.into())
+    }
+
+    /// Plaintext secret, also masked in the log from now on
+    pub fn masked<S>(value: S) -> Self
+    where
+        S: Into<String>,
+    {
+        let value: String = value.into();
+        register(&value);
+        Self(value)
+    }
+}
+
+impl<T> Secret<T> {
+    /// The value, only to be passed where it's needed
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+/// Serialized as the bare value, the format of the records doesn't change
+impl<T: Serialize> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret<String> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// Mask `value` in the log from now on
+pub fn register(value: &str) {
+    if value.len() < MIN_MASKED_LEN || REGISTRY.load().known.contains(value) {
+        return;
+    }
+
+    REGISTRY.rcu(|registry| {
+        let mut registry: Registry = Registry::clone(registry);
+        if registry.known.insert(value.to_string()) {
+            registry.values.push(value.to_string());
+            registry.values.sort_by(|a, b| b.len().cmp(&a.len()));
+        }
+        Arc::new(registry)
+    });
+}
+
+/// Replace the registered values found in `text` with `***`
+pub fn mask(text: &str) -> Cow<'_, str> {
+    let mut masked: Cow<str> = Cow::Borrowed(text);
+    for value in REGISTRY.load().values.iter() {
+        if masked.contains(value.as_str()) {
+            masked = Cow::Owned(masked.replace(value.as_str(), MASK));
+        }
+    }
+    masked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let secret = Secret::masked("secret-password-1");
+        assert_eq!(secret.expose(), "secret-password-1");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(***)");
+        assert_eq!(format!("{}", secret), "***");
+
+        assert_eq!(
+            mask("login with secret-password-1 failed"),
+            "login with *** failed"
+        );
+        assert_eq!(mask("nothing to hide"), "nothing to hide");
+
+        // Too short to be masked safely
+        Secret::masked("abc");
+        assert_eq!(mask("abcdef"), "abcdef");
+
+        // Not registered, ex. a ciphertext
+        Secret::new("ciphertext-1");
+        assert_eq!(mask("ciphertext-1"), "ciphertext-1");
+    }
+}
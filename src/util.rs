@@ -1,16 +1,196 @@
 // Copyright (c) 2021-2022 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
 
-pub fn format_gh_to_th(amount: f64) -> String {
-    let mut number: String = format_number((amount / 1000.0) as usize);
-    number.push_str(" Th/s");
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Unit used to display BTC amounts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    #[default]
+    Sat,
+    Btc,
+}
+
+impl Unit {
+    pub const CODES: &'static str = "btc, sat";
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Sat => "sat",
+            Self::Btc => "btc",
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sat" | "sats" => Ok(Self::Sat),
+            "btc" => Ok(Self::Btc),
+            _ => Err(()),
+        }
+    }
+}
+
+const SATS_PER_BTC: u64 = 100_000_000;
+const HASHRATE_UNITS: [&str; 4] = ["Gh/s", "Th/s", "Ph/s", "Eh/s"];
+
+/// Format a hashrate in Gh/s with the largest unit that keeps the value above 1
+/// (ex. `142.35 Th/s`, `5.82 Eh/s`), with 2 decimals
+pub fn format_hashrate(gh: f64) -> String {
+    let mut value: f64 = gh;
+    let mut unit: usize = 0;
+
+    // Compare the rounded value, so 999.999 Gh/s is shown as 1.00 Th/s
+    while unit < HASHRATE_UNITS.len() - 1 && round_decimal(value, 2).1.len() > 3 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    format!("{} {}", format_decimal(value, 2), HASHRATE_UNITS[unit])
+}
+
+/// Round half-up (away from zero) to `precision` decimals. Work on the shortest decimal
+/// representation of the value, so 1.005 is rounded to 1.01 and not to 1.00 as its
+/// binary approximation (1.00499999...) would be.
+/// Return if negative, the integer digits and `precision` decimal digits.
+fn round_decimal(value: f64, precision: usize) -> (bool, String, String) {
+    let repr: String = value.abs().to_string();
+    let (int_part, frac_part) = repr.split_once('.').unwrap_or((&repr, ""));
+
+    let mut digits: Vec<u8> = int_part.bytes().collect();
+    digits.extend(
+        frac_part
+            .bytes()
+            .chain(std::iter::repeat(b'0'))
+            .take(precision),
+    );
+
+    if frac_part.as_bytes().get(precision).copied().unwrap_or(b'0') >= b'5' {
+        let mut carry: bool = true;
+        for digit in digits.iter_mut().rev() {
+            if *digit == b'9' {
+                *digit = b'0';
+            } else {
+                *digit += 1;
+                carry = false;
+                break;
+            }
+        }
+        if carry {
+            digits.insert(0, b'1');
+        }
+    }
+
+    let digits: String = String::from_utf8(digits).unwrap_or_default();
+    let (int_digits, frac_digits) = digits.split_at(digits.len() - precision);
+    let negative: bool = value < 0.0 && digits.bytes().any(|digit| digit != b'0');
+
+    (negative, int_digits.to_string(), frac_digits.to_string())
+}
+
+/// Format with thousands separators and `precision` decimals, rounding half-up
+pub fn format_decimal(value: f64, precision: usize) -> String {
+    if !value.is_finite() {
+        return value.to_string();
+    }
+
+    let (negative, int_digits, frac_digits) = round_decimal(value, precision);
+
+    let mut number = String::new();
+    if negative {
+        number.push('-');
+    }
+    number.push_str(&group_thousands(&int_digits));
+    if precision > 0 {
+        number.push('.');
+        number.push_str(&frac_digits);
+    }
     number
 }
 
+/// Convert BTC to sats, rounding to the nearest sat (negative amounts are 0,
+/// amounts beyond `u64::MAX` sats saturate)
+pub fn btc_to_sats(amount: f64) -> u64 {
+    if !amount.is_finite() || amount <= 0.0 {
+        return 0;
+    }
+
+    let (_, int_digits, frac_digits) = round_decimal(amount, 8);
+    format!("{}{}", int_digits, frac_digits)
+        .parse()
+        .unwrap_or(u64::MAX)
+}
+
 pub fn format_btc_to_sats(amount: f64) -> String {
-    format_sats((amount * 100_000_000.0) as u64)
+    format_sats(btc_to_sats(amount))
+}
+
+/// Format BTC amount with up to 8 decimals, trimming trailing zeros (ex. `0.001 BTC`).
+/// The amount is rounded to the sat and formatted from the integer, so there are no
+/// float formatting surprises.
+pub fn format_btc(amount: f64) -> String {
+    let sats: u64 = btc_to_sats(amount.abs());
+    let sign: &str = if amount < 0.0 && sats > 0 { "-" } else { "" };
+
+    let whole: String = format_number((sats / SATS_PER_BTC) as usize);
+    let fraction: String = format!("{:08}", sats % SATS_PER_BTC);
+    let fraction: &str = fraction.trim_end_matches('0');
+
+    if fraction.is_empty() {
+        format!("{}{} BTC", sign, whole)
+    } else {
+        format!("{}{}.{} BTC", sign, whole, fraction)
+    }
+}
+
+/// Format BTC amount in the unit chosen by the user. Use it everywhere money is displayed.
+pub fn format_amount(amount: f64, unit: Unit) -> String {
+    match unit {
+        Unit::Sat => format_btc_to_sats(amount),
+        Unit::Btc => format_btc(amount),
+    }
+}
+
+/// Format a ratio as a percentage (ex. `0.9733` -> `97.33%`). Broken values
+/// (negative, NaN, infinite) are shown as `n/a`.
+pub fn format_percent(value: f64, decimals: usize) -> String {
+    if !value.is_finite() || value < 0.0 {
+        return String::from("n/a");
+    }
+
+    format!("{}%", format_decimal(value * 100.0, decimals))
+}
+
+/// Indicator of a luck ratio: 🟢 at least 100%, 🟡 from 90%, 🔴 below.
+/// `None` for broken values (see [`format_percent`]).
+pub fn luck_indicator(luck: f64) -> Option<&'static str> {
+    if !luck.is_finite() || luck < 0.0 {
+        None
+    } else if luck >= 1.0 {
+        Some("🟢")
+    } else if luck >= 0.9 {
+        Some("🟡")
+    } else {
+        Some("🔴")
+    }
+}
+
+/// Luck ratio as a percentage with its indicator (ex. `🟡 97.33%`)
+pub fn format_luck(luck: f64) -> String {
+    match luck_indicator(luck) {
+        Some(indicator) => format!("{} {}", indicator, format_percent(luck, 2)),
+        None => format_percent(luck, 2),
+    }
 }
 
 pub fn format_sats(amount: u64) -> String {
@@ -19,58 +199,483 @@ pub fn format_sats(amount: u64) -> String {
     number
 }
 
+/// Group the digits of a non-negative integer in thousands (ex. `1234567` -> `1,234,567`)
+fn group_thousands(digits: &str) -> String {
+    let mut grouped: Vec<char> = Vec::with_capacity(digits.len() + digits.len() / 3);
+
+    // Right to left, a separator before every 3 digits except the leading ones
+    for (i, digit) in digits.chars().rev().enumerate() {
+        if i > 0 && i % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+
+    grouped.into_iter().rev().collect()
+}
+
 pub fn format_number(num: usize) -> String {
-    let mut number: String = num.to_string();
-    let number_len: usize = number.len();
+    group_thousands(&num.to_string())
+}
 
-    if number_len > 3 {
-        let mut counter: u8 = 1;
-        loop {
-            if num / usize::pow(1000, counter.into()) > 0 {
-                counter += 1;
+/// `None` if the timestamp is out of the range supported by chrono
+pub fn timestamp_to_utc_datetime(timestamp: i64) -> Option<DateTime<Utc>> {
+    let nt = NaiveDateTime::from_timestamp_opt(timestamp, 0)?;
+    Some(DateTime::from_utc(nt, Utc))
+}
+
+/// UTC day of the timestamp
+pub fn timestamp_to_utc_date(timestamp: i64) -> Option<NaiveDate> {
+    Some(timestamp_to_utc_datetime(timestamp)?.naive_utc().date())
+}
+
+pub fn format_date(timestamp: i64, fmt: &str) -> String {
+    format_date_tz(timestamp, fmt, Tz::UTC)
+}
+
+/// Format the timestamp in the timezone, `n/a` if out of range
+pub fn format_date_tz(timestamp: i64, fmt: &str, tz: Tz) -> String {
+    match timestamp_to_utc_datetime(timestamp) {
+        Some(dt) => dt.with_timezone(&tz).format(fmt).to_string(),
+        None => String::from("n/a"),
+    }
+}
+
+/// Parse a day as `YYYY-MM-DD`, `today` or `yesterday`
+pub fn parse_date(s: &str, today: NaiveDate) -> Option<NaiveDate> {
+    match s.to_lowercase().as_str() {
+        "today" => Some(today),
+        "yesterday" => today.pred_opt(),
+        s => NaiveDate::parse_from_str(s, "%Y-%m-%d").ok(),
+    }
+}
+
+/// Format a duration with its two most significant units (ex. `3d 4h`, `2h 15m`, `45s`).
+/// A zero second unit is omitted (ex. `1h`).
+pub fn format_duration(secs: u64) -> String {
+    let units: [(u64, &str); 4] = [
+        (secs / 86400, "d"),
+        (secs % 86400 / 3600, "h"),
+        (secs % 3600 / 60, "m"),
+        (secs % 60, "s"),
+    ];
+
+    let first: usize = units
+        .iter()
+        .position(|(value, _)| *value > 0)
+        .unwrap_or(units.len() - 1);
+
+    units[first..]
+        .iter()
+        .take(2)
+        .enumerate()
+        .filter(|(i, (value, _))| *i == 0 || *value > 0)
+        .map(|(_, (value, unit))| format!("{}{}", value, unit))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    /// Number without unit (ex. `30`)
+    MissingUnit,
+    /// Unit without number (ex. `h`)
+    MissingValue,
+    UnknownUnit(char),
+    Overflow,
+}
+
+/// Parse a duration like `30m`, `2h`, `1d` or `1h30m`.
+/// Supported units: `s`, `m`, `h`, `d`, `w`. A number without unit is rejected.
+pub fn parse_duration(s: &str) -> Result<Duration, ParseError> {
+    let s: &str = s.trim();
+
+    if s.is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    let mut secs: u64 = 0;
+    let mut value: Option<u64> = None;
+
+    for c in s.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            let v: u64 = value.unwrap_or(0);
+            value = Some(
+                v.checked_mul(10)
+                    .and_then(|v| v.checked_add(digit as u64))
+                    .ok_or(ParseError::Overflow)?,
+            );
+            continue;
+        }
+
+        let multiplier: u64 = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(ParseError::UnknownUnit(c)),
+        };
+
+        let v: u64 = value.take().ok_or(ParseError::MissingValue)?;
+        secs = v
+            .checked_mul(multiplier)
+            .and_then(|v| secs.checked_add(v))
+            .ok_or(ParseError::Overflow)?;
+    }
+
+    if value.is_some() {
+        return Err(ParseError::MissingUnit);
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Estimate of the next rewards, see [`estimate_payout`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PayoutEstimate {
+    /// Expected time between two blocks of the pool
+    pub block_interval_secs: u64,
+    /// Expected reward of each block of the pool at the current hashrate (BTC)
+    pub reward_per_block: f64,
+    /// Blocks and seconds to reach the payout threshold, if any
+    pub to_threshold: Option<(u64, u64)>,
+}
+
+/// Estimate the next rewards, assuming that the pool luck and the user hashrate don't change.
+///
+/// The pool blocks are a Poisson process: `round_probability` is the chance of a block within
+/// `round_duration_secs`, `1 - e^(-duration / interval)`, so the expected interval between two
+/// blocks is `-duration / ln(1 - round_probability)`. The reward of each block is
+/// `estimated_reward`, scaled by the current (5m) over the scoring hashrate of the user.
+/// `missing` is the amount (BTC) still needed to reach the payout threshold.
+///
+/// Return `None` if the interval or the reward can't be estimated (ex. no hashrate).
+pub fn estimate_payout(
+    estimated_reward: f64,
+    hash_rate_5m: f64,
+    hash_rate_scoring: f64,
+    round_probability: f64,
+    round_duration_secs: u64,
+    missing: Option<f64>,
+) -> Option<PayoutEstimate> {
+    if round_duration_secs == 0 || hash_rate_scoring <= 0.0 {
+        return None;
+    }
+
+    // Infinite for a probability of 0, zero for 1 and NaN out of range
+    let block_interval: f64 = -(round_duration_secs as f64) / (-round_probability).ln_1p();
+    let reward_per_block: f64 = estimated_reward * hash_rate_5m / hash_rate_scoring;
+
+    if !block_interval.is_finite()
+        || block_interval <= 0.0
+        || !reward_per_block.is_finite()
+        || reward_per_block <= 0.0
+    {
+        return None;
+    }
+
+    let to_threshold: Option<(u64, u64)> = missing.map(|missing| {
+        if missing <= 0.0 {
+            return (0, 0);
+        }
+        let blocks: u64 = (missing / reward_per_block).ceil() as u64;
+        (blocks, (blocks as f64 * block_interval).round() as u64)
+    });
+
+    Some(PayoutEstimate {
+        block_interval_secs: block_interval.round() as u64,
+        reward_per_block,
+        to_threshold,
+    })
+}
+
+/// Build CSV from a list of `(timestamp, total_reward_btc)`
+pub fn rewards_to_csv(rewards: &[(i64, f64)]) -> String {
+    let mut csv = String::from("date,total_reward_btc,total_reward_sats\n");
+
+    for (timestamp, total_reward) in rewards.iter() {
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            format_date(*timestamp, "%Y-%m-%d"),
+            format_decimal(*total_reward, 8).replace(',', ""),
+            btc_to_sats(*total_reward)
+        ));
+    }
+
+    csv
+}
+
+enum TemplateToken<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+/// Split a template into text and `{name}` placeholders. `{{` and `}}` are literal braces.
+fn template_tokens(tmpl: &str) -> Vec<TemplateToken<'_>> {
+    let mut tokens: Vec<TemplateToken> = Vec::new();
+    let mut rest: &str = tmpl;
+
+    while let Some(start) = rest.find(|c| c == '{' || c == '}') {
+        if start > 0 {
+            tokens.push(TemplateToken::Text(&rest[..start]));
+        }
+
+        let tail: &str = &rest[start..];
+
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            tokens.push(TemplateToken::Text(&tail[..1]));
+            rest = &tail[2..];
+        } else if let Some(end) = tail.find('}').filter(|_| tail.starts_with('{')) {
+            tokens.push(TemplateToken::Placeholder(&tail[1..end]));
+            rest = &tail[end + 1..];
+        } else {
+            // Unmatched brace
+            tokens.push(TemplateToken::Text(&tail[..1]));
+            rest = &tail[1..];
+        }
+    }
+
+    if !rest.is_empty() {
+        tokens.push(TemplateToken::Text(rest));
+    }
+
+    tokens
+}
+
+/// Replace the `{name}` placeholders of a template with the values.
+/// Placeholders without a value are left as they are, unused values are ignored.
+pub fn render_template(tmpl: &str, values: &HashMap<&str, String>) -> String {
+    template_tokens(tmpl)
+        .into_iter()
+        .map(|token| match token {
+            TemplateToken::Text(text) => text.to_string(),
+            TemplateToken::Placeholder(name) => match values.get(name) {
+                Some(value) => value.clone(),
+                None => format!("{{{}}}", name),
+            },
+        })
+        .collect()
+}
+
+/// Names of the placeholders used in a template
+pub fn template_placeholders(tmpl: &str) -> Vec<&str> {
+    template_tokens(tmpl)
+        .into_iter()
+        .filter_map(|token| match token {
+            TemplateToken::Placeholder(name) => Some(name),
+            TemplateToken::Text(_) => None,
+        })
+        .collect()
+}
+
+const SPARKLINE_TICKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// One line trend of the values. Series longer than `width` are downsampled by averaging
+/// consecutive values; all-equal values are drawn with the lowest block.
+pub fn sparkline(values: &[f64], width: usize) -> String {
+    let values: Vec<f64> = downsample(values, width);
+    let min: f64 = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max: f64 = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let range: f64 = max - min;
+
+    values
+        .iter()
+        .map(|value| {
+            if range > 0.0 {
+                let index = ((value - min) / range * (SPARKLINE_TICKS.len() - 1) as f64).round();
+                SPARKLINE_TICKS[index as usize]
             } else {
-                break;
+                SPARKLINE_TICKS[0]
             }
+        })
+        .collect()
+}
+
+/// Average the values in `width` buckets of (almost) the same size
+fn downsample(values: &[f64], width: usize) -> Vec<f64> {
+    if values.len() <= width {
+        return values.to_vec();
+    }
+
+    (0..width)
+        .map(|i| {
+            let bucket: &[f64] = &values[i * values.len() / width..(i + 1) * values.len() / width];
+            bucket.iter().sum::<f64>() / bucket.len() as f64
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+/// Table with aligned columns, rendered as monospace plain text or as HTML.
+/// Cells wider than the max width are truncated with an ellipsis.
+pub struct Table {
+    headers: Vec<String>,
+    aligns: Vec<Align>,
+    rows: Vec<Vec<String>>,
+    max_width: usize,
+}
+
+impl Table {
+    pub const DEFAULT_MAX_WIDTH: usize = 24;
+
+    pub fn new<S: Into<String>>(headers: impl IntoIterator<Item = S>) -> Self {
+        let headers: Vec<String> = headers.into_iter().map(Into::into).collect();
+        Self {
+            aligns: vec![Align::Left; headers.len()],
+            headers,
+            rows: Vec::new(),
+            max_width: Self::DEFAULT_MAX_WIDTH,
         }
+    }
 
-        counter -= 1;
+    /// Max display width of a cell, at least 1
+    pub fn max_width(mut self, max_width: usize) -> Self {
+        self.max_width = max_width.max(1);
+        self
+    }
 
-        let mut formatted_number: Vec<String> =
-            vec![number[0..(number_len - counter as usize * 3)].into()];
+    /// Align a column, ex. right for the numbers
+    pub fn align(mut self, column: usize, align: Align) -> Self {
+        if let Some(current) = self.aligns.get_mut(column) {
+            *current = align;
+        }
+        self
+    }
 
-        number.replace_range(0..(number_len - counter as usize * 3), "");
+    /// Add a row. Missing cells are left empty, the extra ones are ignored.
+    pub fn row<S: Into<String>>(&mut self, cells: impl IntoIterator<Item = S>) {
+        let mut row: Vec<String> = cells
+            .into_iter()
+            .take(self.headers.len())
+            .map(Into::into)
+            .collect();
+        row.resize(self.headers.len(), String::new());
+        self.rows.push(row);
+    }
 
-        loop {
-            if counter > 0 {
-                if !number[0..3].is_empty() {
-                    formatted_number.push(number[0..3].into());
-                    number.replace_range(0..3, "");
-                }
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
 
-                counter -= 1
-            } else {
-                break;
+    /// Headers and rows, truncated to the max width
+    fn lines(&self) -> Vec<Vec<String>> {
+        std::iter::once(&self.headers)
+            .chain(self.rows.iter())
+            .map(|cells| {
+                cells
+                    .iter()
+                    .map(|cell| truncate(cell, self.max_width))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Aligned columns separated by 2 spaces, with a dashed line under the headers
+    pub fn to_plain(&self) -> String {
+        let lines: Vec<Vec<String>> = self.lines();
+
+        let mut widths: Vec<usize> = vec![0; self.headers.len()];
+        for cells in lines.iter() {
+            for (width, cell) in widths.iter_mut().zip(cells.iter()) {
+                *width = (*width).max(cell.width());
             }
         }
 
-        number = formatted_number.join(",");
+        let render = |cells: &[String]| -> String {
+            let line: Vec<String> = cells
+                .iter()
+                .zip(widths.iter().zip(self.aligns.iter()))
+                .map(|(cell, (width, align))| {
+                    let padding: String = " ".repeat(width - cell.width());
+                    match align {
+                        Align::Left => format!("{}{}", cell, padding),
+                        Align::Right => format!("{}{}", padding, cell),
+                    }
+                })
+                .collect();
+            line.join("  ").trim_end().to_string()
+        };
+
+        let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+
+        let mut output: Vec<String> = Vec::with_capacity(lines.len() + 1);
+        output.push(render(&lines[0]));
+        output.push(separator.join("  "));
+        output.extend(lines[1..].iter().map(|cells| render(cells)));
+        output.join("\n")
     }
 
-    number
+    /// HTML `<table>`, for the formatted body of the messages
+    pub fn to_html(&self) -> String {
+        let lines: Vec<Vec<String>> = self.lines();
+
+        let render = |cells: &[String], tag: &str| -> String {
+            let cells: String = cells
+                .iter()
+                .zip(self.aligns.iter())
+                .map(|(cell, align)| match align {
+                    Align::Left => format!("<{}>{}</{}>", tag, escape_html(cell), tag),
+                    Align::Right => {
+                        format!("<{} align=\"right\">{}</{}>", tag, escape_html(cell), tag)
+                    }
+                })
+                .collect();
+            format!("<tr>{}</tr>", cells)
+        };
+
+        let body: String = lines[1..].iter().map(|cells| render(cells, "td")).collect();
+
+        format!(
+            "<table><thead>{}</thead><tbody>{}</tbody></table>",
+            render(&lines[0], "th"),
+            body
+        )
+    }
 }
 
-pub fn timestamp_to_utc_datetime(timestamp: i64) -> DateTime<Utc> {
-    let nt = NaiveDateTime::from_timestamp(timestamp, 0);
-    DateTime::from_utc(nt, Utc)
+/// Cut the text to `max_width` columns, ending with `…` if truncated.
+/// Wide chars (ex. CJK, emoji) take 2 columns.
+fn truncate(text: &str, max_width: usize) -> String {
+    if text.width() <= max_width {
+        return text.to_string();
+    }
+
+    let mut truncated = String::new();
+    let mut width: usize = 0;
+
+    for c in text.chars() {
+        let char_width: usize = c.width().unwrap_or(0);
+        // Keep a column for the ellipsis
+        if width + char_width >= max_width {
+            break;
+        }
+        width += char_width;
+        truncated.push(c);
+    }
+
+    truncated.push('…');
+    truncated
 }
 
-pub fn format_date(timestamp: i64, fmt: &str) -> String {
-    let dt = timestamp_to_utc_datetime(timestamp);
-    dt.format(fmt).to_string()
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
 }
 
 #[cfg(test)]
 mod test {
+    use rand::Rng;
+
     use super::*;
 
     #[test]
@@ -79,21 +684,186 @@ mod test {
             format_date(1646649012, "%Y-%m-%d"),
             "2022-03-07".to_string()
         );
+        assert_eq!(format_date(i64::MAX, "%Y-%m-%d"), "n/a");
+        assert_eq!(format_date(i64::MIN, "%Y-%m-%d"), "n/a");
+        assert_eq!(timestamp_to_utc_date(i64::MAX), None);
     }
 
     #[test]
-    fn format_num() {
-        assert_eq!(format_number(180000), "180,000".to_string());
+    fn test_format_date_tz() {
+        let fmt = "%Y-%m-%d %H:%M:%S %Z";
+        let rome: Tz = "Europe/Rome".parse().unwrap();
+        let new_york: Tz = "America/New_York".parse().unwrap();
+
+        // Europe/Rome: CET to CEST at 2022-03-27 01:00 UTC, back at 2022-10-30 01:00 UTC
+        assert_eq!(
+            format_date_tz(1648342799, fmt, rome),
+            "2022-03-27 01:59:59 CET"
+        );
+        assert_eq!(
+            format_date_tz(1648342800, fmt, rome),
+            "2022-03-27 03:00:00 CEST"
+        );
+        assert_eq!(
+            format_date_tz(1667091599, fmt, rome),
+            "2022-10-30 02:59:59 CEST"
+        );
+        assert_eq!(
+            format_date_tz(1667091600, fmt, rome),
+            "2022-10-30 02:00:00 CET"
+        );
+
+        // America/New_York: EST to EDT at 2022-03-13 07:00 UTC
+        assert_eq!(
+            format_date_tz(1647154799, fmt, new_york),
+            "2022-03-13 01:59:59 EST"
+        );
+        assert_eq!(
+            format_date_tz(1647154800, fmt, new_york),
+            "2022-03-13 03:00:00 EDT"
+        );
+
+        // Midnight UTC is the previous day in New York
+        assert_eq!(
+            format_date_tz(1649980800, "%Y-%m-%d", new_york),
+            "2022-04-14"
+        );
+        assert_eq!(
+            format_date_tz(1649980800, fmt, Tz::UTC),
+            "2022-04-15 00:00:00 UTC"
+        );
+        assert_eq!(format_date_tz(i64::MAX, fmt, rome), "n/a");
     }
 
     #[test]
-    fn test_format_gh_to_th() {
-        assert_eq!(format_gh_to_th(1000.0), "1 Th/s".to_string());
-        assert_eq!(format_gh_to_th(1000000.0), "1,000 Th/s".to_string());
+    fn test_parse_date() {
+        let today = NaiveDate::from_ymd(2022, 3, 1);
+
+        assert_eq!(parse_date("today", today), Some(today));
+        assert_eq!(
+            parse_date("Yesterday", today),
+            Some(NaiveDate::from_ymd(2022, 2, 28))
+        );
         assert_eq!(
-            format_gh_to_th(5820970883.3011),
-            "5,820,970 Th/s".to_string()
+            parse_date("2021-12-31", today),
+            Some(NaiveDate::from_ymd(2021, 12, 31))
         );
+        assert_eq!(parse_date("2022-02-29", today), None);
+        assert_eq!(parse_date("31/12/2021", today), None);
+        assert_eq!(parse_date("", today), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1d2h3m4s"), Ok(Duration::from_secs(93784)));
+        assert_eq!(parse_duration(" 90m "), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("0m"), Ok(Duration::from_secs(0)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(14 * 86400)));
+        assert_eq!(parse_duration("6w3d"), Ok(Duration::from_secs(45 * 86400)));
+        // Round trip of the formatted durations
+        assert_eq!(parse_duration("3d 4h"), Err(ParseError::UnknownUnit(' ')));
+        assert_eq!(
+            parse_duration(&format_duration(8100).replace(' ', "")),
+            Ok(Duration::from_secs(8100))
+        );
+    }
+
+    #[test]
+    fn test_parse_duration_errors() {
+        assert_eq!(parse_duration(""), Err(ParseError::Empty));
+        assert_eq!(parse_duration("  "), Err(ParseError::Empty));
+        assert_eq!(parse_duration("30"), Err(ParseError::MissingUnit));
+        assert_eq!(parse_duration("1h30"), Err(ParseError::MissingUnit));
+        assert_eq!(parse_duration("m"), Err(ParseError::MissingValue));
+        assert_eq!(parse_duration("1hm"), Err(ParseError::MissingValue));
+        assert_eq!(parse_duration("10x"), Err(ParseError::UnknownUnit('x')));
+        assert_eq!(parse_duration("10é"), Err(ParseError::UnknownUnit('é')));
+        assert_eq!(parse_duration("-1h"), Err(ParseError::UnknownUnit('-')));
+        assert_eq!(parse_duration("1 h"), Err(ParseError::UnknownUnit(' ')));
+        // No sub-second units: `ms` is a minute unit without value after it
+        assert_eq!(parse_duration("500ms"), Err(ParseError::MissingValue));
+        assert_eq!(parse_duration("1.5h"), Err(ParseError::UnknownUnit('.')));
+        assert_eq!(
+            parse_duration("99999999999999999999s"),
+            Err(ParseError::Overflow)
+        );
+        assert_eq!(
+            parse_duration("999999999999999999d"),
+            Err(ParseError::Overflow)
+        );
+    }
+
+    /// Reference grouping for the tests: split off the last 3 digits, recursively
+    fn reference_format_number(num: usize) -> String {
+        if num < 1000 {
+            num.to_string()
+        } else {
+            format!("{},{:03}", reference_format_number(num / 1000), num % 1000)
+        }
+    }
+
+    #[test]
+    fn format_num() {
+        assert_eq!(format_number(180000), "180,000".to_string());
+        assert_eq!(format_number(0), "0".to_string());
+        assert_eq!(format_number(999), "999".to_string());
+        assert_eq!(format_number(1000), "1,000".to_string());
+        assert_eq!(format_number(1000001), "1,000,001".to_string());
+        assert_eq!(format_number(10000000), "10,000,000".to_string());
+        assert_eq!(format_number(123456789), "123,456,789".to_string());
+        assert_eq!(
+            format_number(usize::MAX),
+            reference_format_number(usize::MAX)
+        );
+    }
+
+    #[test]
+    fn test_format_number_exhaustive() {
+        for num in 0..=9999 {
+            assert_eq!(format_number(num), reference_format_number(num));
+        }
+
+        let mut power: usize = 1;
+        loop {
+            assert_eq!(format_number(power), reference_format_number(power));
+            assert_eq!(format_number(power - 1), reference_format_number(power - 1));
+            match power.checked_mul(10) {
+                Some(next) => power = next,
+                None => break,
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let num: usize = rng.gen();
+            assert_eq!(format_number(num), reference_format_number(num));
+        }
+    }
+
+    #[test]
+    fn test_format_hashrate() {
+        assert_eq!(format_hashrate(1000.0), "1.00 Th/s".to_string());
+        assert_eq!(format_hashrate(1000000.0), "1.00 Ph/s".to_string());
+        assert_eq!(format_hashrate(142350.0), "142.35 Th/s".to_string());
+        assert_eq!(format_hashrate(5820970883.3011), "5.82 Eh/s".to_string());
+        assert_eq!(format_hashrate(0.5), "0.50 Gh/s".to_string());
+        assert_eq!(format_hashrate(0.0), "0.00 Gh/s".to_string());
+        // Beyond the largest unit
+        assert_eq!(format_hashrate(2.5e12), "2,500.00 Eh/s".to_string());
+    }
+
+    #[test]
+    fn test_format_hashrate_boundaries() {
+        assert_eq!(format_hashrate(999.99), "999.99 Gh/s".to_string());
+        assert_eq!(format_hashrate(999.996), "1.00 Th/s".to_string());
+        assert_eq!(format_hashrate(999_990.0), "999.99 Th/s".to_string());
+        assert_eq!(format_hashrate(1_000_000_000.0), "1.00 Eh/s".to_string());
     }
 
     #[test]
@@ -115,5 +885,330 @@ mod test {
         assert_eq!(format_btc_to_sats(0.01), "1,000,000 SAT".to_string());
         assert_eq!(format_btc_to_sats(1.0), "100,000,000 SAT".to_string());
         assert_eq!(format_btc_to_sats(10.0), "1,000,000,000 SAT".to_string());
+        // Rounded, not truncated
+        assert_eq!(format_btc_to_sats(0.00000001999), "2 SAT".to_string());
+        assert_eq!(format_btc_to_sats(0.000000014), "1 SAT".to_string());
+        assert_eq!(format_btc_to_sats(0.000000005), "1 SAT".to_string());
+        assert_eq!(format_btc_to_sats(0.29), "29,000,000 SAT".to_string());
+        assert_eq!(format_btc_to_sats(-0.0001), "0 SAT".to_string());
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        assert_eq!(format_decimal(0.9, 2), "0.90".to_string());
+        assert_eq!(format_decimal(1.005, 2), "1.01".to_string());
+        assert_eq!(format_decimal(2.675, 2), "2.68".to_string());
+        assert_eq!(format_decimal(0.125, 2), "0.13".to_string());
+        assert_eq!(format_decimal(0.124, 2), "0.12".to_string());
+        assert_eq!(format_decimal(9.995, 2), "10.00".to_string());
+        assert_eq!(format_decimal(999.5, 0), "1,000".to_string());
+        assert_eq!(format_decimal(1234567.891, 1), "1,234,567.9".to_string());
+        assert_eq!(format_decimal(-1.005, 2), "-1.01".to_string());
+        assert_eq!(format_decimal(-0.001, 2), "0.00".to_string());
+        assert_eq!(format_decimal(0.0, 3), "0.000".to_string());
+        assert_eq!(format_decimal(1e-7, 8), "0.00000010".to_string());
+        assert_eq!(format_decimal(f64::NAN, 2), "NaN".to_string());
+    }
+
+    #[test]
+    fn test_sats_round_trip() {
+        let mut values: Vec<u64> = (0..10_000).collect();
+        values.extend([
+            99_999_999,
+            100_000_000,
+            100_000_001,
+            2_099_999_999_999_999,
+            2_100_000_000_000_000,
+        ]);
+        let mut rng = rand::thread_rng();
+        values.extend((0..10_000).map(|_| rng.gen_range(0..=2_100_000_000_000_000)));
+
+        for sats in values.into_iter() {
+            let btc: f64 = sats as f64 / 100_000_000.0;
+            assert_eq!(btc_to_sats(btc), sats, "{} BTC", btc);
+
+            let formatted: String = format_btc(btc);
+            let parsed: f64 = formatted
+                .trim_end_matches(" BTC")
+                .replace(',', "")
+                .parse()
+                .unwrap();
+            assert_eq!(btc_to_sats(parsed), sats, "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn test_format_btc() {
+        assert_eq!(format_btc(0.00012345), "0.00012345 BTC".to_string());
+        assert_eq!(format_btc(0.5), "0.5 BTC".to_string());
+        assert_eq!(format_btc(1.0), "1 BTC".to_string());
+        assert_eq!(format_btc(0.0), "0 BTC".to_string());
+        assert_eq!(format_btc(6.25000001), "6.25000001 BTC".to_string());
+        assert_eq!(format_btc(0.001), "0.001 BTC".to_string());
+        assert_eq!(format_btc(1.23456789), "1.23456789 BTC".to_string());
+        assert_eq!(format_btc(-0.5), "-0.5 BTC".to_string());
+    }
+
+    #[test]
+    fn test_format_btc_edges() {
+        // Below 1 sat
+        assert_eq!(format_btc(0.000000004), "0 BTC".to_string());
+        assert_eq!(format_btc(0.000000005), "0.00000001 BTC".to_string());
+        assert_eq!(format_btc(-0.000000004), "0 BTC".to_string());
+        assert_eq!(format_amount(0.000000004, Unit::Sat), "0 SAT".to_string());
+        assert_eq!(format_amount(0.000000006, Unit::Sat), "1 SAT".to_string());
+
+        // Exactly 1 BTC, and 1 sat around it
+        assert_eq!(format_btc(1.0), "1 BTC".to_string());
+        assert_eq!(format_btc(0.99999999), "0.99999999 BTC".to_string());
+        assert_eq!(format_btc(1.00000001), "1.00000001 BTC".to_string());
+        assert_eq!(format_btc(0.999999999), "1 BTC".to_string());
+        assert_eq!(format_amount(1.0, Unit::Sat), "100,000,000 SAT".to_string());
+
+        // 21M and beyond
+        assert_eq!(format_btc(21_000_000.0), "21,000,000 BTC".to_string());
+        assert_eq!(
+            format_btc(20_999_999.99999999),
+            "20,999,999.99999999 BTC".to_string()
+        );
+        assert_eq!(
+            format_btc(21_000_000.00000001),
+            "21,000,000.00000001 BTC".to_string()
+        );
+        assert_eq!(
+            format_amount(21_000_000.0, Unit::Sat),
+            "2,100,000,000,000,000 SAT".to_string()
+        );
+        assert_eq!(
+            format_btc(100_000_000_000.0),
+            "100,000,000,000 BTC".to_string()
+        );
+        assert_eq!(btc_to_sats(1e12), u64::MAX);
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(0.0001, Unit::Sat), "10,000 SAT".to_string());
+        assert_eq!(format_amount(0.0001, Unit::Btc), "0.0001 BTC".to_string());
+        assert_eq!(Unit::from_str("BTC"), Ok(Unit::Btc));
+        assert_eq!(Unit::default(), Unit::Sat);
+    }
+
+    #[test]
+    fn test_format_percent() {
+        assert_eq!(format_percent(0.9732891, 2), "97.33%".to_string());
+        assert_eq!(format_percent(1.05, 2), "105.00%".to_string());
+        assert_eq!(format_percent(0.42, 0), "42%".to_string());
+        assert_eq!(format_percent(0.000049, 2), "0.00%".to_string());
+        assert_eq!(format_percent(0.00005, 2), "0.01%".to_string());
+        assert_eq!(format_percent(0.0, 1), "0.0%".to_string());
+        assert_eq!(format_percent(123.456, 1), "12,345.6%".to_string());
+        assert_eq!(format_percent(-0.1, 2), "n/a".to_string());
+        assert_eq!(format_percent(f64::NAN, 2), "n/a".to_string());
+        assert_eq!(format_percent(f64::INFINITY, 2), "n/a".to_string());
+    }
+
+    #[test]
+    fn test_luck_indicator() {
+        assert_eq!(luck_indicator(2.5), Some("🟢"));
+        assert_eq!(luck_indicator(1.0), Some("🟢"));
+        assert_eq!(luck_indicator(0.9999), Some("🟡"));
+        assert_eq!(luck_indicator(0.9), Some("🟡"));
+        assert_eq!(luck_indicator(0.8999), Some("🔴"));
+        assert_eq!(luck_indicator(0.0), Some("🔴"));
+        assert_eq!(luck_indicator(-1.0), None);
+        assert_eq!(luck_indicator(f64::NAN), None);
+
+        assert_eq!(format_luck(0.9732891), "🟡 97.33%".to_string());
+        assert_eq!(format_luck(f64::NAN), "n/a".to_string());
+    }
+
+    #[test]
+    fn test_table() {
+        let mut table = Table::new(["Worker", "State", "Hashrate"])
+            .align(2, Align::Right)
+            .max_width(12);
+        table.row(["s19", "ok", "95.00 Th/s"]);
+        table.row(["rig-with-a-long-name", "low", "1.00 Gh/s"]);
+        table.row(["矿机一号", "off"]);
+        table.row(["<b>&", "ok", "0.00 Gh/s", "ignored"]);
+
+        assert_eq!(
+            table.to_plain(),
+            [
+                "Worker        State    Hashrate",
+                "------------  -----  ----------",
+                "s19           ok     95.00 Th/s",
+                "rig-with-a-…  low     1.00 Gh/s",
+                "矿机一号      off",
+                "<b>&          ok      0.00 Gh/s",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            table.to_html(),
+            [
+                "<table><thead><tr><th>Worker</th><th>State</th><th align=\"right\">Hashrate</th></tr></thead><tbody>",
+                "<tr><td>s19</td><td>ok</td><td align=\"right\">95.00 Th/s</td></tr>",
+                "<tr><td>rig-with-a-…</td><td>low</td><td align=\"right\">1.00 Gh/s</td></tr>",
+                "<tr><td>矿机一号</td><td>off</td><td align=\"right\"></td></tr>",
+                "<tr><td>&lt;b&gt;&amp;</td><td>ok</td><td align=\"right\">0.00 Gh/s</td></tr>",
+                "</tbody></table>",
+            ]
+            .concat()
+        );
+
+        let empty = Table::new(["Date", "Reward"]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.to_plain(), "Date  Reward\n----  ------".to_string());
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("s19", 5), "s19".to_string());
+        assert_eq!(truncate("abcde", 5), "abcde".to_string());
+        assert_eq!(truncate("abcdef", 5), "abcd…".to_string());
+        // Wide chars take 2 columns, never cut in half
+        assert_eq!(truncate("日本語です", 5), "日本…".to_string());
+        assert_eq!(truncate("日本語です", 6), "日本…".to_string());
+        assert_eq!(truncate("日本語です", 10), "日本語です".to_string());
+        assert_eq!(truncate("abc", 1), "…".to_string());
+    }
+
+    #[test]
+    fn test_render_template() {
+        let values: HashMap<&str, String> = HashMap::from([
+            ("reward", String::from("1,000 SAT")),
+            ("hashrate", String::from("120.00 Th/s")),
+        ]);
+
+        assert_eq!(
+            render_template("Reward: {reward}\nHashrate: {hashrate}", &values),
+            "Reward: 1,000 SAT\nHashrate: 120.00 Th/s".to_string()
+        );
+        // Extra values are ignored
+        assert_eq!(
+            render_template("{reward}", &values),
+            "1,000 SAT".to_string()
+        );
+        // Missing placeholders are kept
+        assert_eq!(
+            render_template("{reward} ({luck})", &values),
+            "1,000 SAT ({luck})".to_string()
+        );
+        assert_eq!(
+            render_template("{{reward}} {reward} { } }", &values),
+            "{reward} 1,000 SAT { } }".to_string()
+        );
+        assert_eq!(render_template("", &values), "".to_string());
+        assert_eq!(render_template("{reward", &values), "{reward".to_string());
+    }
+
+    #[test]
+    fn test_template_placeholders() {
+        assert_eq!(
+            template_placeholders("{reward} {{literal}} {hashrate}"),
+            vec!["reward", "hashrate"]
+        );
+        assert!(template_placeholders("no placeholders").is_empty());
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[], 10), "".to_string());
+        assert_eq!(sparkline(&[5.0, 5.0, 5.0], 10), "▁▁▁".to_string());
+        assert_eq!(
+            sparkline(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], 8),
+            "▁▂▃▄▅▆▇█".to_string()
+        );
+        assert_eq!(sparkline(&[10.0, 0.0, 10.0], 10), "█▁█".to_string());
+        assert_eq!(sparkline(&[1.0, 2.0, 3.0], 0), "".to_string());
+
+        // Pairs averaged to 1.5, 3.5, ..., 15.5
+        let values: Vec<f64> = (1..=16).map(|value| value as f64).collect();
+        assert_eq!(sparkline(&values, 8), "▁▂▃▄▅▆▇█".to_string());
+        assert_eq!(sparkline(&values, 1), "▁".to_string());
+
+        // Buckets of 2, 3, 2 and 3 values: 0, 10, 0 and 5
+        assert_eq!(
+            sparkline(&[0.0, 0.0, 10.0, 10.0, 10.0, 0.0, 0.0, 5.0, 5.0, 5.0], 4),
+            "▁█▁▅".to_string()
+        );
+        assert_eq!(sparkline(&[2.0; 300], 48), "▁".repeat(48));
+    }
+
+    #[test]
+    fn test_downsample() {
+        assert_eq!(downsample(&[1.0, 2.0], 5), vec![1.0, 2.0]);
+        assert_eq!(downsample(&[1.0, 3.0, 5.0, 7.0, 9.0], 2), vec![2.0, 7.0]);
+        assert_eq!(downsample(&[1.0; 288], 48).len(), 48);
+        assert!(downsample(&[1.0], 0).is_empty());
+    }
+
+    #[test]
+    fn test_rewards_to_csv() {
+        assert_eq!(
+            rewards_to_csv(&[]),
+            "date,total_reward_btc,total_reward_sats\n".to_string()
+        );
+        assert_eq!(
+            rewards_to_csv(&[(1646649012, 0.00012345), (1646562612, 1.0)]),
+            "date,total_reward_btc,total_reward_sats\n2022-03-07,0.00012345,12345\n2022-03-06,1.00000000,100000000\n".to_string()
+        );
+    }
+
+    #[test]
+    fn test_estimate_payout() {
+        // ln(1 - 0.42) = -0.5447: a block every 6609s
+        let estimate = estimate_payout(0.004, 120.0, 100.0, 0.42, 3600, None).unwrap();
+        assert_eq!(estimate.block_interval_secs, 6609);
+        assert!((estimate.reward_per_block - 0.0048).abs() < 1e-12);
+        assert_eq!(estimate.to_threshold, None);
+
+        // 0.01 BTC missing: 3 blocks of 0.0048
+        let estimate = estimate_payout(0.004, 120.0, 100.0, 0.42, 3600, Some(0.01)).unwrap();
+        assert_eq!(estimate.to_threshold, Some((3, 19826)));
+
+        // Already reached
+        let estimate = estimate_payout(0.004, 120.0, 100.0, 0.42, 3600, Some(-0.001)).unwrap();
+        assert_eq!(estimate.to_threshold, Some((0, 0)));
+
+        // Half probability in one hour: a block every 1h 26m
+        let estimate = estimate_payout(0.004, 100.0, 100.0, 0.5, 3600, None).unwrap();
+        assert_eq!(estimate.block_interval_secs, 5194);
+
+        // Not enough data
+        assert_eq!(estimate_payout(0.004, 0.0, 100.0, 0.42, 3600, None), None);
+        assert_eq!(estimate_payout(0.004, 120.0, 0.0, 0.42, 3600, None), None);
+        assert_eq!(estimate_payout(0.0, 120.0, 100.0, 0.42, 3600, None), None);
+        assert_eq!(estimate_payout(0.004, 120.0, 100.0, 0.0, 3600, None), None);
+        assert_eq!(estimate_payout(0.004, 120.0, 100.0, 1.0, 3600, None), None);
+        assert_eq!(estimate_payout(0.004, 120.0, 100.0, 1.5, 3600, None), None);
+        assert_eq!(estimate_payout(0.004, 120.0, 100.0, 0.42, 0, None), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0s".to_string());
+        // Sub-second durations are truncated to 0
+        assert_eq!(
+            format_duration(Duration::from_millis(999).as_secs()),
+            "0s".to_string()
+        );
+        assert_eq!(format_duration(45), "45s".to_string());
+        assert_eq!(format_duration(60), "1m".to_string());
+        assert_eq!(format_duration(125), "2m 5s".to_string());
+        assert_eq!(format_duration(3600), "1h".to_string());
+        assert_eq!(format_duration(3601), "1h".to_string());
+        assert_eq!(format_duration(8100), "2h 15m".to_string());
+        assert_eq!(format_duration(86400), "1d".to_string());
+        assert_eq!(format_duration(86400 + 59 * 60), "1d".to_string());
+        assert_eq!(
+            format_duration(3 * 86400 + 4 * 3600 + 5 * 60 + 6),
+            "3d 4h".to_string()
+        );
+        // Multi-week durations stay in days
+        assert_eq!(format_duration(6 * 604800 + 3600), "42d 1h".to_string());
+        assert_eq!(format_duration(u64::MAX), "213503982334601d 7h".to_string());
     }
 }